@@ -0,0 +1,516 @@
+//! Authentication of peers and authorization of their requests.
+//!
+//! Peers on the Unix socket are identified by their credentials (SO_PEERCRED),
+//! peers on TCP by a token sent in the request. A policy file maps them to the
+//! commands, working directories and environment variables they may use, and
+//! the files they may put and get:
+//!
+//! ```json
+//! {
+//!   "tokens": { "s3cret": "deploy" },
+//!   "rules": [
+//!     { "uid": 0, "commands": ["*"] },
+//!     { "owner": "deploy", "commands": ["systemctl restart app-*"], "shell": false, "cwd": ["/srv/*"], "env": ["APP_*"], "paths": ["/srv/app/*"], "owners": ["app"], "profile": "sandbox" }
+//!   ],
+//!   "profiles": { "sandbox": { "user": "nobody" } }
+//! }
+//! ```
+//!
+//! A request is allowed when any rule matches it, everything else is denied.
+//! It runs with the profile it asks for, if the rule allows that one, or with
+//! the profile of the rule. See [`crate::profile`].
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::profile::Profile;
+use crate::protocol::Request;
+
+/// Who is on the other side of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peer {
+    Unix { uid: u32, gid: u32, pid: Option<i32> },
+    Tcp(SocketAddr),
+    /// TCP with TLS, `identity` is named by the client certificate
+    Tls { addr: SocketAddr, identity: Option<String> },
+}
+
+impl Peer {
+    pub fn uid(&self) -> Option<u32> {
+        match self {
+            Peer::Unix { uid, .. } => Some(*uid),
+            Peer::Tcp(_) | Peer::Tls { .. } => None,
+        }
+    }
+
+    pub fn gid(&self) -> Option<u32> {
+        match self {
+            Peer::Unix { gid, .. } => Some(*gid),
+            Peer::Tcp(_) | Peer::Tls { .. } => None,
+        }
+    }
+
+    /// Whether the peer is known before its first request: by its credentials
+    /// or its client certificate. Other TCP peers are known by their token.
+    pub fn authenticated(&self) -> bool {
+        matches!(self, Peer::Unix { .. } | Peer::Tls { identity: Some(_), .. })
+    }
+
+    /// Who the peer is as far as it is proven, to count its requests by: the
+    /// uid, the certificate identity, `owner` when a token proved it, or the
+    /// address of TCP peers otherwise
+    pub fn identity(&self, owner: Option<&str>) -> String {
+        match (self, owner) {
+            (Peer::Unix { uid, .. }, _) => format!("uid {uid}"),
+            (Peer::Tls { identity: Some(identity), .. }, _) => identity.clone(),
+            (Peer::Tcp(_) | Peer::Tls { .. }, Some(owner)) => owner.to_string(),
+            (Peer::Tcp(addr) | Peer::Tls { addr, .. }, None) => addr.ip().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Unix { uid, gid, pid } => write!(f, "uid={uid} gid={gid} pid={}", pid.unwrap_or(-1)),
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Tls { addr, identity: Some(identity) } => write!(f, "{addr} cert={identity}"),
+            Peer::Tls { addr, identity: None } => write!(f, "{addr} tls"),
+        }
+    }
+}
+
+/// Kind of a failed request, sent along with the error message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Request could not be parsed or is of an unsupported version
+    Invalid,
+    /// Peer could not be identified
+    Unauthenticated,
+    /// Peer is not allowed to run the request
+    Denied,
+    /// Request to cancel, job or profile is not found
+    NotFound,
+    /// Job has not finished yet
+    Running,
+    /// Too many commands are running and queued
+    Busy,
+    /// Server failed to set up the command
+    Internal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denied {
+    pub kind: ErrorKind,
+    pub reason: String,
+}
+
+impl Denied {
+    fn new(kind: ErrorKind, reason: impl ToString) -> Self {
+        Denied { kind, reason: reason.to_string() }
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Owner of the token or certificate of TCP peers. For Unix peers it only
+    /// narrows a rule with `uid`, a rule without `uid` never matches them.
+    pub owner: Option<String>,
+    /// Uid of Unix peers, rules with uid never match TCP peers
+    pub uid: Option<u32>,
+    /// Patterns of allowed commands, `*` matches any string and `?` any character.
+    /// Commands run by bash, so wildcards never match shell metacharacters which
+    /// would chain another command, e.g. `echo *` does not allow `echo; id`.
+    /// A lone `*` allows any command.
+    /// Patterns match `argv` of requests word by word, each word one argument
+    /// where wildcards match any character as no shell is involved, and a last
+    /// lone `*` any further arguments: `git log *` allows `["git", "log", "-1"]`
+    /// but not `["git", "log -1"]`.
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /// Whether requests may run command lines with bash, true by default,
+    /// requests must give `argv` otherwise
+    pub shell: Option<bool>,
+    /// Patterns of allowed working directories, requests without one are always allowed
+    #[serde(default)]
+    pub cwd: Vec<String>,
+    /// Patterns of environment variable names the request may set
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Patterns of absolute paths of files the request may put and get
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Patterns of users and groups, as requests name them, put files may be
+    /// given to besides the uid and gid of the peer
+    #[serde(default)]
+    pub owners: Vec<String>,
+    /// Profile of requests which do not ask for one
+    pub profile: Option<String>,
+    /// Patterns of profiles requests may ask for
+    #[serde(default)]
+    pub profiles: Vec<String>,
+}
+
+impl Rule {
+    /// Whether the rule is for the Unix peer of `uid`, or for the `owner`
+    /// authenticated by a token or certificate. The owner a Unix peer names
+    /// is its own claim, it only narrows rules of its uid.
+    fn applies(&self, uid: Option<u32>, owner: Option<&str>, named: &str) -> bool {
+        match (self.uid, &self.owner) {
+            (Some(u), owner) => Some(u) == uid && owner.as_ref().is_none_or(|o| o == named),
+            (None, Some(o)) => owner == Some(o.as_str()),
+            (None, None) => true,
+        }
+    }
+
+    /// Check `request` and return the profile it runs with
+    fn check(&self, request: &Request) -> Result<Option<String>, String> {
+        match request.transfer() {
+            Some((op, path)) if !self.paths.iter().any(|p| matches(p, path)) => return Err(format!("{op} of `{path}` is not allowed")),
+            Some(_) => return self.check_profile(request),
+            None => (),
+        }
+        if request.shell() && self.shell == Some(false) {
+            return Err("shell commands are not allowed, run `argv` instead".to_string());
+        }
+        let allowed = match request.shell() {
+            true => self.commands.iter().any(|p| p == "*" || wildcard(p, &request.bash, |c| !SHELL_META.contains(&c))),
+            false => self.commands.iter().any(|p| matches_argv(p, &request.argv)),
+        };
+        if !allowed {
+            return Err(format!("command `{}` is not allowed", request.command_line()));
+        }
+        if let Some(cwd) = &request.cwd
+            && !self.cwd.iter().any(|p| matches(p, cwd))
+        {
+            return Err(format!("working directory `{cwd}` is not allowed"));
+        }
+        if let Some(name) = request.env.keys().find(|k| !self.env.iter().any(|p| matches(p, k))) {
+            return Err(format!("environment variable `{name}` is not allowed"));
+        }
+        self.check_profile(request)
+    }
+
+    /// Profile `request` runs with, if it may ask for one
+    fn check_profile(&self, request: &Request) -> Result<Option<String>, String> {
+        match &request.profile {
+            None => Ok(self.profile.clone()),
+            Some(profile) if self.profile.as_ref() == Some(profile) || self.profiles.iter().any(|p| matches(p, profile)) => {
+                Ok(Some(profile.clone()))
+            }
+            Some(profile) => Err(format!("profile `{profile}` is not allowed")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// Token of TCP peers without client certificate and the owner it authenticates
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Policy {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Check that `peer` speaks for the owner of `request`, return the uid of Unix peers
+    pub fn authenticate(&self, peer: &Peer, request: &Request) -> Result<Option<u32>, Denied> {
+        match peer {
+            Peer::Unix { uid, .. } => Ok(Some(*uid)),
+            Peer::Tls { identity: Some(identity), .. } => {
+                if *identity != request.owner {
+                    return Err(Denied::new(ErrorKind::Denied, format!("certificate does not belong to owner `{}`", request.owner)));
+                }
+                Ok(None)
+            }
+            Peer::Tcp(_) | Peer::Tls { identity: None, .. } => {
+                let token = request.token.as_deref().unwrap_or_default();
+                let Some(owner) = self.tokens.iter().find(|(t, _)| equal(t.as_bytes(), token.as_bytes())).map(|(_, o)| o) else {
+                    return Err(Denied::new(ErrorKind::Unauthenticated, "missing or invalid token"));
+                };
+                if *owner != request.owner {
+                    return Err(Denied::new(ErrorKind::Denied, format!("token does not belong to owner `{}`", request.owner)));
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Check that `peer` may run `request`, return the profile it runs with
+    pub fn authorize(&self, peer: &Peer, request: &Request) -> Result<Option<String>, Denied> {
+        let uid = self.authenticate(peer, request)?;
+        self.permit(uid, request)
+    }
+
+    /// Check `request` of an authenticated peer, a Unix one of `uid` or a TCP
+    /// one proven to be the owner, against the rules, return its profile
+    pub fn permit(&self, uid: Option<u32>, request: &Request) -> Result<Option<String>, Denied> {
+        // owner of TCP peers is proven by their token or certificate
+        let owner = uid.is_none().then_some(request.owner.as_str());
+        let mut reason = match uid {
+            Some(uid) => format!("no rule for owner `{}` of uid {uid}", request.owner),
+            None => format!("no rule for owner `{}`", request.owner),
+        };
+        for rule in self.rules.iter().filter(|r| r.applies(uid, owner, &request.owner)) {
+            match rule.check(request) {
+                Ok(profile) => return Ok(profile),
+                Err(e) => reason = e,
+            }
+        }
+        Err(Denied::new(ErrorKind::Denied, reason))
+    }
+
+    /// Check that a rule allowing the put `request` of the peer of `uid` lets
+    /// it give the file to the user or group `owner`
+    pub fn permit_owner(&self, uid: Option<u32>, request: &Request, owner: &str) -> Result<(), Denied> {
+        let named = uid.is_none().then_some(request.owner.as_str());
+        let mut rules = self.rules.iter().filter(|r| r.applies(uid, named, &request.owner));
+        match rules.any(|rule| rule.check(request).is_ok() && rule.owners.iter().any(|p| matches(p, owner))) {
+            true => Ok(()),
+            false => Err(Denied::new(ErrorKind::Denied, format!("put files may not be given to `{owner}`"))),
+        }
+    }
+}
+
+/// Characters which let bash run more than the matched command
+const SHELL_META: &[char] = &[';', '&', '|', '`', '$', '(', ')', '<', '>', '\n', '\r'];
+
+/// Compare in constant time to not leak tokens through timing
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Wildcard match of `text` with `pattern`, `*` is any string and `?` any character
+pub fn matches(pattern: &str, text: &str) -> bool {
+    wildcard(pattern, text, |_| true)
+}
+
+/// Match `argv` against the words of `pattern`, a last lone `*` matches any further arguments
+fn matches_argv(pattern: &str, argv: &[String]) -> bool {
+    let words: Vec<&str> = pattern.split_whitespace().collect();
+    let (words, rest) = match words.split_last() {
+        Some((&"*", words)) => (words, true),
+        _ => (words.as_slice(), false),
+    };
+    (argv.len() == words.len() || rest && argv.len() > words.len()) && words.iter().zip(argv).all(|(p, arg)| matches(p, arg))
+}
+
+/// Wildcard match where wildcards only match characters accepted by `wild`
+fn wildcard(pattern: &str, text: &str, wild: impl Fn(char) -> bool) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of last `*` and of text when it was seen, to backtrack
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') if wild(text[t]) => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some('*') => p += 1,
+            Some(c) if (*c == '?' && wild(text[t])) || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) if wild(text[st]) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                _ => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::{Get, Put};
+
+    const POLICY: &str = r#"{
+        "tokens": { "s3cret": "deploy" },
+        "rules": [
+            { "uid": 0, "commands": ["*"] },
+            { "uid": 1000, "owner": "alice", "commands": ["echo *"], "cwd": ["/tmp", "/home/alice/*"], "env": ["LANG", "APP_*"] },
+            { "owner": "deploy", "commands": ["systemctl restart app-?"], "profile": "app", "profiles": ["app-*"] },
+            { "owner": "ci", "commands": ["git *"], "shell": false, "paths": ["/srv/ci/*"], "owners": ["www-*"] }
+        ],
+        "profiles": { "app": { "user": "app" }, "app-debug": { "user": "app", "clean_env": true } }
+    }"#;
+
+    fn request(owner: &str, bash: &str) -> Request {
+        Request {
+            owner: owner.to_string(),
+            bash: bash.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn exec(owner: &str, argv: &[&str]) -> Request {
+        Request {
+            owner: owner.to_string(),
+            argv: argv.iter().map(|arg| arg.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn unix(uid: u32) -> Peer {
+        Peer::Unix { uid, gid: uid, pid: None }
+    }
+
+    fn cert(identity: &str) -> Peer {
+        Peer::Tls { addr: "127.0.0.1:5000".parse().unwrap(), identity: Some(identity.to_string()) }
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*", ""));
+        assert!(matches("echo *", "echo hello world"));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+        assert!(matches("app-?", "app-1"));
+        assert!(!matches("app-?", "app-12"));
+        assert!(!matches("echo *", "ls; echo x"));
+    }
+
+    #[test]
+    fn test_unix_peer() {
+        let policy: Policy = serde_json::from_str(POLICY).unwrap();
+        assert!(policy.authorize(&unix(0), &request("anyone", "rm -rf /tmp/x")).is_ok());
+        assert!(policy.authorize(&unix(0), &request("anyone", "ps aux | grep x")).is_ok());
+        assert!(policy.authorize(&unix(1000), &request("alice", "echo hi")).is_ok());
+        assert_eq!(policy.authorize(&unix(1000), &request("bob", "echo hi")).unwrap_err().kind, ErrorKind::Denied);
+        assert_eq!(policy.authorize(&unix(1001), &request("alice", "echo hi")).unwrap_err().kind, ErrorKind::Denied);
+        assert!(policy.authorize(&unix(1000), &request("alice", "cat /etc/shadow")).is_err());
+        assert!(policy.authorize(&unix(1000), &request("alice", "echo hi; cat /etc/shadow")).is_err());
+        // owner named by a Unix peer does not select rules of authenticated owners
+        assert_eq!(policy.authorize(&unix(1001), &request("deploy", "systemctl restart app-1")).unwrap_err().kind, ErrorKind::Denied);
+        assert!(policy.authorize(&unix(1000), &request("alice", "echo $(cat /etc/shadow)")).is_err());
+
+        let mut req = request("alice", "echo hi");
+        req.cwd = Some("/home/alice/src".to_string());
+        req.env.insert("APP_MODE".to_string(), "test".to_string());
+        assert!(policy.authorize(&unix(1000), &req).is_ok());
+        req.cwd = Some("/root".to_string());
+        assert!(policy.authorize(&unix(1000), &req).unwrap_err().reason.contains("working directory"));
+        req.cwd = None;
+        req.env.insert("LD_PRELOAD".to_string(), "/tmp/x.so".to_string());
+        assert!(policy.authorize(&unix(1000), &req).unwrap_err().reason.contains("LD_PRELOAD"));
+    }
+
+    #[test]
+    fn test_argv() {
+        let policy: Policy = serde_json::from_str(POLICY).unwrap();
+        // wildcards of argv match shell metacharacters, which are plain arguments
+        assert!(policy.authorize(&unix(1000), &exec("alice", &["echo", "a; b"])).is_ok());
+        assert!(policy.authorize(&unix(1000), &exec("alice", &["cat", "/etc/shadow"])).is_err());
+        assert!(policy.authorize(&cert("ci"), &exec("ci", &["git", "log", "--format=%H|%s"])).is_ok());
+        // each word matches one argument
+        assert!(policy.authorize(&unix(1000), &exec("alice", &["echo", "a b", "c"])).is_ok());
+        assert!(policy.authorize(&unix(1000), &exec("alice", &["echo"])).is_ok());
+        assert!(policy.authorize(&cert("deploy"), &exec("deploy", &["systemctl", "restart", "app-1"])).is_ok());
+        assert!(policy.authorize(&cert("deploy"), &exec("deploy", &["systemctl", "restart app-1"])).is_err());
+        assert!(policy.authorize(&cert("deploy"), &exec("deploy", &["systemctl", "restart", "app-1", "db"])).is_err());
+        // shell is forbidden to ci even for a command it may exec
+        let denied = policy.authorize(&cert("ci"), &request("ci", "git status")).unwrap_err();
+        assert!(denied.reason.contains("shell"));
+        assert!(policy.authorize(&unix(0), &request("ci", "git status")).is_ok());
+    }
+
+    #[test]
+    fn test_paths() {
+        let policy: Policy = serde_json::from_str(POLICY).unwrap();
+        let get = |owner: &str, path: &str| Request {
+            owner: owner.to_string(),
+            get: Some(Get { path: path.to_string() }),
+            ..Default::default()
+        };
+        // transfers run no shell, which is forbidden to ci
+        assert!(policy.authorize(&cert("ci"), &get("ci", "/srv/ci/build.log")).is_ok());
+        assert!(policy.authorize(&cert("ci"), &get("ci", "/etc/shadow")).unwrap_err().reason.contains("get of `/etc/shadow`"));
+        assert!(policy.authorize(&unix(1), &get("ci", "/srv/ci/build.log")).is_err());
+        assert!(policy.authorize(&unix(1000), &get("alice", "/tmp/x")).is_err());
+        assert!(policy.authorize(&unix(0), &get("anyone", "/tmp/x")).is_err());
+
+        let put = Request { owner: "ci".to_string(), put: Some(Put { path: "/srv/ci/site".to_string(), ..Default::default() }), ..Default::default() };
+        assert!(policy.permit_owner(None, &put, "www-data").is_ok());
+        assert!(policy.permit_owner(None, &put, "root").unwrap_err().reason.contains("`root`"));
+        assert!(policy.permit_owner(Some(1000), &put, "www-data").is_err());
+    }
+
+    #[test]
+    fn test_tcp_peer() {
+        let policy: Policy = serde_json::from_str(POLICY).unwrap();
+        let peer = Peer::Tcp("127.0.0.1:5000".parse().unwrap());
+        let mut req = request("deploy", "systemctl restart app-1");
+        assert_eq!(policy.authorize(&peer, &req).unwrap_err().kind, ErrorKind::Unauthenticated);
+        req.token = Some("wrong".to_string());
+        assert_eq!(policy.authorize(&peer, &req).unwrap_err().kind, ErrorKind::Unauthenticated);
+        req.token = Some("s3cret".to_string());
+        assert_eq!(policy.authorize(&peer, &req).unwrap(), Some("app".to_string()));
+        req.profile = Some("app-debug".to_string());
+        assert_eq!(policy.authorize(&peer, &req).unwrap(), Some("app-debug".to_string()));
+        req.profile = Some("root".to_string());
+        assert!(policy.authorize(&peer, &req).unwrap_err().reason.contains("profile"));
+        req.profile = None;
+        // uid rules do not apply to TCP peers
+        req.bash = "id".to_string();
+        assert_eq!(policy.authorize(&peer, &req).unwrap_err().kind, ErrorKind::Denied);
+        // token only authenticates its own owner
+        let mut req = request("alice", "echo hi");
+        req.token = Some("s3cret".to_string());
+        assert_eq!(policy.authorize(&peer, &req).unwrap_err().kind, ErrorKind::Denied);
+    }
+
+    #[test]
+    fn test_tls_peer() {
+        let policy: Policy = serde_json::from_str(POLICY).unwrap();
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        // the certificate authenticates its owner without token
+        let peer = Peer::Tls { addr, identity: Some("deploy".to_string()) };
+        let req = request("deploy", "systemctl restart app-1");
+        assert_eq!(policy.authorize(&peer, &req).unwrap(), Some("app".to_string()));
+        let mut req = request("alice", "echo hi");
+        req.token = Some("s3cret".to_string());
+        assert_eq!(policy.authorize(&peer, &req).unwrap_err().kind, ErrorKind::Denied);
+        // without certificate the token is needed
+        let peer = Peer::Tls { addr, identity: None };
+        let mut req = request("deploy", "systemctl restart app-1");
+        assert_eq!(policy.authorize(&peer, &req).unwrap_err().kind, ErrorKind::Unauthenticated);
+        req.token = Some("s3cret".to_string());
+        assert!(policy.authorize(&peer, &req).is_ok());
+    }
+
+    #[test]
+    fn test_identity() {
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        assert_eq!(Peer::Unix { uid: 1000, gid: 1000, pid: None }.identity(Some("root")), "uid 1000");
+        assert_eq!(Peer::Tls { addr, identity: Some("deploy".to_string()) }.identity(Some("root")), "deploy");
+        assert_eq!(Peer::Tcp(addr).identity(Some("deploy")), "deploy");
+        // an owner no token proved does not count
+        assert_eq!(Peer::Tcp(addr).identity(None), "127.0.0.1");
+    }
+}
@@ -0,0 +1,433 @@
+//! Run a command through forwarder as if it ran locally: its stdout and
+//! stderr are written to ours, stdin is sent as its input as it is read
+//! unless it is a terminal, and its exit code is ours. A program and its arguments are
+//! executed without shell, a single argument is a command line run by bash.
+//!
+//! ```sh
+//! fwd -- systemctl status app
+//! echo data | fwd 'wc -c'
+//! fwd --exec /usr/local/bin/deploy
+//! fwd --tty -- top
+//! fwd --put app.conf /etc/app/app.conf && fwd --get /var/log/app.log app.log
+//! fwd --submit -- apt-get -y upgrade && fwd --list
+//! ```
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+
+use forwarder::client::{self, Client, ServerError};
+use forwarder::exec::Stream;
+use forwarder::protocol::{self, JobInfo, Request, Response, SessionFrame};
+use forwarder::pty::{self, RawMode, Tty};
+use forwarder::tls;
+use forwarder::transfer::Put;
+
+/// Exit code when the command could not be run, like ssh
+const FAILURE: u8 = 255;
+/// Bytes of stdin read at once
+const CHUNK_LEN: usize = 8192;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Unix socket of forwarder
+    #[arg(long, default_value = protocol::SOCK)]
+    socket: String,
+    /// Connect to forwarder on TCP instead, as host:port
+    #[arg(long, conflicts_with = "socket")]
+    tcp: Option<String>,
+    /// PEM CA bundle to verify the server with, connects with TLS
+    #[arg(long, requires = "tcp")]
+    tls_ca: Option<PathBuf>,
+    /// PEM client certificate, it names the owner on the server
+    #[arg(long, requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the client certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Name the server certificate must be valid for, host of --tcp by default
+    #[arg(long, requires = "tls_ca")]
+    tls_name: Option<String>,
+    /// Owner of the request, $USER by default, or the one of the client certificate
+    #[arg(long)]
+    owner: Option<String>,
+    /// Token of the owner on TCP, $FORWARDER_TOKEN by default
+    #[arg(long)]
+    token: Option<String>,
+    /// Working directory of the command
+    #[arg(long)]
+    cwd: Option<String>,
+    /// Environment variable of the command as NAME=VALUE
+    #[arg(short, long = "env", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+    /// Execution profile of the policy to run with
+    #[arg(long)]
+    profile: Option<String>,
+    /// Kill the command after running this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+    /// Request id, to cancel the command from elsewhere
+    #[arg(long)]
+    id: Option<String>,
+    /// Do not send stdin as input
+    #[arg(short = 'n', long)]
+    no_stdin: bool,
+    /// Wait for the whole output instead of streaming it
+    #[arg(long)]
+    no_stream: bool,
+    /// Run the command as a job and print its id
+    #[arg(long)]
+    submit: bool,
+    /// Print the state of a job
+    #[arg(long, value_name = "ID")]
+    status: Option<String>,
+    /// Print the output of a finished job and exit with its code
+    #[arg(long, value_name = "ID")]
+    result: Option<String>,
+    /// List jobs
+    #[arg(long)]
+    list: bool,
+    /// Cancel a running command or job
+    #[arg(long, value_name = "ID")]
+    cancel: Option<String>,
+    /// Copy a local file to the server, with its mode
+    #[arg(long, num_args = 2, value_names = ["LOCAL", "REMOTE"], conflicts_with = "get")]
+    put: Option<Vec<String>>,
+    /// Copy a file of the server to a local one, with its mode
+    #[arg(long, num_args = 2, value_names = ["REMOTE", "LOCAL"])]
+    get: Option<Vec<String>>,
+    /// Keep the owner of the copied file too, where permitted
+    #[arg(short, long)]
+    preserve: bool,
+    /// Write the put file in place instead of replacing it at once
+    #[arg(long, requires = "put")]
+    in_place: bool,
+    /// Execute a single argument as a program too
+    #[arg(long, conflicts_with = "shell")]
+    exec: bool,
+    /// Run the arguments joined into a command line by bash
+    #[arg(long)]
+    shell: bool,
+    /// Run the command in a terminal attached to ours, stdin is sent as typed
+    #[arg(short, long, conflicts_with_all = ["no_stdin", "no_stream", "submit"])]
+    tty: bool,
+    /// Program and its arguments, executed without shell, a single argument is
+    /// run as a command line by bash
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+fn parse_env(text: &str) -> Result<(String, String), String> {
+    text.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("{text} is not NAME=VALUE"))
+}
+
+/// Exit code of a local shell for `response`
+fn exit_code(response: &Response) -> u8 {
+    match (response.code, response.signal) {
+        (Some(code), _) => code as u8,
+        (None, Some(signal)) => 128 + signal as u8,
+        (None, None) => FAILURE,
+    }
+}
+
+fn print_job(job: &JobInfo) {
+    let status = match (job.code, job.status) {
+        (Some(code), _) => format!("code {code}"),
+        (None, Some(status)) => format!("{status:?}").to_lowercase(),
+        (None, None) => String::new(),
+    };
+    println!("{}\t{:?}\t{}\t{}\t{}", job.id, job.state, job.submitted, status, job.bash);
+}
+
+fn print_output(response: &Response) -> Result<()> {
+    let (stdout, stderr) = response.output_bytes()?;
+    std::io::stdout().write_all(&stdout)?;
+    std::io::stderr().write_all(&stderr)?;
+    Ok(())
+}
+
+async fn run(opt: Cli) -> Result<u8> {
+    let client = match (&opt.tcp, &opt.tls_ca) {
+        (Some(addr), Some(ca)) => {
+            let identity = opt.tls_cert.as_deref().zip(opt.tls_key.as_deref());
+            let connector = tls::connector(ca, identity)?;
+            let host = addr.rsplit_once(':').map_or(addr.as_str(), |(host, _)| host);
+            let name = opt.tls_name.as_deref().unwrap_or(host.trim_start_matches('[').trim_end_matches(']'));
+            Client::connect_tls(addr.as_str(), name, &connector).await?
+        }
+        (Some(addr), None) => Client::connect_tcp(addr.as_str()).await?,
+        (None, _) => Client::connect_unix(&opt.socket).await?,
+    };
+    // the server takes the owner from the client certificate
+    let owner = match opt.tls_cert {
+        Some(_) => opt.owner.clone().unwrap_or_default(),
+        None => opt.owner.clone().or_else(|| std::env::var("USER").ok()).unwrap_or_default(),
+    };
+    let mut client = client.owner(owner);
+    if let Some(token) = opt.token.clone().or_else(|| std::env::var("FORWARDER_TOKEN").ok()) {
+        client = client.token(token);
+    }
+
+    if let Some(id) = &opt.cancel {
+        client.cancel(id).await?;
+        return Ok(0);
+    }
+    if let Some(id) = &opt.status {
+        print_job(&client.status(id).await?);
+        return Ok(0);
+    }
+    if let Some(id) = &opt.result {
+        let response = client.result(id).await?;
+        print_output(&response)?;
+        return Ok(exit_code(&response));
+    }
+    if opt.list {
+        for job in client.list().await? {
+            print_job(&job);
+        }
+        return Ok(0);
+    }
+
+    if let Some([local, remote]) = opt.put.as_deref() {
+        let mut put = Put { path: remote.clone(), in_place: opt.in_place, ..Default::default() };
+        if opt.preserve {
+            let meta = std::fs::metadata(local)?;
+            put.user = Some(meta.uid().to_string());
+            put.group = Some(meta.gid().to_string());
+        }
+        client.put(local, put).await?;
+        return Ok(0);
+    }
+    if let Some([remote, local]) = opt.get.as_deref() {
+        client.get(remote, local, opt.preserve).await?;
+        return Ok(0);
+    }
+
+    if opt.command.is_empty() {
+        return Err(anyhow!("No command given"));
+    }
+    let stdin = !(opt.no_stdin || opt.tty || std::io::stdin().is_terminal());
+    let (bash, argv) = match opt.shell || (opt.command.len() == 1 && !opt.exec) {
+        true => (client::shell_join(&opt.command), Vec::new()),
+        false => (String::new(), opt.command.clone()),
+    };
+    let mut request = Request {
+        id: opt.id.clone(),
+        bash,
+        argv,
+        cwd: opt.cwd.clone(),
+        env: opt.env.iter().cloned().collect::<HashMap<_, _>>(),
+        profile: opt.profile.clone(),
+        timeout_secs: opt.timeout,
+        ..Default::default()
+    };
+    // jobs and whole responses take their input at once
+    if stdin && (opt.submit || opt.no_stream) {
+        let mut input = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut input)?;
+        let (input, encoding) = protocol::encode(&input);
+        (request.input, request.input_encoding) = (Some(input), encoding);
+    }
+    if opt.submit {
+        println!("{}", client.submit(request).await?.id);
+        return Ok(0);
+    }
+    if opt.tty {
+        return session(&mut client, request).await;
+    }
+    if opt.no_stream {
+        let response = client.request(request).await?;
+        print_output(&response)?;
+        return Ok(exit_code(&response));
+    }
+    let input = stdin.then(|| read_input(std::io::stdin()));
+    stream(&mut client, request, input, &mut std::io::stdout(), &mut std::io::stderr()).await
+}
+
+/// Send chunks read from `reader` as they come, up to its end. Reads block,
+/// the thread is left behind when the command exits first.
+fn read_input(mut reader: impl Read + Send + 'static) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        let mut buf = vec![0; CHUNK_LEN];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("fwd: failed to read stdin: {e}");
+                    return;
+                }
+            };
+            if tx.blocking_send(buf[..n].to_vec()).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+/// Run `request` with `input` as its stdin and its output written to `stdout`
+/// and `stderr` as it arrives, return our exit code
+async fn stream(
+    client: &mut Client,
+    request: Request,
+    input: Option<mpsc::Receiver<Vec<u8>>>,
+    stdout: &mut impl Write,
+    stderr: &mut impl Write,
+) -> Result<u8> {
+    let response = client
+        .stream(request, input, |stream, data| {
+            // output is lost anyway once our stdout is closed
+            let _ = match stream {
+                Stream::Stdout => stdout.write_all(data).and_then(|_| stdout.flush()),
+                Stream::Stderr => stderr.write_all(data),
+            };
+        })
+        .await?;
+    Ok(finish(&response))
+}
+
+/// Report what went wrong with a streamed `response`, return our exit code
+fn finish(response: &Response) -> u8 {
+    // the command could not be started, e.g. its program is missing
+    if response.status.is_none() && !response.error.is_empty() {
+        eprintln!("fwd: {}", response.error);
+    }
+    if response.truncated {
+        eprintln!("fwd: output exceeded the cap of the server and was truncated");
+    }
+    exit_code(response)
+}
+
+/// Run `request` in a terminal of the size of ours, with our terminal in raw
+/// mode so that keys, Ctrl-C included, are passed to the command
+async fn session(client: &mut Client, request: Request) -> Result<u8> {
+    let stdout = std::io::stdout();
+    let (rows, cols) = pty::size(stdout.as_raw_fd()).unwrap_or((24, 80));
+    let tty = Tty { rows, cols, term: std::env::var("TERM").ok() };
+    let stdin = std::io::stdin();
+    let raw = match stdin.is_terminal() {
+        true => Some(RawMode::enable(stdin.as_raw_fd())?),
+        false => None,
+    };
+    let (tx, rx) = mpsc::channel(16);
+    let keys = tx.clone();
+    let mut typed = read_input(std::io::stdin());
+    tokio::spawn(async move {
+        while let Some(data) = typed.recv().await {
+            let (data, encoding) = protocol::encode(&data);
+            if keys.send(SessionFrame::Input { data, encoding }).await.is_err() {
+                return;
+            }
+        }
+        let _ = keys.send(SessionFrame::Eof).await;
+    });
+    let mut resized = signal(SignalKind::window_change())?;
+    tokio::spawn(async move {
+        while resized.recv().await.is_some() {
+            if let Ok((rows, cols)) = pty::size(std::io::stdout().as_raw_fd())
+                && tx.send(SessionFrame::Resize { rows, cols }).await.is_err()
+            {
+                return;
+            }
+        }
+    });
+    let mut stdout = stdout.lock();
+    let response = client
+        .session(request, tty, rx, |data| {
+            let _ = stdout.write_all(data).and_then(|_| stdout.flush());
+        })
+        .await;
+    drop(raw);
+    Ok(finish(&response?))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let opt = Cli::parse();
+    match run(opt).await {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            match e.downcast_ref::<ServerError>() {
+                Some(e) => eprintln!("fwd: {}", e.message),
+                None => eprintln!("fwd: {e}"),
+            }
+            ExitCode::from(FAILURE)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use forwarder::exec::Termination;
+    use forwarder::protocol::StreamFrame;
+
+    #[tokio::test]
+    async fn test_stream_input() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let request: Request = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert!(request.stdin && request.input.is_none());
+            // the command echoes its input
+            let mut input = Vec::new();
+            while let SessionFrame::Input { data, encoding } = protocol::recv(&mut server).await.unwrap().unwrap() {
+                input.extend(protocol::decode(&data, encoding).unwrap());
+            }
+            let (data, encoding) = protocol::encode(&input);
+            protocol::send(&mut server, &StreamFrame::Stdout { data, encoding }).await.unwrap();
+            let exit = Response { code: Some(3), status: Some(Termination::Exited), ..Default::default() };
+            protocol::send(&mut server, &StreamFrame::Exit(exit)).await.unwrap();
+        });
+
+        let mut client = Client::new(client);
+        let data: Vec<u8> = (0..=255).cycle().take(3 * CHUNK_LEN).collect();
+        let input = read_input(std::io::Cursor::new(data.clone()));
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let code = stream(&mut client, Request::default(), Some(input), &mut stdout, &mut stderr).await.unwrap();
+        assert_eq!((code, stdout == data, stderr.as_slice()), (3, true, &b""[..]));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exit_before_input_ends() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let _: Request = protocol::recv(&mut server).await.unwrap().unwrap();
+            let frame: SessionFrame = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert_eq!(frame, SessionFrame::Input { data: "line\n".to_string(), encoding: None });
+            let exit = Response { code: Some(0), status: Some(Termination::Exited), ..Default::default() };
+            protocol::send(&mut server, &StreamFrame::Exit(exit)).await.unwrap();
+            // input is ended for the next request on the connection
+            let frame: SessionFrame = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert_eq!(frame, SessionFrame::Eof);
+        });
+
+        // like `tail -f`, input goes on while the command exits
+        let mut client = Client::new(client);
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(b"line\n".to_vec()).await.unwrap();
+        let code = stream(&mut client, Request::default(), Some(rx), &mut Vec::new(), &mut Vec::new()).await.unwrap();
+        assert_eq!(code, 0);
+        server.await.unwrap();
+        drop(tx);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&Response { code: Some(2), ..Default::default() }), 2);
+        assert_eq!(exit_code(&Response { signal: Some(9), ..Default::default() }), 137);
+        assert_eq!(exit_code(&Response::default()), FAILURE);
+    }
+}
@@ -0,0 +1,402 @@
+//! Client of forwarder.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use forwarder::client::Client;
+//!
+//! let mut client = Client::connect_unix(forwarder::protocol::SOCK).await?.owner("deploy");
+//! let response = client.exec(&["systemctl", "is-active", "app"], None).await?;
+//! print!("{}", response.output);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A response the server rejected, e.g. by policy or a full queue, is returned
+//! as [`ServerError`]. A command which ran is answered with its response
+//! whatever its exit code.
+use anyhow::{Result, anyhow};
+use std::fmt;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+use tokio::sync::{Notify, mpsc};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+use crate::auth::ErrorKind;
+use crate::exec::Stream;
+use crate::protocol::{self, JobInfo, Request, Response, SessionFrame, StreamFrame};
+use crate::pty::Tty;
+use crate::transfer::{self, FileInfo, Get, Owner, Put};
+
+/// Connection to forwarder
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Request rejected or failed by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+pub struct Client {
+    io: Box<dyn Io>,
+    owner: String,
+    token: Option<String>,
+}
+
+impl Client {
+    /// Client over `io`, speaking the framed protocol
+    pub fn new(io: impl Io + 'static) -> Self {
+        Client {
+            io: Box::new(io),
+            owner: String::new(),
+            token: None,
+        }
+    }
+
+    pub async fn connect_unix(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path).await.map_err(|e| anyhow!("Failed to connect {}: {e}", path.display()))?;
+        Ok(Client::new(stream))
+    }
+
+    pub async fn connect_tcp(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Client::new(stream))
+    }
+
+    /// Connect on TCP with TLS, the server certificate must be valid for `name`,
+    /// see [`crate::tls::connector`]
+    pub async fn connect_tls(addr: impl ToSocketAddrs, name: &str, connector: &TlsConnector) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let name = ServerName::try_from(name.to_string())?;
+        Ok(Client::new(connector.connect(name, stream).await?))
+    }
+
+    /// Owner sent with every request
+    pub fn owner(mut self, owner: impl ToString) -> Self {
+        self.owner = owner.to_string();
+        self
+    }
+
+    /// Token authenticating the owner on TCP
+    pub fn token(mut self, token: impl ToString) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Send `request` with the version, owner and token of the client, and
+    /// read its response
+    pub async fn request(&mut self, mut request: Request) -> Result<Response> {
+        self.fill(&mut request);
+        protocol::send(&mut self.io, &request).await?;
+        let response: Response = protocol::recv(&mut self.io).await?.ok_or_else(|| anyhow!("Connection closed by server"))?;
+        check(response)
+    }
+
+    fn fill(&self, request: &mut Request) {
+        request.version = protocol::VERSION;
+        if request.owner.is_empty() {
+            request.owner = self.owner.clone();
+        }
+        if request.token.is_none() {
+            request.token = self.token.clone();
+        }
+    }
+
+    /// Run the command line `command` with bash and wait for its output
+    pub async fn run(&mut self, command: &str, input: Option<&str>) -> Result<Response> {
+        self.request(Request {
+            bash: command.to_string(),
+            input: input.map(String::from),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Execute `argv` without shell and wait for its output
+    pub async fn exec<S: ToString>(&mut self, argv: &[S], input: Option<&str>) -> Result<Response> {
+        self.request(Request {
+            argv: argv.iter().map(S::to_string).collect(),
+            input: input.map(String::from),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Run `request` with its output passed to `output` as it arrives, and
+    /// `input` sent as its stdin if given, return the exit status. Give
+    /// `request` an id to cancel it from elsewhere.
+    pub async fn stream(
+        &mut self,
+        mut request: Request,
+        input: Option<mpsc::Receiver<Vec<u8>>>,
+        mut output: impl FnMut(Stream, &[u8]),
+    ) -> Result<Response> {
+        request.stream = true;
+        request.stdin = input.is_some();
+        self.fill(&mut request);
+        protocol::send(&mut self.io, &request).await?;
+        let (mut reader, mut writer) = tokio::io::split(&mut self.io);
+        let exited = Notify::new();
+        let send = async {
+            let Some(mut input) = input else {
+                return Ok(());
+            };
+            // input is cut short once the command exited, between frames
+            loop {
+                let data = tokio::select! {
+                    data = input.recv() => data,
+                    _ = exited.notified() => None,
+                };
+                let Some(data) = data else {
+                    break;
+                };
+                let (data, encoding) = protocol::encode(&data);
+                protocol::send(&mut writer, &SessionFrame::Input { data, encoding }).await?;
+            }
+            protocol::send(&mut writer, &SessionFrame::Eof).await
+        };
+        let receive = async {
+            let result = loop {
+                match protocol::recv(&mut reader).await {
+                    Ok(Some(StreamFrame::Started { .. })) => (),
+                    Ok(Some(StreamFrame::Stdout { data, encoding })) => output(Stream::Stdout, &protocol::decode(&data, encoding)?),
+                    Ok(Some(StreamFrame::Stderr { data, encoding })) => output(Stream::Stderr, &protocol::decode(&data, encoding)?),
+                    Ok(Some(StreamFrame::Exit(response))) => break Ok(response),
+                    Ok(None) => break Err(anyhow!("Connection closed by server before the command exited")),
+                    Err(e) => break Err(e),
+                }
+            };
+            exited.notify_one();
+            result
+        };
+        let (sent, response) = tokio::join!(send, receive);
+        let response = response?;
+        sent?;
+        check(response)
+    }
+
+    /// Run `request` in a terminal of `tty` with the frames of `input` sent
+    /// as they come and its terminal output passed to `output`, return the
+    /// exit status
+    pub async fn session(
+        &mut self,
+        mut request: Request,
+        tty: Tty,
+        mut input: mpsc::Receiver<SessionFrame>,
+        mut output: impl FnMut(&[u8]),
+    ) -> Result<Response> {
+        request.tty = Some(tty);
+        self.fill(&mut request);
+        protocol::send(&mut self.io, &request).await?;
+        let (mut reader, mut writer) = tokio::io::split(&mut self.io);
+        let send = async {
+            while let Some(frame) = input.recv().await {
+                protocol::send(&mut writer, &frame).await?;
+            }
+            // the session goes on until the command exits
+            std::future::pending().await
+        };
+        let receive = async {
+            loop {
+                match protocol::recv(&mut reader).await? {
+                    Some(StreamFrame::Started { .. }) => (),
+                    Some(StreamFrame::Stdout { data, encoding } | StreamFrame::Stderr { data, encoding }) => {
+                        output(&protocol::decode(&data, encoding)?)
+                    }
+                    Some(StreamFrame::Exit(response)) => return check(response),
+                    None => return Err(anyhow!("Connection closed by server before the session ended")),
+                }
+            }
+        };
+        tokio::select! {
+            result = send => result,
+            result = receive => result,
+        }
+    }
+
+    /// Copy the local file `local` to `put.path` on the server, `put` gets its
+    /// size, and its mode unless it gives one
+    pub async fn put(&mut self, local: impl AsRef<Path>, mut put: Put) -> Result<FileInfo> {
+        // symlinks are followed here, they only fail transfers on the server
+        let (file, info) = transfer::open(tokio::fs::canonicalize(local).await?).await?;
+        put.size = info.size;
+        put.mode.get_or_insert(info.mode);
+        let size = put.size;
+        self.request(Request { put: Some(put), ..Default::default() }).await?;
+        transfer::send(file, size, &mut self.io).await?;
+        let response: Response = protocol::recv(&mut self.io).await?.ok_or_else(|| anyhow!("Connection closed by server"))?;
+        written(check(response)?)
+    }
+
+    /// Copy the file `path` of the server to the local file `local` with its
+    /// mode, and with its owner too if `preserve` and we may
+    pub async fn get(&mut self, path: &str, local: impl AsRef<Path>, preserve: bool) -> Result<FileInfo> {
+        let response = self.request(Request { get: Some(Get { path: path.to_string() }), ..Default::default() }).await?;
+        let info = written(response)?;
+        let local = transfer::resolve(local, true).await?;
+        let put = Put {
+            path: local.display().to_string(),
+            size: info.size,
+            mode: Some(info.mode),
+            ..Default::default()
+        };
+        // a replaced file keeps its owner otherwise
+        let owner = match preserve {
+            true => Owner { uid: Some(info.uid), gid: Some(info.gid), ..Default::default() },
+            false => Owner::default(),
+        };
+        match transfer::receive(&mut self.io, &put, &local, owner).await? {
+            Ok(file) => Ok(FileInfo { sha256: file.sha256, ..info }),
+            Err((_, error)) => Err(anyhow!(error)),
+        }
+    }
+
+    /// Start `request` as a job
+    pub async fn submit(&mut self, request: Request) -> Result<JobInfo> {
+        let response = self.request(Request { submit: true, ..request }).await?;
+        job(response)
+    }
+
+    pub async fn status(&mut self, id: &str) -> Result<JobInfo> {
+        let response = self.request(Request { status: Some(id.to_string()), ..Default::default() }).await?;
+        job(response)
+    }
+
+    /// Response of a finished job
+    pub async fn result(&mut self, id: &str) -> Result<Response> {
+        self.request(Request { result: Some(id.to_string()), ..Default::default() }).await
+    }
+
+    /// Jobs of the owner, oldest first
+    pub async fn list(&mut self) -> Result<Vec<JobInfo>> {
+        let response = self.request(Request { list: true, ..Default::default() }).await?;
+        Ok(response.jobs)
+    }
+
+    /// Cancel the running request or job `id`
+    pub async fn cancel(&mut self, id: &str) -> Result<()> {
+        self.request(Request { cancel: Some(id.to_string()), ..Default::default() }).await?;
+        Ok(())
+    }
+}
+
+fn check(response: Response) -> Result<Response> {
+    match response.kind {
+        Some(kind) => Err(ServerError { kind, message: response.error }.into()),
+        None => Ok(response),
+    }
+}
+
+fn written(response: Response) -> Result<FileInfo> {
+    response.file.ok_or_else(|| anyhow!("Server answered without file"))
+}
+
+fn job(response: Response) -> Result<JobInfo> {
+    response.job.map(|job| *job).ok_or_else(|| anyhow!("Server answered without job"))
+}
+
+/// Join `args` into a command line which runs them as they are, an only
+/// argument is taken as a command line already
+pub fn shell_join<S: AsRef<str>>(args: &[S]) -> String {
+    match args {
+        [command] => command.as_ref().to_string(),
+        _ => protocol::shell_quote(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::Termination;
+    use crate::protocol::Encoding;
+
+    #[tokio::test]
+    async fn test_client() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let request: Request = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert_eq!((request.version, request.owner.as_str(), request.token.as_deref()), (protocol::VERSION, "test", Some("t")));
+            let response = Response { output: request.bash, code: Some(0), ..Default::default() };
+            protocol::send(&mut server, &response).await.unwrap();
+
+            let request: Request = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert!(request.stream && request.stdin);
+            let frame: SessionFrame = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert_eq!(frame, SessionFrame::Input { data: "AP8=".to_string(), encoding: Some(Encoding::Base64) });
+            for frame in [
+                StreamFrame::Started { id: "1".to_string() },
+                StreamFrame::Stdout { data: "out".to_string(), encoding: None },
+                StreamFrame::Stderr { data: "/w==".to_string(), encoding: Some(Encoding::Base64) },
+                StreamFrame::Exit(Response { code: Some(1), status: Some(Termination::Exited), ..Default::default() }),
+            ] {
+                protocol::send(&mut server, &frame).await.unwrap();
+            }
+            // input ends after the exit, as the client still reads it
+            let frame: SessionFrame = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert_eq!(frame, SessionFrame::Eof);
+
+            let _: Request = protocol::recv(&mut server).await.unwrap().unwrap();
+            protocol::send(&mut server, &Response::error(ErrorKind::Denied, "no")).await.unwrap();
+        });
+
+        let mut client = Client::new(client).owner("test").token("t");
+        assert_eq!(client.run("echo", None).await.unwrap().output, "echo");
+        let mut output = Vec::new();
+        let (input, rx) = mpsc::channel(4);
+        input.send(vec![0, 0xff]).await.unwrap();
+        let request = Request { bash: "x".to_string(), ..Default::default() };
+        let response = client.stream(request, Some(rx), |stream, data| output.push((stream, data.to_vec()))).await.unwrap();
+        drop(input);
+        assert_eq!(response.code, Some(1));
+        assert_eq!(output, [(Stream::Stdout, b"out".to_vec()), (Stream::Stderr, vec![0xff])]);
+        let e = client.run("rm -rf /", None).await.unwrap_err();
+        assert_eq!(e.downcast_ref::<ServerError>().unwrap().kind, ErrorKind::Denied);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let request: Request = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert_eq!(request.tty.unwrap().rows, 24);
+            let frame: SessionFrame = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert_eq!(frame, SessionFrame::Input { data: "ls\r".to_string(), encoding: None });
+            protocol::send(&mut server, &StreamFrame::Stdout { data: "ls\r\n".to_string(), encoding: None }).await.unwrap();
+            let frame: SessionFrame = protocol::recv(&mut server).await.unwrap().unwrap();
+            assert_eq!(frame, SessionFrame::Eof);
+            protocol::send(&mut server, &StreamFrame::Exit(Response { code: Some(0), ..Default::default() })).await.unwrap();
+        });
+
+        let mut client = Client::new(client);
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(SessionFrame::Input { data: "ls\r".to_string(), encoding: None }).await.unwrap();
+        tx.send(SessionFrame::Eof).await.unwrap();
+        let mut output = Vec::new();
+        let tty = Tty { rows: 24, cols: 80, term: None };
+        let response = client.session(Request::default(), tty, rx, |data| output.extend_from_slice(data)).await.unwrap();
+        assert_eq!((response.code, output.as_slice()), (Some(0), &b"ls\r\n"[..]));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_shell_join() {
+        assert_eq!(shell_join(&["ls | wc -l"]), "ls | wc -l");
+        assert_eq!(shell_join(&["echo", "a b", "it's", "--x=1", ""]), r"echo 'a b' 'it'\''s' --x=1 ''");
+    }
+}
@@ -0,0 +1,320 @@
+//! Configuration file of the server.
+//!
+//! ```toml
+//! socket = "/run/forwarder/forwarder.sock"
+//! socket_mode = 0o660
+//! socket_group = "forwarder"
+//! policy_file = "/etc/forwarder/policy.json"
+//! shutdown_timeout_secs = 60
+//! idle_timeout_secs = 300
+//!
+//! [log]
+//! dir = "/var/log/forwarder/"
+//! sinks = ["file", "journald"]
+//! level = "info"
+//! modules = { "forwarder::exec" = "debug" }
+//! format = "json"
+//!
+//! [tcp]
+//! host = "0.0.0.0"
+//! port = 7788
+//! tls = { cert = "/etc/forwarder/server.pem", key = "/etc/forwarder/server.key", client_ca = "/etc/forwarder/ca.pem" }
+//!
+//! [limits]
+//! running = 32
+//! per_owner = 8
+//!
+//! [metrics]
+//! listen = "127.0.0.1:9788"
+//! ```
+//!
+//! Every setting has a default, which the server also runs with when it is
+//! given no file. The policy is read from `policy_file` or given inline as a
+//! `[policy]` table. On SIGHUP the file is read again, the policy, limits,
+//! output cap, cgroup root, redaction rules and TLS certificates apply to
+//! requests accepted afterwards. [`Config::restart_needed`] names the changed
+//! settings which only apply once the server is restarted.
+use anyhow::{Result, anyhow};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::auth::Policy;
+use crate::limit::Limits;
+use crate::log::{Format, PATTERN, Sink};
+use crate::protocol::SOCK;
+use crate::tls::TlsFiles;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Unix socket, unused when systemd passes one
+    pub socket: PathBuf,
+    /// Permissions of the socket, only its owner may connect by default. Other
+    /// users may only be let in with a policy.
+    pub socket_mode: u32,
+    /// Group name or gid owning the socket
+    pub socket_group: Option<String>,
+    pub log: LogConfig,
+    /// TCP listener, disabled without it
+    pub tcp: Option<TcpConfig>,
+    /// Policy file of allowed peers and commands, everything is allowed without a policy
+    pub policy_file: Option<PathBuf>,
+    /// Policy given inline instead of `policy_file`
+    pub policy: Option<Policy>,
+    /// Max output (Mb) of one command, output beyond it is dropped. At most
+    /// [`MAX_OUTPUT`](crate::protocol::MAX_OUTPUT), so that a response fits in a frame
+    pub max_output: usize,
+    /// cgroup v2 directory delegated to forwarder, transient cgroups of profiles are created in it
+    pub cgroup_root: PathBuf,
+    pub jobs: JobsConfig,
+    pub limits: Limits,
+    pub audit: AuditConfig,
+    /// HTTP listener of `/metrics` and `/healthz`, disabled without it
+    pub metrics: Option<MetricsConfig>,
+    /// Seconds to wait for running commands on SIGTERM before they are cancelled
+    pub shutdown_timeout_secs: u64,
+    /// Seconds a connection may idle between requests before it is closed
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Directory of log files, created if missing
+    pub dir: String,
+    /// Size (Mb) of a log file before it is rolled
+    pub size: u64,
+    /// Rolled files kept
+    pub max: u32,
+    /// Where records go, see [`crate::log`]
+    pub sinks: Vec<Sink>,
+    /// Level of modules not in `modules`
+    pub level: LevelFilter,
+    /// Level by module path, like `forwarder::exec`
+    pub modules: BTreeMap<String, LevelFilter>,
+    /// Format of the file and stderr sinks
+    pub format: Format,
+    /// log4rs pattern of the `pattern` format
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    pub host: String,
+    pub port: u16,
+    /// Serve TLS, plaintext without it
+    pub tls: Option<TlsFiles>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// Seconds to keep results of finished jobs
+    pub retention_secs: u64,
+    /// Directory to keep jobs across restarts, jobs are kept in memory only without it
+    pub dir: Option<PathBuf>,
+    /// Seconds a job may run, a shorter `timeout_secs` of its request applies
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Regexes of secrets to redact besides the default ones, only their group
+    /// `secret` is redacted if they have one
+    pub redact: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to listen on, as `host:port`
+    pub listen: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            socket: PathBuf::from(SOCK),
+            socket_mode: 0o600,
+            socket_group: None,
+            log: LogConfig::default(),
+            tcp: None,
+            policy_file: None,
+            policy: None,
+            max_output: 8,
+            cgroup_root: PathBuf::from("/sys/fs/cgroup/forwarder"),
+            jobs: JobsConfig::default(),
+            limits: Limits::default(),
+            audit: AuditConfig::default(),
+            metrics: None,
+            shutdown_timeout_secs: 30,
+            idle_timeout_secs: 300,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            dir: "/var/log/forwarder/".to_string(),
+            size: 50,
+            max: 50,
+            sinks: vec![Sink::File],
+            level: LevelFilter::Info,
+            modules: BTreeMap::new(),
+            format: Format::Pattern,
+            pattern: PATTERN.to_string(),
+        }
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig { host: "127.0.0.1".to_string(), port: 7788, tls: None }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { listen: "127.0.0.1:9788".to_string() }
+    }
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig { retention_secs: 3600, dir: None, timeout_secs: 86400 }
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+        let config: Config = toml::from_str(&content).map_err(|e| anyhow!("Invalid config {}: {e}", path.display()))?;
+        if config.policy_file.is_some() && config.policy.is_some() {
+            return Err(anyhow!("Invalid config {}: both `policy_file` and `policy` are given", path.display()));
+        }
+        if config.idle_timeout_secs == 0 {
+            return Err(anyhow!("Invalid config {}: `idle_timeout_secs` is 0, connections would be closed before their first request", path.display()));
+        }
+        if config.log.sinks.is_empty() {
+            return Err(anyhow!("Invalid config {}: `log.sinks` is empty, nothing would be logged", path.display()));
+        }
+        Ok(config)
+    }
+
+    /// Policy of the config, read from its file
+    pub fn load_policy(&self) -> Result<Option<Policy>> {
+        match &self.policy_file {
+            Some(path) => Ok(Some(Policy::load(path).map_err(|e| anyhow!("Failed to load policy {}: {e}", path.display()))?)),
+            None => Ok(self.policy.clone()),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    /// Settings changed from `self` which only apply after a restart
+    pub fn restart_needed(&self, new: &Config) -> Vec<&'static str> {
+        let listener = |config: &Config| config.tcp.as_ref().map(|tcp| (tcp.host.clone(), tcp.port, tcp.tls.is_some()));
+        let tls = |config: &Config| config.tcp.as_ref().and_then(|tcp| tcp.tls.clone());
+        [
+            ("socket", self.socket != new.socket),
+            ("socket_mode", self.socket_mode != new.socket_mode),
+            ("socket_group", self.socket_group != new.socket_group),
+            ("log", self.log != new.log),
+            ("tcp", listener(self) != listener(new)),
+            // certificates are read again from the same files
+            ("tcp.tls", listener(self) == listener(new) && tls(self) != tls(new)),
+            ("jobs", self.jobs != new.jobs),
+            ("metrics", self.metrics != new.metrics),
+            ("shutdown_timeout_secs", self.shutdown_timeout_secs != new.shutdown_timeout_secs),
+            ("idle_timeout_secs", self.idle_timeout_secs != new.idle_timeout_secs),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("forwarder-config-{name}-{}.toml", std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load() {
+        let path = write(
+            "load",
+            r#"
+socket = "/tmp/f.sock"
+socket_mode = 0o660
+max_output = 8
+
+[tcp]
+port = 7000
+tls = { cert = "server.pem", key = "server.key" }
+
+[log]
+sinks = ["stderr", "syslog"]
+modules = { "forwarder::exec" = "debug" }
+format = "json"
+
+[limits]
+running = 4
+
+[metrics]
+
+[[policy.rules]]
+owner = "deploy"
+commands = ["systemctl restart app"]
+"#,
+        );
+        let config = Config::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((config.socket.to_str(), config.socket_mode, config.max_output), (Some("/tmp/f.sock"), 0o660, 8));
+        let tcp = config.tcp.as_ref().unwrap();
+        assert_eq!((tcp.host.as_str(), tcp.port, tcp.tls.as_ref().unwrap().client_ca.as_ref()), ("127.0.0.1", 7000, None));
+        assert_eq!(config.limits, Limits { running: 4, ..Limits::default() });
+        assert_eq!((config.log.sinks.as_slice(), config.log.level, config.log.format), ([Sink::Stderr, Sink::Syslog].as_slice(), LevelFilter::Info, Format::Json));
+        assert_eq!(config.log.modules.get("forwarder::exec"), Some(&LevelFilter::Debug));
+        assert_eq!(config.metrics, Some(MetricsConfig::default()));
+        assert_eq!(config.load_policy().unwrap().unwrap().rules[0].owner.as_deref(), Some("deploy"));
+
+        let path = write("invalid", "sock = \"/tmp/f.sock\"\n");
+        assert!(Config::load(&path).unwrap_err().to_string().contains("unknown field `sock`"));
+        fs::write(&path, "policy_file = \"p.json\"\n[policy]\n").unwrap();
+        assert!(Config::load(&path).is_err());
+        fs::write(&path, "[log]\nsinks = []\n").unwrap();
+        assert!(Config::load(&path).unwrap_err().to_string().contains("`log.sinks` is empty"));
+        fs::write(&path, "idle_timeout_secs = 0\n").unwrap();
+        assert!(Config::load(&path).unwrap_err().to_string().contains("`idle_timeout_secs` is 0"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_restart_needed() {
+        let old = Config::default();
+        let mut new = Config { max_output: 1, limits: Limits { running: 1, ..Limits::default() }, ..Config::default() };
+        assert!(old.restart_needed(&new).is_empty());
+        new.socket_mode = 0o660;
+        new.tcp = Some(TcpConfig::default());
+        assert_eq!(old.restart_needed(&new), ["socket_mode", "tcp"]);
+    }
+}
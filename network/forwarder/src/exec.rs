@@ -0,0 +1,595 @@
+//! Execution of commands with their output forwarded as it arrives.
+//!
+//! stdout and stderr are read in chunks and sent into a bounded channel, so a
+//! slow consumer stops the reads and the command blocks on its full pipe
+//! instead of being buffered in memory. Output beyond the cap is read and
+//! dropped, the command keeps running until it exits.
+//!
+//! Each command leads its own process group. On timeout or cancellation the
+//! whole group gets SIGTERM, then SIGKILL if it is still running after
+//! [`KILL_GRACE`], so pipelines and background jobs of the command are stopped
+//! too. The group is also killed when the execution is dropped before the
+//! command exits.
+//!
+//! A [`session`] runs the command in a pseudo-terminal instead, relaying input
+//! to it and its output, stdout and stderr mixed as on a terminal, back.
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::process::ExitStatusExt;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use log::warn;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use crate::protocol::{self, Encoding};
+use crate::pty;
+
+const CHUNK_LEN: usize = 8192;
+/// Time between SIGTERM and SIGKILL
+pub const KILL_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub stream: Stream,
+    pub data: Vec<u8>,
+}
+
+/// How a command ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Termination {
+    /// Exited by itself with an exit code
+    Exited,
+    /// Killed by a signal not sent by forwarder
+    Signaled,
+    /// Killed after running longer than its timeout
+    TimedOut,
+    /// Killed on request of a client or because the client is gone
+    Cancelled,
+}
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub status: ExitStatus,
+    pub termination: Termination,
+    /// Output exceeded the cap and was partly dropped
+    pub truncated: bool,
+}
+
+/// Where stdin of a command comes from
+#[derive(Debug, Default)]
+pub enum Stdin {
+    #[default]
+    Null,
+    /// Written at once
+    Data(Vec<u8>),
+    /// Written as it arrives, closed once the sender is dropped
+    Stream(mpsc::Receiver<Vec<u8>>),
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub input: Stdin,
+    /// Cap of stdout and stderr together in bytes
+    pub max_output: usize,
+    pub timeout: Option<Duration>,
+}
+
+impl Options {
+    pub fn new(input: Stdin) -> Self {
+        Options {
+            input,
+            max_output: usize::MAX,
+            timeout: None,
+        }
+    }
+}
+
+/// Input of a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// Typed on the terminal
+    Data(Vec<u8>),
+    Resize { rows: u16, cols: u16 },
+    /// No more input, the terminal is hung up
+    Eof,
+}
+
+/// Process group which is killed on drop unless it is released
+struct Group(Option<i32>);
+
+impl Group {
+    fn signal(&self, signal: i32) {
+        if let Some(pgid) = self.0 {
+            unsafe { libc::killpg(pgid, signal) };
+        }
+    }
+
+    /// Terminate the group and wait `run` to end
+    async fn stop<T>(&self, mut run: Pin<&mut impl Future<Output = T>>) -> T {
+        self.signal(libc::SIGTERM);
+        tokio::select! {
+            result = &mut run => return result,
+            _ = sleep(KILL_GRACE) => warn!("Process group {:?} survived SIGTERM, killing it", self.0),
+        }
+        self.signal(libc::SIGKILL);
+        run.await
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        self.signal(libc::SIGKILL);
+    }
+}
+
+/// Run `command`, sending at most `max_output` bytes of its output to `tx`.
+/// The command is killed when `cancel` completes before it exits.
+pub async fn execute(
+    mut command: Command,
+    options: Options,
+    cancel: impl Future<Output = ()>,
+    tx: mpsc::Sender<Chunk>,
+) -> io::Result<Outcome> {
+    command
+        .stdin(if matches!(options.input, Stdin::Null) { Stdio::null() } else { Stdio::piped() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    let mut child = command.spawn()?;
+    let mut group = Group(child.id().map(|pid| pid as i32));
+    // written aside so that a command which outputs before reading all input does not block
+    match (options.input, child.stdin.take()) {
+        (Stdin::Data(input), Some(mut stdin)) => {
+            tokio::spawn(async move {
+                let _ = stdin.write_all(&input).await;
+            });
+        }
+        (Stdin::Stream(mut input), Some(mut stdin)) => {
+            tokio::spawn(async move {
+                while let Some(data) = input.recv().await {
+                    if stdin.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        _ => (),
+    }
+
+    let remaining = AtomicUsize::new(options.max_output);
+    let stdout = child.stdout.take().ok_or_else(|| io::Error::other("stdout not captured"))?;
+    let stderr = child.stderr.take().ok_or_else(|| io::Error::other("stderr not captured"))?;
+    let run = async {
+        tokio::join!(
+            pump(stdout, Stream::Stdout, &tx, &remaining),
+            pump(stderr, Stream::Stderr, &tx, &remaining),
+            child.wait(),
+        )
+    };
+    let deadline = async {
+        match options.timeout {
+            Some(timeout) => sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(run);
+    let (killed, (out, err, status)) = tokio::select! {
+        result = &mut run => (None, result),
+        _ = deadline => (Some(Termination::TimedOut), group.stop(run.as_mut()).await),
+        _ = cancel => (Some(Termination::Cancelled), group.stop(run.as_mut()).await),
+    };
+    // the group may be reused once the command is reaped
+    group.0 = None;
+
+    let status = status?;
+    let termination = match killed {
+        Some(termination) => termination,
+        None if status.signal().is_some() => Termination::Signaled,
+        None => Termination::Exited,
+    };
+    Ok(Outcome {
+        status,
+        termination,
+        truncated: out? | err?,
+    })
+}
+
+/// Run `command` as an interactive session in a new terminal of `rows` and
+/// `cols`. `input` is relayed to the terminal and at most `max_output` bytes of
+/// its output are sent to `tx` as stdout. The command is killed as by
+/// [`execute`], and gets SIGHUP at [`Input::Eof`].
+pub async fn session(
+    mut command: Command,
+    (rows, cols): (u16, u16),
+    options: Options,
+    cancel: impl Future<Output = ()>,
+    mut input: mpsc::Receiver<Input>,
+    tx: mpsc::Sender<Chunk>,
+) -> io::Result<Outcome> {
+    let (master, terminal) = pty::open(rows, cols)?;
+    command
+        .stdin(Stdio::from(terminal.try_clone()?))
+        .stdout(Stdio::from(terminal.try_clone()?))
+        .stderr(Stdio::from(terminal));
+    // the command leads its own session, so its process group is ours to kill
+    unsafe { command.pre_exec(pty::attach) };
+    let mut child = command.spawn()?;
+    // reads of the master end once the command and its children close the terminal, not ours
+    drop(command);
+    let mut group = Group(child.id().map(|pid| pid as i32));
+    pty::set_nonblocking(master.as_raw_fd())?;
+    let master = AsyncFd::new(master)?;
+
+    let remaining = AtomicUsize::new(options.max_output);
+    let pgid = group.0;
+    let run = async { tokio::join!(relay(&master, &mut input, &tx, &remaining, pgid), child.wait()) };
+    let deadline = async {
+        match options.timeout {
+            Some(timeout) => sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(run);
+    let (killed, (out, status)) = tokio::select! {
+        result = &mut run => (None, result),
+        _ = deadline => (Some(Termination::TimedOut), group.stop(run.as_mut()).await),
+        _ = cancel => (Some(Termination::Cancelled), group.stop(run.as_mut()).await),
+    };
+    group.0 = None;
+
+    let status = status?;
+    let termination = match killed {
+        Some(termination) => termination,
+        None if status.signal().is_some() => Termination::Signaled,
+        None => Termination::Exited,
+    };
+    Ok(Outcome {
+        status,
+        termination,
+        truncated: out?,
+    })
+}
+
+/// Relay `input` to the terminal and its output to `tx` until the terminal is
+/// closed, return whether output was dropped
+async fn relay(
+    master: &AsyncFd<OwnedFd>,
+    input: &mut mpsc::Receiver<Input>,
+    tx: &mpsc::Sender<Chunk>,
+    remaining: &AtomicUsize,
+    pgid: Option<i32>,
+) -> io::Result<bool> {
+    let mut truncated = false;
+    let mut open = true;
+    // input not written yet, as the command does not read it
+    let mut pending = Vec::new();
+    loop {
+        tokio::select! {
+            data = read(master) => {
+                let data = match data {
+                    Ok(data) if !data.is_empty() => data,
+                    // EIO once the terminal is closed on the other side
+                    Ok(_) => return Ok(truncated),
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) => return Ok(truncated),
+                    Err(e) => return Err(e),
+                };
+                let n = data.len();
+                let allowed = match remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| Some(r.saturating_sub(n))) {
+                    Ok(r) | Err(r) => r.min(n),
+                };
+                truncated |= allowed < n;
+                if allowed > 0 {
+                    let _ = tx.send(Chunk { stream: Stream::Stdout, data: data[..allowed].to_vec() }).await;
+                }
+            }
+            written = write(master, &pending), if !pending.is_empty() => {
+                pending.drain(..written?);
+            }
+            next = input.recv(), if open && pending.is_empty() => match next {
+                Some(Input::Data(data)) => pending = data,
+                Some(Input::Resize { rows, cols }) => {
+                    if let Err(e) = pty::resize(master.as_raw_fd(), rows, cols) {
+                        warn!("Failed to resize terminal to {rows}x{cols}: {e}");
+                    }
+                }
+                Some(Input::Eof) => {
+                    if let Some(pgid) = pgid {
+                        unsafe { libc::killpg(pgid, libc::SIGHUP) };
+                    }
+                    open = false;
+                }
+                None => open = false,
+            },
+        }
+    }
+}
+
+async fn read(fd: &AsyncFd<OwnedFd>) -> io::Result<Vec<u8>> {
+    loop {
+        let mut guard = fd.readable().await?;
+        let mut buf = vec![0u8; CHUNK_LEN];
+        let result = guard.try_io(|fd| {
+            match unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+                -1 => Err(io::Error::last_os_error()),
+                n => Ok(n as usize),
+            }
+        });
+        if let Ok(n) = result {
+            buf.truncate(n?);
+            return Ok(buf);
+        }
+    }
+}
+
+async fn write(fd: &AsyncFd<OwnedFd>, data: &[u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = fd.writable().await?;
+        let result = guard.try_io(|fd| {
+            match unsafe { libc::write(fd.as_raw_fd(), data.as_ptr() as *const libc::c_void, data.len()) } {
+                -1 => Err(io::Error::last_os_error()),
+                n => Ok(n as usize),
+            }
+        });
+        if let Ok(written) = result {
+            return written;
+        }
+    }
+}
+
+/// Run `command` and collect its output, as `(stdout, stderr)`
+pub async fn collect(
+    command: Command,
+    options: Options,
+    cancel: impl Future<Output = ()>,
+) -> io::Result<(Outcome, Vec<u8>, Vec<u8>)> {
+    let (tx, mut rx) = mpsc::channel::<Chunk>(16);
+    let (mut stdout, mut stderr) = (vec![], vec![]);
+    let receive = async {
+        while let Some(chunk) = rx.recv().await {
+            match chunk.stream {
+                Stream::Stdout => stdout.extend_from_slice(&chunk.data),
+                Stream::Stderr => stderr.extend_from_slice(&chunk.data),
+            }
+        }
+    };
+    let (outcome, _) = tokio::join!(execute(command, options, cancel, tx), receive);
+    Ok((outcome?, stdout, stderr))
+}
+
+/// Forward `reader` to `tx` until EOF, return whether output was dropped
+async fn pump(
+    mut reader: impl AsyncRead + Unpin,
+    stream: Stream,
+    tx: &mpsc::Sender<Chunk>,
+    remaining: &AtomicUsize,
+) -> io::Result<bool> {
+    let mut buf = vec![0u8; CHUNK_LEN];
+    let mut truncated = false;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(truncated);
+        }
+        let allowed = match remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| Some(r.saturating_sub(n))) {
+            Ok(r) | Err(r) => r.min(n),
+        };
+        truncated |= allowed < n;
+        // output is still drained when consumer is gone
+        if allowed > 0 {
+            let _ = tx.send(Chunk { stream, data: buf[..allowed].to_vec() }).await;
+        }
+    }
+}
+
+/// Encode output split into chunks with [`protocol::encode`], a UTF-8
+/// character cut at the end of a chunk is kept until the next one so that
+/// text stays text.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn decode(&mut self, data: &[u8]) -> (String, Option<Encoding>) {
+        self.pending.extend_from_slice(data);
+        let keep = match std::str::from_utf8(&self.pending) {
+            Ok(_) => 0,
+            // incomplete sequence at the end
+            Err(e) if e.error_len().is_none() => self.pending.len() - e.valid_up_to(),
+            Err(_) => 0,
+        };
+        let rest = self.pending.split_off(self.pending.len() - keep);
+        let encoded = protocol::encode(&self.pending);
+        self.pending = rest;
+        encoded
+    }
+
+    /// Bytes left at the end of the stream
+    pub fn finish(&mut self) -> (String, Option<Encoding>) {
+        let encoded = protocol::encode(&self.pending);
+        self.pending.clear();
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bash(cmd: &str) -> Command {
+        let mut command = Command::new("bash");
+        command.arg("-c").arg(cmd);
+        command
+    }
+
+    #[tokio::test]
+    async fn test_stream_order() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let task = tokio::spawn(execute(bash("echo out; sleep 0.2; echo err >&2"), Options::new(Stdin::Null), std::future::pending(), tx));
+        let first = rx.recv().await.unwrap();
+        assert_eq!(first, Chunk { stream: Stream::Stdout, data: b"out\n".to_vec() });
+        // stdout arrives while the command is still running
+        assert!(!task.is_finished());
+        let second = rx.recv().await.unwrap();
+        assert_eq!(second, Chunk { stream: Stream::Stderr, data: b"err\n".to_vec() });
+        let outcome = task.await.unwrap().unwrap();
+        assert!(outcome.status.success());
+        assert_eq!(outcome.termination, Termination::Exited);
+        assert!(!outcome.truncated);
+    }
+
+    /// Process exists and is not a zombie waiting for its reaper
+    fn alive(pid: &str) -> bool {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        stat.rsplit_once(") ").is_some_and(|(_, rest)| !rest.starts_with('Z'))
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_group() {
+        let options = Options {
+            timeout: Some(Duration::from_millis(200)),
+            ..Options::new(Stdin::Null)
+        };
+        // background job of the command is in the same group
+        let started = std::time::Instant::now();
+        let (outcome, stdout, _) = collect(bash("sleep 30 & echo $!; wait"), options, std::future::pending())
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(outcome.termination, Termination::TimedOut);
+        assert_eq!(outcome.status.signal(), Some(libc::SIGTERM));
+        let pid = String::from_utf8(stdout).unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert!(!alive(&pid));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_signal() {
+        let cancel = sleep(Duration::from_millis(100));
+        let (outcome, _, _) = collect(bash("sleep 30"), Options::new(Stdin::Null), cancel).await.unwrap();
+        assert_eq!(outcome.termination, Termination::Cancelled);
+
+        let (outcome, _, _) = collect(bash("kill -USR1 $$"), Options::new(Stdin::Null), std::future::pending())
+            .await
+            .unwrap();
+        assert_eq!(outcome.termination, Termination::Signaled);
+        assert_eq!(outcome.status.signal(), Some(libc::SIGUSR1));
+        assert_eq!(outcome.status.code(), None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_kills_group() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let task = tokio::spawn(execute(bash("echo $$; sleep 30"), Options::new(Stdin::Null), std::future::pending(), tx));
+        let pid = String::from_utf8(rx.recv().await.unwrap().data).unwrap();
+        assert!(alive(&pid));
+        task.abort();
+        let _ = task.await;
+        sleep(Duration::from_millis(100)).await;
+        assert!(!alive(&pid));
+    }
+
+    #[tokio::test]
+    async fn test_max_output() {
+        let options = Options {
+            max_output: 1000,
+            ..Options::new(Stdin::Null)
+        };
+        let (outcome, stdout, stderr) = collect(bash("head -c 100000 /dev/zero; echo err >&2"), options, std::future::pending())
+            .await
+            .unwrap();
+        assert!(outcome.status.success());
+        assert!(outcome.truncated);
+        assert_eq!(stdout.len() + stderr.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_large_input() {
+        // command writes its output before reading all of its input
+        let input = "x".repeat(1 << 20);
+        let (outcome, stdout, _) = collect(bash("cat"), Options::new(Stdin::Data(input.clone().into_bytes())), std::future::pending())
+            .await
+            .unwrap();
+        assert!(outcome.status.success());
+        assert_eq!(stdout.len(), input.len());
+    }
+
+    #[tokio::test]
+    async fn test_stream_input() {
+        let (input_tx, input_rx) = mpsc::channel(16);
+        let task = tokio::spawn(collect(bash("od -An -tx1"), Options::new(Stdin::Stream(input_rx)), std::future::pending()));
+        input_tx.send(vec![0xff, 0]).await.unwrap();
+        input_tx.send(b"a".to_vec()).await.unwrap();
+        // stdin is closed with the sender
+        drop(input_tx);
+        let (outcome, stdout, _) = task.await.unwrap().unwrap();
+        assert!(outcome.status.success());
+        assert_eq!(String::from_utf8(stdout).unwrap().trim(), "ff 00 61");
+    }
+
+    /// Run `cmd` in a session fed with `input`, return its outcome and output
+    async fn session_output(cmd: &str, input: Vec<Input>) -> (Outcome, String) {
+        let (input_tx, input_rx) = mpsc::channel(16);
+        let (tx, mut rx) = mpsc::channel(16);
+        let task = tokio::spawn(session(bash(cmd), (24, 80), Options::new(Stdin::Null), std::future::pending(), input_rx, tx));
+        for input in input {
+            input_tx.send(input).await.unwrap();
+        }
+        let mut output = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            assert_eq!(chunk.stream, Stream::Stdout);
+            output.extend_from_slice(&chunk.data);
+        }
+        (task.await.unwrap().unwrap(), String::from_utf8(output).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let resize = Input::Resize { rows: 30, cols: 100 };
+        let (outcome, output) = session_output("test -t 0 && read line && echo \"got $line\" >&2 && stty size", vec![resize, Input::Data(b"hi\n".to_vec())]).await;
+        assert!(outcome.status.success());
+        // input is echoed by the terminal and stderr is on it too
+        assert_eq!(output, "hi\r\ngot hi\r\n30 100\r\n");
+
+        let (outcome, _) = session_output("sleep 30", vec![Input::Eof]).await;
+        assert_eq!(outcome.termination, Termination::Signaled);
+        assert_eq!(outcome.status.signal(), Some(libc::SIGHUP));
+    }
+
+    #[test]
+    fn test_decoder() {
+        let text = "héllo";
+        let bytes = text.as_bytes();
+        let mut decoder = Decoder::default();
+        // split inside `é`
+        let mut decoded = decoder.decode(&bytes[..2]).0;
+        decoded += &decoder.decode(&bytes[2..]).0;
+        decoded += &decoder.finish().0;
+        assert_eq!(decoded, text);
+
+        let binary = [b'a', 0xff, 0xfe, b'b'];
+        let (data, encoding) = decoder.decode(&binary);
+        assert_eq!(encoding, Some(Encoding::Base64));
+        assert_eq!(protocol::decode(&data, encoding).unwrap(), binary);
+        // cut character left at the end of the stream
+        assert_eq!(decoder.decode(&bytes[..2]), ("h".to_string(), None));
+        let (data, encoding) = decoder.finish();
+        assert_eq!(protocol::decode(&data, encoding).unwrap(), &bytes[1..2]);
+    }
+}
@@ -0,0 +1,224 @@
+//! Jobs submitted to run without holding the connection open.
+//!
+//! A job is kept with its response until `retention` after it finished. With a
+//! directory each job is also written there as `<id>.json`, so results survive
+//! a restart of forwarder. Jobs still running when forwarder stopped are loaded
+//! as [`JobState::Lost`].
+//!
+//! A job keeps the request it ran, without secrets, so that reading it is
+//! checked against the policy of the time it is read.
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::{JobInfo, JobState, Request, Response};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub info: JobInfo,
+    /// Request of the job without its token, input and environment values
+    pub request: Request,
+    /// Response once finished
+    pub result: Option<Response>,
+}
+
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    retention: Duration,
+    dir: Option<PathBuf>,
+    /// Held while job files are written or removed, not while jobs are read
+    files: Mutex<()>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+impl Jobs {
+    /// Keep finished jobs for `retention`, persist them into `dir` if given
+    pub fn new(retention: Duration, dir: Option<&Path>) -> io::Result<Self> {
+        let mut jobs = HashMap::new();
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_none_or(|e| e != "json") {
+                    continue;
+                }
+                match fs::read(&path).map_err(anyhow::Error::from).and_then(|d| Ok(serde_json::from_slice::<Job>(&d)?)) {
+                    Ok(mut job) => {
+                        if job.info.state == JobState::Running {
+                            job.info.state = JobState::Lost;
+                        }
+                        jobs.insert(job.info.id.clone(), job);
+                    }
+                    Err(e) => warn!("Ignore job file {}: {e}", path.display()),
+                }
+            }
+        }
+        let jobs = Jobs {
+            jobs: Mutex::new(jobs),
+            retention,
+            dir: dir.map(Path::to_path_buf),
+            files: Mutex::new(()),
+        };
+        jobs.purge();
+        Ok(jobs)
+    }
+
+    /// Add a running job of `request`, fail if its id is taken
+    pub fn submit(&self, info: JobInfo, request: &Request) -> Result<(), String> {
+        let request = Request {
+            token: None,
+            input: None,
+            env: request.env.keys().map(|k| (k.clone(), String::new())).collect(),
+            ..request.clone()
+        };
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs.contains_key(&info.id) {
+                return Err(format!("Job {} already exists", info.id));
+            }
+            jobs.insert(info.id.clone(), Job { info: info.clone(), request, result: None });
+        }
+        self.save(&info.id);
+        Ok(())
+    }
+
+    pub fn finish(&self, id: &str, response: Response) {
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            let Some(job) = jobs.get_mut(id) else {
+                return;
+            };
+            job.info.state = JobState::Finished;
+            job.info.finished = Some(now());
+            job.info.status = response.status;
+            job.info.code = response.code;
+            job.result = Some(response);
+        }
+        self.save(id);
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Jobs accepted by `filter`, oldest first
+    pub fn list(&self, filter: impl Fn(&Job) -> bool) -> Vec<JobInfo> {
+        let mut list: Vec<_> = self.jobs.lock().unwrap().values().filter(|j| filter(j)).map(|j| j.info.clone()).collect();
+        list.sort_by(|a, b| (a.submitted, &a.id).cmp(&(b.submitted, &b.id)));
+        list
+    }
+
+    /// Drop jobs which ended longer than retention ago
+    pub fn purge(&self) {
+        let deadline = now().saturating_sub(self.retention.as_secs());
+        let mut expired = Vec::new();
+        self.jobs.lock().unwrap().retain(|id, job| {
+            // lost jobs expire as if they finished when they were submitted
+            let ended = match job.info.state {
+                JobState::Running => return true,
+                JobState::Finished => job.info.finished.unwrap_or_default(),
+                JobState::Lost => job.info.submitted,
+            };
+            if ended >= deadline {
+                return true;
+            }
+            expired.push(id.clone());
+            false
+        });
+        if let Some(dir) = &self.dir
+            && !expired.is_empty()
+        {
+            let _files = self.files.lock().unwrap();
+            for id in expired {
+                let _ = fs::remove_file(dir.join(format!("{id}.json")));
+            }
+        }
+    }
+
+    /// Write the job `id` as it is when the file is written, so that files
+    /// end up with the last state whatever order saves run in
+    fn save(&self, id: &str) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let _files = self.files.lock().unwrap();
+        let Some(job) = self.get(id) else {
+            return;
+        };
+        let path = dir.join(format!("{id}.json"));
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec(&job)
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(&tmp, data))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            error!("Failed to save job {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(id: &str) -> JobInfo {
+        JobInfo {
+            id: id.to_string(),
+            owner: "test".to_string(),
+            uid: None,
+            bash: "true".to_string(),
+            state: JobState::Running,
+            submitted: now(),
+            finished: None,
+            status: None,
+            code: None,
+        }
+    }
+
+    #[test]
+    fn test_persist() {
+        let dir = std::env::temp_dir().join(format!("forwarder-jobs-{}", std::process::id()));
+        let jobs = Jobs::new(Duration::from_secs(60), Some(&dir)).unwrap();
+        let request = Request {
+            bash: "true".to_string(),
+            token: Some("secret".to_string()),
+            env: HashMap::from([("KEY".to_string(), "secret".to_string())]),
+            ..Default::default()
+        };
+        jobs.submit(info("a"), &request).unwrap();
+        jobs.submit(info("b"), &Request::default()).unwrap();
+        assert!(jobs.submit(info("a"), &Request::default()).is_err());
+        jobs.finish("a", Response { output: "done".to_string(), code: Some(0), ..Default::default() });
+
+        // restart
+        let jobs = Jobs::new(Duration::from_secs(60), Some(&dir)).unwrap();
+        let a = jobs.get("a").unwrap();
+        assert_eq!(a.info.state, JobState::Finished);
+        assert_eq!(a.result.unwrap().output, "done");
+        // secrets of the request are not kept
+        assert_eq!((a.request.bash.as_str(), a.request.token, a.request.env["KEY"].as_str()), ("true", None, ""));
+        assert_eq!(jobs.get("b").unwrap().info.state, JobState::Lost);
+        assert_eq!(jobs.list(|_| true).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retention() {
+        let jobs = Jobs::new(Duration::ZERO, None).unwrap();
+        jobs.submit(info("old"), &Request::default()).unwrap();
+        jobs.submit(info("running"), &Request::default()).unwrap();
+        jobs.finish("old", Response::default());
+        jobs.jobs.lock().unwrap().get_mut("old").unwrap().info.finished = Some(now() - 10);
+        jobs.purge();
+        assert!(jobs.get("old").is_none());
+        assert!(jobs.get("running").is_some());
+    }
+}
@@ -1 +1,15 @@
+pub mod audit;
+pub mod auth;
+pub mod client;
+pub mod config;
+pub mod exec;
+pub mod jobs;
+pub mod limit;
 pub mod log;
+pub mod metrics;
+pub mod profile;
+pub mod protocol;
+pub mod pty;
+pub mod systemd;
+pub mod tls;
+pub mod transfer;
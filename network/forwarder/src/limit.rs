@@ -0,0 +1,317 @@
+//! Bound of commands running at once.
+//!
+//! A request takes a slot before its command is spawned. Without a free slot,
+//! in total or of its owner, it waits in a FIFO queue and is rejected when the
+//! queue is full. A released slot goes to the first queued request it fits,
+//! so an owner at its own limit does not hold back requests of other owners.
+//! Limits may be changed while requests run, running commands are never
+//! stopped by a lower limit.
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Commands running at once
+    pub running: usize,
+    /// Commands of one owner running at once
+    pub per_owner: usize,
+    /// Requests waiting for a slot
+    pub queued: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { running: 64, per_owner: 16, queued: 256 }
+    }
+}
+
+impl Limits {
+    /// Fail on limits which would let no command run
+    pub fn check(&self) -> Result<(), String> {
+        match (self.running, self.per_owner) {
+            (0, _) => Err("limits.running must be at least 1".to_string()),
+            (_, 0) => Err("limits.per_owner must be at least 1".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Counters of the limiter, for operators
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    pub running: usize,
+    pub queued: usize,
+    /// Requests rejected as the queue was full
+    pub rejected: u64,
+    /// Requests which got a slot after waiting in the queue
+    pub waited: u64,
+    /// Total and longest wait of them in seconds
+    pub wait_secs: f64,
+    pub max_wait_secs: f64,
+}
+
+/// Rejection of a request when the queue is full
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Full {
+    pub queued: usize,
+}
+
+impl fmt::Display for Full {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Too many commands running, {} requests are queued already", self.queued)
+    }
+}
+
+struct Waiter {
+    owner: String,
+    tx: oneshot::Sender<()>,
+}
+
+struct State {
+    limits: Limits,
+    running: usize,
+    owners: HashMap<String, usize>,
+    queue: VecDeque<Waiter>,
+    stats: Stats,
+}
+
+impl State {
+    fn fits(&self, owner: &str) -> bool {
+        self.running < self.limits.running && self.owners.get(owner).copied().unwrap_or_default() < self.limits.per_owner
+    }
+
+    /// Grant free slots to queued requests in order
+    fn dispatch(&mut self) {
+        let mut i = 0;
+        while i < self.queue.len() {
+            if self.queue[i].tx.is_closed() {
+                self.queue.remove(i);
+                continue;
+            }
+            if !self.fits(&self.queue[i].owner) {
+                i += 1;
+                continue;
+            }
+            let waiter = self.queue.remove(i).unwrap();
+            self.take(&waiter.owner);
+            if waiter.tx.send(()).is_err() {
+                self.put(&waiter.owner);
+            }
+        }
+    }
+
+    fn take(&mut self, owner: &str) {
+        self.running += 1;
+        *self.owners.entry(owner.to_string()).or_default() += 1;
+    }
+
+    fn put(&mut self, owner: &str) {
+        self.running -= 1;
+        if let Some(count) = self.owners.get_mut(owner) {
+            *count -= 1;
+            if *count == 0 {
+                self.owners.remove(owner);
+            }
+        }
+    }
+}
+
+pub struct Limiter {
+    state: Mutex<State>,
+}
+
+/// Slot of a running command, released on drop
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+    owner: String,
+    /// Time spent in the queue
+    pub waited: Duration,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.release(&self.owner);
+    }
+}
+
+/// Place in the queue, gives the slot back if it is granted after the request is gone
+struct Queued<'a> {
+    limiter: &'a Limiter,
+    owner: &'a str,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.limiter.release(self.owner);
+            }
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        let state = State {
+            limits,
+            running: 0,
+            owners: HashMap::new(),
+            queue: VecDeque::new(),
+            stats: Stats::default(),
+        };
+        Limiter { state: Mutex::new(state) }
+    }
+
+    /// Apply `limits` to requests acquiring or waiting for a slot from now on
+    pub fn set_limits(&self, limits: Limits) {
+        let mut state = self.state.lock().unwrap();
+        state.limits = limits;
+        state.dispatch();
+    }
+
+    /// Wait for a slot of `owner`, fail at once if the queue is full
+    pub async fn acquire(&self, owner: &str) -> Result<Permit<'_>, Full> {
+        let start = Instant::now();
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            state.queue.retain(|w| !w.tx.is_closed());
+            if state.fits(owner) {
+                state.take(owner);
+                return Ok(Permit { limiter: self, owner: owner.to_string(), waited: Duration::ZERO });
+            }
+            if state.queue.len() >= state.limits.queued {
+                state.stats.rejected += 1;
+                return Err(Full { queued: state.queue.len() });
+            }
+            let (tx, rx) = oneshot::channel();
+            state.queue.push_back(Waiter { owner: owner.to_string(), tx });
+            rx
+        };
+        let mut queued = Queued { limiter: self, owner, rx: Some(rx) };
+        // the slot is taken on our behalf by the request releasing it
+        let granted = queued.rx.as_mut().unwrap().await.is_ok();
+        queued.rx = None;
+        if !granted {
+            return Err(Full { queued: 0 });
+        }
+        let waited = start.elapsed();
+        let mut state = self.state.lock().unwrap();
+        state.stats.waited += 1;
+        state.stats.wait_secs += waited.as_secs_f64();
+        state.stats.max_wait_secs = state.stats.max_wait_secs.max(waited.as_secs_f64());
+        Ok(Permit { limiter: self, owner: owner.to_string(), waited })
+    }
+
+    fn release(&self, owner: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.put(owner);
+        state.dispatch();
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut state = self.state.lock().unwrap();
+        state.queue.retain(|w| !w.tx.is_closed());
+        Stats {
+            running: state.running,
+            queued: state.queue.len(),
+            ..state.stats.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    /// Poll once, so a request which has to wait enters the queue
+    fn poll_once<F: Future + Unpin>(future: &mut F) -> Option<F::Output> {
+        match std::pin::Pin::new(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => Some(output),
+            Poll::Pending => None,
+        }
+    }
+
+    fn limiter() -> Limiter {
+        Limiter::new(Limits { running: 2, per_owner: 1, queued: 2 })
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let limiter = limiter();
+        let a = limiter.acquire("a").await.unwrap();
+        // a is at its own limit, b still runs
+        let b = limiter.acquire("b").await.unwrap();
+        let mut c = Box::pin(limiter.acquire("c"));
+        assert!(poll_once(&mut c).is_none());
+        let mut a2 = Box::pin(limiter.acquire("a"));
+        assert!(poll_once(&mut a2).is_none());
+        assert_eq!(limiter.acquire("d").await.err(), Some(Full { queued: 2 }));
+        assert_eq!(limiter.stats().queued, 2);
+
+        // slot of a goes to c, first in the queue
+        drop(a);
+        let c = c.await.unwrap();
+        assert!(poll_once(&mut a2).is_none());
+        drop(b);
+        let a2 = a2.await.unwrap();
+        let stats = limiter.stats();
+        assert_eq!((stats.running, stats.queued, stats.rejected, stats.waited), (2, 0, 1, 2));
+        drop((c, a2));
+        assert_eq!(limiter.stats().running, 0);
+    }
+
+    #[tokio::test]
+    async fn test_gone_while_queued() {
+        let limiter = limiter();
+        let a = limiter.acquire("a").await.unwrap();
+        let mut queued = Box::pin(limiter.acquire("a"));
+        assert!(poll_once(&mut queued).is_none());
+        // slot is granted to the queued request which is dropped before it sees it
+        drop(a);
+        drop(queued);
+        assert_eq!(limiter.stats().running, 0);
+
+        let _a = limiter.acquire("a").await.unwrap();
+        let mut queued = Box::pin(limiter.acquire("a"));
+        assert!(poll_once(&mut queued).is_none());
+        drop(queued);
+        assert_eq!(limiter.stats().queued, 0);
+    }
+
+    #[tokio::test]
+    async fn test_set_limits() {
+        let limiter = limiter();
+        let a = limiter.acquire("a").await.unwrap();
+        let mut a2 = Box::pin(limiter.acquire("a"));
+        assert!(poll_once(&mut a2).is_none());
+        // a raised limit grants queued requests at once
+        limiter.set_limits(Limits { running: 2, per_owner: 2, queued: 2 });
+        let a2 = a2.await.unwrap();
+
+        // a lowered one keeps running commands and holds back new ones
+        limiter.set_limits(Limits { running: 1, per_owner: 1, queued: 1 });
+        let mut b = Box::pin(limiter.acquire("b"));
+        assert!(poll_once(&mut b).is_none());
+        drop(a);
+        assert!(poll_once(&mut b).is_none());
+        drop(a2);
+        let _b = b.await.unwrap();
+        assert_eq!(limiter.stats().running, 1);
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(Limits::default().check().is_ok());
+        assert!(Limits { running: 0, ..Limits::default() }.check().is_err());
+        assert!(Limits { per_owner: 0, ..Limits::default() }.check().is_err());
+    }
+}
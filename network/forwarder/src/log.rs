@@ -1,49 +1,381 @@
-//use systemd_journal_logger::JournalLog;
+//! Logging of the server to its sinks.
+//!
+//! Records go to any of the sinks of [`LogConfig`]:
+//!
+//! - `file`: `forwarder.log` in the log directory, rolled by size, with audit
+//!   records in `audit.log` next to it,
+//! - `journald`: the native protocol of systemd-journald, with the request
+//!   of a record as `REQUEST_UUID` and its owner as `OWNER`, for all records
+//!   logged while a request is served,
+//! - `syslog`: RFC 5424 messages to `/dev/log`, with the same fields as
+//!   structured data,
+//! - `stderr`.
+//!
+//! Files and stderr are written with `pattern`, a log4rs pattern, or as one
+//! JSON object per record. The level applies to modules without one of their
+//! own in `modules`, audit records are always logged.
 use anyhow::Result;
-use log::LevelFilter;
+use chrono::{SecondsFormat, Utc};
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, LevelFilter, Record};
 use log4rs::{
-    append::rolling_file::{
-        policy::compound::{
-            roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy,
+    append::{
+        console::{ConsoleAppender, Target},
+        rolling_file::{
+            policy::compound::{
+                roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy,
+            },
+            RollingFileAppender,
         },
-        RollingFileAppender,
+        Append,
     },
-    config::{Appender, Config, Root},
-    encode::pattern::PatternEncoder,
+    config::{Appender, Config, Logger, Root},
+    encode::{pattern::PatternEncoder, Encode},
 };
+use serde::Deserialize;
 use std::fs;
+use std::os::unix::net::UnixDatagram;
 use std::path::Path;
+use uuid::Uuid;
 
-pub fn init(dir: &str, size: u64, max: u32) -> Result<()> {
-    //env_logger::init();
+use crate::audit::AUDIT;
+use crate::config::LogConfig;
 
-    //JournalLog::new()?
-    //    .install()?;
-    //log::set_max_level(LevelFilter::Info);
+/// Pattern of log lines by default
+pub const PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S%.3f %Z)(utc)} {l} - {m}{n}";
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Longest message sent in one datagram, longer ones are cut
+const MAX_MESSAGE: usize = 64 << 10;
+/// Syslog facility of daemons
+const DAEMON: u8 = 3;
 
-    let pattern = ensure_log_dir(dir)?;
-    let trigger = SizeTrigger::new(size * 1024_u64 * 1024_u64);
-    let roller = FixedWindowRoller::builder().build(&pattern, max).unwrap();
-    let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Sink {
+    File,
+    Journald,
+    Syslog,
+    Stderr,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Lines of `pattern`
+    #[default]
+    Pattern,
+    /// One JSON object per record
+    Json,
+}
 
-    let logfile = RollingFileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(
-            "{d(%Y-%m-%d %H:%M:%S%.3f %Z)(utc)} {l} - {m}{n}",
-        )))
-        .build(format!("{dir}/forwarder.log"), Box::new(policy))
-        .unwrap();
-    let config = Config::builder()
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(Root::builder().appender("logfile").build(LevelFilter::Info))
-        .unwrap();
+pub fn init(config: &LogConfig) -> Result<()> {
+    let mut builder = Config::builder();
+    let (mut root, mut audit) = (Vec::new(), Vec::new());
+    for sink in [Sink::File, Sink::Journald, Sink::Syslog, Sink::Stderr] {
+        if !config.sinks.contains(&sink) {
+            continue;
+        }
+        match sink {
+            Sink::File => {
+                ensure_log_dir(&config.dir)?;
+                let logfile = rolling_file(&config.dir, "forwarder", config.size, config.max, encoder(config))?;
+                // audit records are JSON lines of their own, rolled the same way
+                let records = rolling_file(&config.dir, "audit", config.size, config.max, Box::new(PatternEncoder::new("{m}{n}")))?;
+                builder = builder.appender(Appender::builder().build("logfile", Box::new(logfile)));
+                builder = builder.appender(Appender::builder().build("audit", Box::new(records)));
+                root.push("logfile");
+                audit.push("audit");
+            }
+            Sink::Journald => {
+                builder = builder.appender(Appender::builder().build("journald", Box::new(Journald::new()?)));
+                root.push("journald");
+                audit.push("journald");
+            }
+            Sink::Syslog => {
+                builder = builder.appender(Appender::builder().build("syslog", Box::new(Syslog::new()?)));
+                root.push("syslog");
+                audit.push("syslog");
+            }
+            Sink::Stderr => {
+                let stderr = ConsoleAppender::builder().target(Target::Stderr).encoder(encoder(config)).build();
+                builder = builder.appender(Appender::builder().build("stderr", Box::new(stderr)));
+                root.push("stderr");
+                audit.push("stderr");
+            }
+        }
+    }
+    for (module, level) in &config.modules {
+        builder = builder.logger(Logger::builder().build(module, *level));
+    }
+    let config = builder
+        .logger(Logger::builder().appenders(audit).additive(false).build(AUDIT, LevelFilter::Info))
+        .build(Root::builder().appenders(root).build(config.level))?;
     let _handle = log4rs::init_config(config)?;
     Ok(())
 }
 
-fn ensure_log_dir(dir: &str) -> Result<String> {
+fn encoder(config: &LogConfig) -> Box<dyn Encode> {
+    match config.format {
+        Format::Pattern => Box::new(PatternEncoder::new(&config.pattern)),
+        Format::Json => Box::new(JsonEncoder),
+    }
+}
+
+/// `<dir>/<name>.log` rolled into `<dir>/<name>.<n>.gz` every `size` Mb
+fn rolling_file(dir: &str, name: &str, size: u64, max: u32, encoder: Box<dyn Encode>) -> Result<RollingFileAppender> {
+    let trigger = SizeTrigger::new(size * 1024_u64 * 1024_u64);
+    let roller = FixedWindowRoller::builder().build(&format!("{dir}{name}.{{}}.gz"), max)?;
+    let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
+    Ok(RollingFileAppender::builder()
+        .encoder(encoder)
+        .build(format!("{dir}/{name}.log"), Box::new(policy))?)
+}
+
+fn ensure_log_dir(dir: &str) -> Result<()> {
     let path = Path::new(dir);
     if !path.try_exists()? {
         fs::create_dir_all(dir)?;
     }
-    Ok(format!("{}forwarder.{{}}.gz", path.to_str().unwrap()))
+    Ok(())
+}
+
+tokio::task_local! {
+    /// Owner of the request the task serves
+    static OWNER: String;
+}
+
+/// Run `future` with `owner` as the `owner` field of the records it logs
+pub fn with_owner<F: Future>(owner: &str, future: F) -> impl Future<Output = F::Output> + use<F> {
+    OWNER.scope(owner.to_string(), future)
+}
+
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.as_str().to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// Key-values of `record`, with the request of a message starting with
+/// `[<uuid>]` as `request_uuid` and the owner of the served request as `owner`
+fn fields(record: &Record) -> Vec<(String, String)> {
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    let mut fields = fields.0;
+    let message = record.args().to_string();
+    if !fields.iter().any(|(key, _)| key == "request_uuid")
+        && let Some(uuid) = message.strip_prefix('[').and_then(|m| m.get(..36)).filter(|uuid| Uuid::try_parse(uuid).is_ok())
+    {
+        fields.push(("request_uuid".to_string(), uuid.to_string()));
+    }
+    if !fields.iter().any(|(key, _)| key == "owner")
+        && let Ok(owner) = OWNER.try_with(Clone::clone)
+        && !owner.is_empty()
+    {
+        fields.push(("owner".to_string(), owner));
+    }
+    fields
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// One JSON object per record with its time, level, target, message and fields
+#[derive(Debug)]
+struct JsonEncoder;
+
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn log4rs::encode::Write, record: &Record) -> Result<()> {
+        let mut object = serde_json::Map::new();
+        object.insert("time".to_string(), now().into());
+        object.insert("level".to_string(), record.level().as_str().into());
+        object.insert("target".to_string(), record.target().into());
+        object.insert("message".to_string(), record.args().to_string().into());
+        for (key, value) in fields(record) {
+            object.insert(key, value.into());
+        }
+        serde_json::to_writer(&mut *w, &object)?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// Syslog severity of `level`
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Cut `message` to fit a datagram
+fn cut(mut message: String) -> String {
+    if message.len() > MAX_MESSAGE {
+        let mut end = MAX_MESSAGE;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+        message.push_str("...");
+    }
+    message
+}
+
+/// Sends records to systemd-journald
+#[derive(Debug)]
+struct Journald {
+    socket: UnixDatagram,
+}
+
+impl Journald {
+    fn new() -> std::io::Result<Self> {
+        Ok(Journald { socket: UnixDatagram::unbound()? })
+    }
+}
+
+/// Entry of `record` in the native protocol of journald
+fn journal_entry(record: &Record) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let mut field = |name: &str, value: &str| {
+        // values with newlines are sent with their length
+        match value.contains('\n') {
+            true => {
+                entry.extend_from_slice(name.as_bytes());
+                entry.push(b'\n');
+                entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            }
+            false => {
+                entry.extend_from_slice(name.as_bytes());
+                entry.push(b'=');
+            }
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    };
+    field("MESSAGE", &cut(record.args().to_string()));
+    field("PRIORITY", &severity(record.level()).to_string());
+    field("SYSLOG_IDENTIFIER", "forwarder");
+    field("TARGET", record.target());
+    if let Some(file) = record.file() {
+        field("CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        field("CODE_LINE", &line.to_string());
+    }
+    for (key, value) in fields(record) {
+        let name: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+        // fields starting with `_` are trusted ones set by journald
+        field(name.trim_start_matches('_'), &value);
+    }
+    entry
+}
+
+impl Append for Journald {
+    fn append(&self, record: &Record) -> Result<()> {
+        self.socket.send_to(&journal_entry(record), JOURNAL_SOCKET)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sends records to the local syslog daemon
+#[derive(Debug)]
+struct Syslog {
+    socket: UnixDatagram,
+    hostname: String,
+}
+
+impl Syslog {
+    fn new() -> std::io::Result<Self> {
+        let mut name = [0u8; 256];
+        let hostname = match unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } {
+            0 => String::from_utf8_lossy(&name[..name.iter().position(|c| *c == 0).unwrap_or(name.len())]).into_owned(),
+            _ => "-".to_string(),
+        };
+        Ok(Syslog { socket: UnixDatagram::unbound()?, hostname })
+    }
+}
+
+/// RFC 5424 message of `record`, its fields are structured data of the
+/// example enterprise number
+fn syslog_message(record: &Record, hostname: &str, time: &str) -> String {
+    let priority = DAEMON * 8 + severity(record.level());
+    let msgid = if record.target() == AUDIT { "audit" } else { "-" };
+    let fields = fields(record);
+    let data = match fields.is_empty() {
+        true => "-".to_string(),
+        false => {
+            let params: Vec<_> = fields
+                .iter()
+                .map(|(key, value)| format!("{key}=\"{}\"", value.replace('\\', r"\\").replace('"', "\\\"").replace(']', "\\]")))
+                .collect();
+            format!("[forwarder@32473 {}]", params.join(" "))
+        }
+    };
+    let message = cut(record.args().to_string());
+    format!("<{priority}>1 {time} {hostname} forwarder {} {msgid} {data} {message}", std::process::id())
+}
+
+impl Append for Syslog {
+    fn append(&self, record: &Record) -> Result<()> {
+        self.socket.send_to(syslog_message(record, &self.hostname, &now()).as_bytes(), SYSLOG_SOCKET)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    #[test]
+    fn test_fields() {
+        let uuid = fields(&Record::builder().args(format_args!("[{UUID}][7] - Got request")).build());
+        assert_eq!(uuid, [("request_uuid".to_string(), UUID.to_string())]);
+
+        let kvs = [("request_uuid", UUID), ("owner", "deploy")];
+        let record = Record::builder().args(format_args!("{{}}")).level(Level::Info).target(AUDIT).key_values(&kvs).build();
+        let entry = String::from_utf8(journal_entry(&record)).unwrap();
+        assert!(entry.contains(&format!("PRIORITY=6\nSYSLOG_IDENTIFIER=forwarder\nTARGET=audit\nREQUEST_UUID={UUID}\nOWNER=deploy\n")));
+        assert_eq!(
+            syslog_message(&record, "host", "2026-01-02T03:04:05.000Z"),
+            format!("<30>1 2026-01-02T03:04:05.000Z host forwarder {} audit [forwarder@32473 request_uuid=\"{UUID}\" owner=\"deploy\"] {{}}", std::process::id())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_owner() {
+        let message = format!("[{UUID}] - Got");
+        let record = || fields(&Record::builder().args(format_args!("{message}")).build());
+        let owned = with_owner("deploy", async { record() }).await;
+        assert_eq!(owned[1], ("owner".to_string(), "deploy".to_string()));
+        assert_eq!(record().len(), 1);
+    }
+
+    #[test]
+    fn test_journal_multiline() {
+        let record = Record::builder().args(format_args!("a\nb")).level(Level::Error).build();
+        let entry = journal_entry(&record);
+        assert!(entry.starts_with(b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\nPRIORITY=3\n"));
+    }
+
+    #[test]
+    fn test_json() {
+        let mut line = log4rs::encode::writer::simple::SimpleWriter(Vec::new());
+        let encoded = JsonEncoder.encode(&mut line, &Record::builder().args(format_args!("[{UUID}] - Done")).level(Level::Warn).build());
+        encoded.unwrap();
+        let object: serde_json::Value = serde_json::from_slice(&line.0).unwrap();
+        assert_eq!((object["level"].as_str(), object["request_uuid"].as_str()), (Some("WARN"), Some(UUID)));
+        assert_eq!(object["message"], format!("[{UUID}] - Done"));
+    }
 }
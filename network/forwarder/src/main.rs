@@ -1,212 +1,1654 @@
 #![allow(unused)]
 
 use anyhow::Result;
-use bytes::BytesMut;
 use clap::Parser;
 use log::{error, info, warn, debug, LevelFilter};
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use std::process::Output;
 use std::process::Stdio;
 use std::os::unix::fs::PermissionsExt;
 use std::os::fd::AsRawFd;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::os::unix::process::ExitStatusExt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpListener;
 use tokio::net::UnixListener;
 use tokio::process::Command;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
-use tokio::time::{timeout, Duration};
 use uuid::Uuid;
 
+use forwarder::audit::{Capture, Record, Redactor};
+use forwarder::auth::{ErrorKind, Peer, Policy};
+use forwarder::config::{Config, MetricsConfig, TcpConfig};
+use forwarder::exec::{self, Decoder, Input, Options, Outcome, Stdin, Stream, Termination};
+use forwarder::jobs::{self, Job, Jobs};
+use forwarder::limit::{Limiter, Limits, Permit};
 use forwarder::log as logging;
+use forwarder::metrics::{self, Metrics};
+use forwarder::profile::{self, Sandbox};
+use forwarder::protocol::{self, JobInfo, JobState, Mode, Request, Response, SessionFrame, StreamFrame};
+use forwarder::pty::Tty;
+use forwarder::systemd::{self, Activated};
+use forwarder::tls::{self, Tls, TlsFiles};
+use forwarder::transfer::{self, FileInfo, Owner};
 
-#[derive(Deserialize)]
-struct Request {
-    bash: String,
-    input: Option<String>,
+/// Time for a TLS client to finish its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time for a client to send the rest of a request it started
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time for a scrape of the metrics listener to be answered
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// State shared by all connections
+struct Context {
+    settings: RwLock<Arc<Settings>>,
+    /// Requests being run, by id
+    running: Mutex<HashMap<String, Running>>,
+    jobs: Jobs,
+    /// Longest time a job may run
+    job_timeout: Duration,
+    /// Longest time a connection may wait between requests
+    idle_timeout: Duration,
+    limiter: Limiter,
+    /// Commands are no longer accepted
+    stopping: AtomicBool,
+    metrics: Metrics,
+}
+
+/// Settings replaced on reload, a running command keeps the ones it started with
+struct Settings {
+    /// Requests are not checked without policy
+    policy: Option<Policy>,
+    /// Cap of output of one command in bytes
+    max_output: usize,
+    /// Parent of transient cgroups of profiles
+    cgroup_root: PathBuf,
+    /// Redacts secrets from audit records
+    redactor: Redactor,
+}
+
+impl Settings {
+    fn new(config: &Config) -> Result<Self> {
+        let policy = config.load_policy()?;
+        if policy.is_none() {
+            // every local user would run any command as the server
+            if config.socket_mode & 0o077 != 0 {
+                return Err(anyhow::anyhow!("socket_mode {:#o} lets other users connect, which needs a policy", config.socket_mode));
+            }
+            warn!("No policy given, requests of any peer are allowed");
+        }
+        config.limits.check().map_err(|e| anyhow::anyhow!(e))?;
+        let max_output = config.max_output * 1024 * 1024;
+        if max_output > protocol::MAX_OUTPUT {
+            return Err(anyhow::anyhow!("max_output of {} MiB is over {} MiB, which fits in one response", config.max_output, protocol::MAX_OUTPUT >> 20));
+        }
+        Ok(Settings {
+            policy,
+            max_output,
+            cgroup_root: config.cgroup_root.clone(),
+            redactor: Redactor::new(&config.audit.redact)?,
+        })
+    }
+}
+
+/// Running request which can be cancelled by its owner
+struct Running {
     owner: String,
+    uid: Option<u32>,
+    cancel: oneshot::Sender<()>,
+}
+
+/// Entry of `Context::running`, removed when the request ends
+struct Registration<'a> {
+    ctx: &'a Context,
+    id: String,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.ctx.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Context {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Write the audit record of a finished request and count it in the metrics
+    fn audit(&self, record: Record) {
+        // a policy proves owners of requests it did not reject
+        let rejected = matches!(record.kind, Some(ErrorKind::Invalid | ErrorKind::Unauthenticated | ErrorKind::Denied));
+        self.metrics.observe(&record, self.settings().policy.is_some() && !rejected);
+        record.write();
+    }
+
+    /// Register a request to be cancelled, return the future to wait for cancellation
+    fn register<'a>(
+        &'a self,
+        request: &mut Request,
+        peer: &Peer,
+    ) -> std::result::Result<(Registration<'a>, impl Future<Output = ()> + use<>), Box<Response>> {
+        let id = assign_id(request)?;
+        let mut running = self.running.lock().unwrap();
+        if running.contains_key(&id) {
+            return Err(Box::new(Response::error(ErrorKind::Invalid, format!("Request {id} is already running"))));
+        }
+        let (tx, rx) = oneshot::channel();
+        running.insert(id.clone(), Running { owner: request.owner.clone(), uid: peer.uid(), cancel: tx });
+        let cancelled = async move {
+            // sender is only dropped once the request ends
+            if rx.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        };
+        Ok((Registration { ctx: self, id }, cancelled))
+    }
+
+    /// Whether the owner of `request` may act on what `owner` started from `uid`
+    fn owns(&self, peer: &Peer, request: &Request, owner: &str, uid: Option<u32>) -> bool {
+        match (&self.settings().policy, peer) {
+            (None, _) => true,
+            // root may act on anything on the local socket
+            (Some(_), Peer::Unix { uid: 0, .. }) => true,
+            (Some(_), Peer::Unix { uid: peer, .. }) => uid == Some(*peer) && owner == request.owner,
+            (Some(_), Peer::Tcp(_) | Peer::Tls { .. }) => owner == request.owner,
+        }
+    }
+
+    /// Answer requests about running requests and jobs, `None` for others
+    fn query(&self, request: &Request, peer: &Peer) -> Option<Response> {
+        if let Some(id) = &request.cancel {
+            return Some(self.cancel(id, request, peer));
+        }
+        if request.stats {
+            return Some(Response {
+                stats: Some(Box::new(self.limiter.stats())),
+                ..Default::default()
+            });
+        }
+        if request.list {
+            self.jobs.purge();
+            return Some(Response {
+                jobs: self.jobs.list(|job| self.owns(peer, request, &job.info.owner, job.info.uid) && self.allowed(job).is_ok()),
+                ..Default::default()
+            });
+        }
+        let id = request.status.as_ref().or(request.result.as_ref())?;
+        self.jobs.purge();
+        let job = match self.jobs.get(id) {
+            Some(job) if !self.owns(peer, request, &job.info.owner, job.info.uid) => {
+                return Some(Response::error(ErrorKind::Denied, format!("Job {id} belongs to another owner")));
+            }
+            Some(job) => job,
+            None => return Some(Response::error(ErrorKind::NotFound, format!("No job {id}"))),
+        };
+        if let Err(reason) = self.allowed(&job) {
+            return Some(Response::error(ErrorKind::Denied, format!("Job {id} is no longer allowed: {reason}")));
+        }
+        let response = match (&request.status, job.result) {
+            (Some(_), _) => Response::default(),
+            (None, Some(result)) => result,
+            (None, None) => Response::error(ErrorKind::Running, format!("Job {id} is {:?}", job.info.state)),
+        };
+        Some(Response {
+            id: Some(id.clone()),
+            job: Some(Box::new(job.info)),
+            ..response
+        })
+    }
+
+    /// Whether the request of `job`, which may be from before a reload or a
+    /// restart, is allowed by the current policy and its profile still exists
+    fn allowed(&self, job: &Job) -> std::result::Result<(), String> {
+        let settings = self.settings();
+        let Some(policy) = &settings.policy else {
+            return Ok(());
+        };
+        let request = Request { owner: job.info.owner.clone(), ..job.request.clone() };
+        policy.permit(job.info.uid, &request).map_err(|denied| denied.to_string())?;
+        match &request.profile {
+            Some(name) if !policy.profiles.contains_key(name) => Err(format!("no profile {name}")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Cancel request `id` on behalf of the owner of `request`
+    fn cancel(&self, id: &str, request: &Request, peer: &Peer) -> Response {
+        let mut running = self.running.lock().unwrap();
+        let Some(target) = running.get(id) else {
+            return Response::error(ErrorKind::NotFound, format!("Request {id} is not running"));
+        };
+        if !self.owns(peer, request, &target.owner, target.uid) {
+            return Response::error(ErrorKind::Denied, format!("Request {id} belongs to another owner"));
+        }
+        if let Some(target) = running.remove(id) {
+            let _ = target.cancel.send(());
+        }
+        Response {
+            id: Some(id.to_string()),
+            ..Default::default()
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Default)]
-struct Response {
-    output: String,
-    error: String,
-    code: Option<i32>,
+/// Use the id of `request` or give it one
+fn assign_id(request: &mut Request) -> std::result::Result<String, Box<Response>> {
+    let id = request.id.get_or_insert_with(|| Uuid::new_v4().to_string()).clone();
+    // id names the transient cgroup of the request and the file of the job
+    if id.is_empty() || id.len() > 128 || !id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) || id.starts_with('.') {
+        return Err(Box::new(Response::error(ErrorKind::Invalid, "Request id must be 1-128 characters of [A-Za-z0-9._-] not starting with `.`")));
+    }
+    Ok(id)
+}
+
+/// Start `request` as a job and answer with its id
+fn submit(mut request: Request, peer: &Peer, uuid: Uuid, ctx: &Arc<Context>) -> Response {
+    let id = match assign_id(&mut request) {
+        Ok(id) => id,
+        Err(response) => return *response,
+    };
+    let info = JobInfo {
+        id: id.clone(),
+        owner: request.owner.clone(),
+        uid: peer.uid(),
+        bash: request.command_line(),
+        state: JobState::Running,
+        submitted: jobs::now(),
+        finished: None,
+        status: None,
+        code: None,
+    };
+    // a job outlives its connection, only a cancel or its timeout ends it early
+    let timeout = ctx.job_timeout.as_secs();
+    request.timeout_secs = Some(request.timeout_secs.map_or(timeout, |t| t.min(timeout)));
+    if let Err(e) = ctx.jobs.submit(info.clone(), &request) {
+        return Response::error(ErrorKind::Invalid, e);
+    }
+    info!("[{uuid}] - Submitted job {id}");
+    let (ctx, peer) = (ctx.clone(), peer.clone());
+    tokio::spawn(logging::with_owner(&request.owner.clone(), async move {
+        let response = run_request(request, &peer, uuid, &ctx, std::future::pending()).await;
+        ctx.jobs.finish(&id, response);
+    }));
+    Response {
+        id: info.id.clone().into(),
+        job: Some(Box::new(info)),
+        ..Default::default()
+    }
+}
+
+/// Answer `request` with one response
+async fn respond(
+    request: Request,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Arc<Context>,
+    disconnected: impl Future<Output = ()>,
+) -> Response {
+    if let Some(response) = ctx.query(&request, peer) {
+        return response;
+    }
+    if request.submit {
+        return submit(request, peer, uuid, ctx);
+    }
+    run_request(request, peer, uuid, ctx, disconnected).await
 }
 
 async fn handle_stream(
-    mut stream: impl AsyncReadExt + AsyncWriteExt + std::marker::Unpin + AsRawFd,
+    stream: impl AsyncReadExt + AsyncWriteExt + std::marker::Unpin + AsRawFd,
+    peer: Peer,
     uuid: Uuid,
+    ctx: Arc<Context>,
 ) -> Result<()> {
-    const BUF_LEN: usize = 4096;
-    let mut data = BytesMut::with_capacity(BUF_LEN);
-    let mut n = 0;
-    // Timeout read in case of data length equal to buffer length
-    // It will hang in such case since we don't know how many bytes we need to read.
-    loop {
-        match timeout(Duration::from_secs(5), stream.read_buf(&mut data)).await {
-            Ok(Ok(n)) if n == 0 => break,  // Connection closed
-            Ok(Ok(n)) if n % BUF_LEN != 0 => break,  // All data read
-            Ok(Ok(n)) => {
-                // Extend buffer to read more
-                data.reserve(BUF_LEN);
+    let sock = stream.as_raw_fd();
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let Some(mode) = read_timely(protocol::detect(&mut reader), &ctx).await? else {
+        warn!("[{uuid}][{sock}] - Read timeout");
+        return Ok(());
+    };
+    match mode {
+        None => info!("[{uuid}][{sock}] - Closed without request"),
+        Some(Mode::Legacy) => {
+            // One-shot JSON of clients without framing, response is followed by EOF.
+            // These clients may close their write side after the request, so EOF is not a disconnect.
+            let max = if peer.authenticated() { protocol::MAX_FRAME } else { protocol::MAX_ANONYMOUS_FRAME };
+            let Some(data) = read_timely(protocol::read_legacy(&mut reader, max), &ctx).await? else {
+                warn!("[{uuid}][{sock}] - Read timeout");
+                return Ok(());
+            };
+            info!("[{uuid}][{sock}] - Got legacy Request({} bytes): {:?}", data.len(), ctx.settings().redactor.redact(&String::from_utf8_lossy(&data)));
+            let response = match accept(&data, &peer, uuid, &ctx) {
+                Ok(request) if request.tty.is_some() => Response::error(ErrorKind::Invalid, "Sessions need a framed connection"),
+                Ok(request) if request.transfer().is_some() => Response::error(ErrorKind::Invalid, "Transfers need a framed connection"),
+                Ok(request) if request.stdin => Response::error(ErrorKind::Invalid, "Streamed input needs a framed connection"),
+                Ok(request) => logging::with_owner(&request.owner.clone(), respond(request, &peer, uuid, &ctx, std::future::pending())).await,
+                Err(response) => *response,
+            };
+            let response = serde_json::to_vec(&response)?;
+            match writer.write_all(&response).await {
+                Ok(_) => info!("[{uuid}][{sock}] - Send response({} bytes) successfully.", response.len()),
+                Err(e) => error!("[{uuid}][{sock}] - Failed to send response: {e}"),
             }
-            Ok(Err(e)) => {
-                error!("[{uuid}] - {e}");
-                break;
-            },
-            Err(e) => {
-                warn!("[{uuid} - Read timeout: {e}]");
-                break;
-            },
+            writer.shutdown().await?;
+        }
+        Some(Mode::Framed) => {
+            // frames are kept small until the peer is authenticated by a request
+            let mut authenticated = peer.authenticated();
+            // the first request is due like the rest of a request, then
+            // connections may idle between requests up to `idle_timeout`
+            let mut first = true;
+            loop {
+                let filled = if first {
+                    read_timely(reader.fill_buf(), &ctx).await?
+                } else {
+                    tokio::time::timeout(ctx.idle_timeout, reader.fill_buf()).await.ok().transpose()?
+                };
+                match filled {
+                    Some([]) => break,
+                    Some(_) => first = false,
+                    None if first => {
+                        warn!("[{uuid}][{sock}] - Read timeout");
+                        return Ok(());
+                    }
+                    None => {
+                        info!("[{uuid}][{sock}] - Closed idle connection");
+                        return Ok(());
+                    }
+                }
+                let max = if authenticated { protocol::MAX_FRAME } else { protocol::MAX_ANONYMOUS_FRAME };
+                let Some(data) = read_timely(protocol::read_frame(&mut reader, max), &ctx).await? else {
+                    warn!("[{uuid}][{sock}] - Read timeout");
+                    return Ok(());
+                };
+                let Some(data) = data else { break };
+                info!("[{uuid}][{sock}] - Got Request({} bytes): {:?}", data.len(), ctx.settings().redactor.redact(&String::from_utf8_lossy(&data)));
+                let accepted = accept(&data, &peer, uuid, &ctx);
+                authenticated |= accepted.is_ok();
+                let owner = accepted.as_ref().map(|request| request.owner.clone()).unwrap_or_default();
+                // the response unless the request answered on its own
+                let served = logging::with_owner(&owner, async {
+                    let response = match accepted {
+                        Ok(request) if let Some(tty) = request.tty.clone() => {
+                            session_request(request, tty, &peer, uuid, &ctx, &mut reader, &mut writer).await?;
+                            info!("[{uuid}][{sock}] - Session ended.");
+                            return Ok(None);
+                        }
+                        Ok(request) if request.transfer().is_some() => {
+                            transfer_request(request, &peer, uuid, &ctx, &mut reader, &mut writer).await?;
+                            info!("[{uuid}][{sock}] - Transfer ended.");
+                            return Ok(None);
+                        }
+                        Ok(request) if request.stream && !request.submit => {
+                            stream_request(request, &peer, uuid, &ctx, &mut reader, &mut writer).await?;
+                            info!("[{uuid}][{sock}] - Streamed response successfully.");
+                            return Ok(None);
+                        }
+                        Ok(request) => {
+                            let half_close = request.half_close;
+                            respond(request, &peer, uuid, &ctx, closed(&mut reader, half_close)).await
+                        }
+                        // streaming clients wait for the exit frame
+                        Err(response) if wants_stream(&data) => {
+                            let response = Response { version: Some(protocol::VERSION), ..*response };
+                            protocol::send(&mut writer, &StreamFrame::Exit(response)).await?;
+                            skip_input(&mut reader, !sends_input(&data)).await?;
+                            return Ok(None);
+                        }
+                        Err(response) => *response,
+                    };
+                    let response = Response { version: Some(protocol::VERSION), ..response };
+                    if let Err(e) = protocol::send(&mut writer, &response).await {
+                        error!("[{uuid}][{sock}] - Failed to send response: {e}");
+                        return Err(e);
+                    }
+                    Ok(Some(response))
+                })
+                .await?;
+                if served.is_some() {
+                    info!("[{uuid}][{sock}] - Send response successfully.");
+                }
+            }
+            info!("[{uuid}][{sock}] - Connection closed");
         }
     }
-    n = data.len();
-    let sock = stream.as_raw_fd();
-    info!("[{uuid}][{sock}] - Got Request({n} bytes): {data:?}");
+    Ok(())
+}
+
+/// Wait for `read` of a request for up to `READ_TIMEOUT`, `None` if it timed out
+async fn read_timely<T>(read: impl Future<Output = std::io::Result<T>>, ctx: &Context) -> std::io::Result<Option<T>> {
+    match tokio::time::timeout(READ_TIMEOUT, read).await {
+        Ok(data) => data.map(Some),
+        Err(_) => {
+            ctx.metrics.read_timeout();
+            Ok(None)
+        }
+    }
+}
+
+/// Whether a request, which may not be valid, asked for streaming or a session
+fn wants_stream(data: &[u8]) -> bool {
+    let request = serde_json::from_slice::<serde_json::Value>(data).unwrap_or_default();
+    let stream = request.get("stream").and_then(|s| s.as_bool()).unwrap_or_default();
+    let tty = request.get("tty").is_some_and(|tty| !tty.is_null());
+    (stream || tty) && !request.get("submit").and_then(|s| s.as_bool()).unwrap_or_default()
+}
+
+/// Whether a request, which may not be valid, is followed by input frames
+fn sends_input(data: &[u8]) -> bool {
+    let request = serde_json::from_slice::<serde_json::Value>(data).unwrap_or_default();
+    request.get("stdin").and_then(|s| s.as_bool()).unwrap_or_default() && wants_stream(data)
+}
+
+/// Complete when the client closes the connection while its request is running,
+/// a closed write side only with `half_close` unset, or the connection fails.
+/// Next request sent early is left in the buffer.
+async fn closed(reader: &mut (impl AsyncBufRead + Unpin), half_close: bool) {
+    match reader.fill_buf().await {
+        Ok([]) if !half_close => (),
+        Err(_) => (),
+        Ok(_) => std::future::pending().await,
+    }
+}
+
+/// Parse and authorize a request, or answer why it is not run
+fn accept(data: &[u8], peer: &Peer, uuid: Uuid, ctx: &Context) -> std::result::Result<Request, Box<Response>> {
+    let request = match serde_json::from_slice::<Request>(data) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("[{uuid}] - Invalid request: {e}");
+            return Err(Box::new(Response::error(ErrorKind::Invalid, format!("Invalid request: {e}"))));
+        }
+    };
+    if request.version > protocol::VERSION {
+        return Err(Box::new(Response::error(ErrorKind::Invalid, format!(
+            "Unsupported protocol version {}, server speaks {}",
+            request.version,
+            protocol::VERSION
+        ))));
+    }
+    let mut request = request;
+    // client certificate names the owner, with or without policy
+    if let Peer::Tls { identity: Some(identity), .. } = peer {
+        if request.owner.is_empty() {
+            request.owner = identity.clone();
+        } else if request.owner != *identity {
+            let denied = format!("certificate does not belong to owner `{}`", request.owner);
+            warn!(owner = request.owner.as_str(); "[{uuid}] - {peer} denied: {denied}");
+            let response = Response::error(ErrorKind::Denied, denied);
+            let redactor = &ctx.settings().redactor;
+            ctx.audit(Record::new(uuid, peer, &request, redactor).finish(&response, None, redactor));
+            return Err(Box::new(response));
+        }
+    }
+    let query = request.cancel.is_some() || request.status.is_some() || request.result.is_some() || request.list || request.stats;
+    if !query {
+        if let Some(reason) = malformed(&request) {
+            return Err(Box::new(Response::error(ErrorKind::Invalid, reason)));
+        }
+        if ctx.stopping.load(Ordering::Relaxed) {
+            return Err(Box::new(Response::error(ErrorKind::Busy, "Server is shutting down")));
+        }
+    }
+    let settings = ctx.settings();
+    if let Some(policy) = &settings.policy {
+        // queries only need to prove the owner, it is checked against the running request or job
+        let result = match query {
+            true => policy.authenticate(peer, &request).map(|_| request.profile.clone()),
+            false => policy.authorize(peer, &request),
+        };
+        match result {
+            Ok(profile) => request.profile = profile,
+            Err(denied) => {
+                warn!(owner = request.owner.as_str(); "[{uuid}] - {peer} denied: {denied}");
+                let response = Response::error(denied.kind, denied);
+                ctx.audit(Record::new(uuid, peer, &request, &settings.redactor).finish(&response, None, &settings.redactor));
+                return Err(Box::new(response));
+            }
+        }
+    }
+    Ok(request)
+}
+
+/// Why a request to run a command or transfer a file cannot be run as it is
+fn malformed(request: &Request) -> Option<String> {
+    if let Some((op, path)) = request.transfer() {
+        if request.put.is_some() && request.get.is_some() {
+            return Some("Request has both `put` and `get`".to_string());
+        }
+        if !request.bash.is_empty() || !request.argv.is_empty() || request.input.is_some() || request.stdin || request.tty.is_some() {
+            return Some(format!("A {op} request runs no command"));
+        }
+        if request.submit || request.stream {
+            return Some(format!("A {op} request cannot run as a job or be streamed"));
+        }
+        return transfer::check_path(path).err();
+    }
+    if !request.bash.is_empty() && !request.argv.is_empty() {
+        return Some("Request has both `bash` and `argv`".to_string());
+    }
+    if request.bash.is_empty() && request.argv.is_empty() {
+        return Some("Request runs no command".to_string());
+    }
+    if request.argv.first().is_some_and(|program| program.is_empty()) {
+        return Some("Program of `argv` is empty".to_string());
+    }
+    if let Err(e) = request.input_bytes() {
+        return Some(format!("Invalid input: {e}"));
+    }
+    if request.stdin && (request.input.is_some() || !request.stream || request.submit || request.tty.is_some()) {
+        return Some("Only a streaming request without `input` takes input frames".to_string());
+    }
+    let tty = request.tty.as_ref()?;
+    if request.submit {
+        return Some("A session cannot run as a job".to_string());
+    }
+    if request.input.is_some() {
+        return Some("A session takes its input from session frames".to_string());
+    }
+    tty.term().err()
+}
+
+/// Build the command of `request` in the sandbox of its profile, which must be
+/// kept until the command ends
+fn command(request: &Request, settings: &Settings) -> std::result::Result<(Command, Option<Sandbox>), Box<Response>> {
+    let mut command = match request.argv.split_first() {
+        Some((program, args)) => {
+            info!("Exec: {}", request.command_line());
+            let mut command = Command::new(program);
+            command.args(args);
+            command
+        }
+        None => bash(&request.bash),
+    };
+    // only the server speaks to systemd
+    command.env_remove("NOTIFY_SOCKET");
+    let Some(name) = &request.profile else {
+        if let Some(cwd) = &request.cwd {
+            command.current_dir(cwd);
+        }
+        command.envs(&request.env);
+        return Ok((command, None));
+    };
+    let Some(profile) = settings.policy.as_ref().and_then(|p| p.profiles.get(name)) else {
+        return Err(Box::new(Response::error(ErrorKind::NotFound, format!("No profile {name}"))));
+    };
+    let id = request.id.as_deref().unwrap_or_default();
+    let sandbox = profile.prepare(id, &settings.cgroup_root).map_err(|e| {
+        error!("Failed to prepare profile {name} for {id}: {e}");
+        Response::error(ErrorKind::Internal, format!("Failed to prepare profile {name}: {e}"))
+    })?;
+    sandbox.apply(&mut command, &request.env, request.cwd.as_deref());
+    Ok((command, Some(sandbox)))
+}
+
+/// Wait until `request` of `peer` may run, the request is answered when it is
+/// cancelled meanwhile like a cancelled command
+async fn wait_slot<'a>(
+    request: &Request,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &'a Context,
+    cancel: impl Future<Output = ()>,
+) -> std::result::Result<Permit<'a>, Box<Response>> {
+    // the owner named by the request is only proven with a policy
+    let proven = ctx.settings().policy.is_some().then_some(request.owner.as_str());
+    let identity = peer.identity(proven);
+    let permit = tokio::select! {
+        permit = ctx.limiter.acquire(&identity) => permit,
+        _ = cancel => {
+            return Err(Box::new(Response {
+                status: Some(Termination::Cancelled),
+                error: "Request is cancelled while queued".to_string(),
+                ..Default::default()
+            }));
+        }
+    };
+    match permit {
+        Ok(permit) => {
+            if !permit.waited.is_zero() {
+                info!("[{uuid}] - Request {:?} waited {:?} in queue", request.id, permit.waited);
+            }
+            Ok(permit)
+        }
+        Err(full) => {
+            warn!("[{uuid}] - Reject request {:?} of {identity}: {full}", request.id);
+            Err(Box::new(Response::error(ErrorKind::Busy, full)))
+        }
+    }
+}
+
+fn options(request: &Request, settings: &Settings) -> Options {
+    Options {
+        // checked by `malformed`
+        input: request.input_bytes().ok().flatten().map_or(Stdin::Null, Stdin::Data),
+        max_output: request.max_output.unwrap_or(settings.max_output).min(settings.max_output),
+        timeout: request.timeout_secs.map(Duration::from_secs),
+    }
+}
+
+/// Fill exit status of `outcome` into `response`
+fn finish(response: &mut Response, outcome: &Outcome) {
+    response.code = outcome.status.code();
+    response.signal = outcome.status.signal();
+    response.status = Some(outcome.termination);
+    response.truncated = outcome.truncated;
+}
+
+async fn run_request(
+    request: Request,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Context,
+    disconnected: impl Future<Output = ()>,
+) -> Response {
+    let settings = ctx.settings();
+    let record = Record::new(uuid, peer, &request, &settings.redactor);
+    let (response, output) = run_command(request, peer, uuid, ctx, disconnected).await;
+    ctx.audit(record.finish(&response, output, &settings.redactor));
+    response
+}
 
+/// Run the command of `request`, answer with its output also captured for audit if it ran
+async fn run_command(
+    mut request: Request,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Context,
+    disconnected: impl Future<Output = ()>,
+) -> (Response, Option<(Capture, Capture)>) {
+    let settings = ctx.settings();
+    let (_registration, cancelled) = match ctx.register(&mut request, peer) {
+        Ok(registered) => registered,
+        Err(response) => return (*response, None),
+    };
+    let cancel = async {
+        tokio::select! {
+            _ = cancelled => info!("[{uuid}] - Request {:?} is cancelled", request.id),
+            _ = disconnected => info!("[{uuid}] - Client is gone, cancel request {:?}", request.id),
+        }
+    };
+    tokio::pin!(cancel);
+    let _permit = match wait_slot(&request, peer, uuid, ctx, &mut cancel).await {
+        Ok(permit) => permit,
+        Err(error) => return (Response { id: request.id.clone(), ..*error }, None),
+    };
     let mut response = Response {
+        id: request.id.clone(),
         ..Default::default()
     };
-    let request: Request = serde_json::from_slice::<Request>(&data)?;
-    match run_cmd(&request.bash, request.input.as_deref()).await {
-        Ok(output) => {
-            response.output = String::from_utf8_lossy(output.stdout.as_slice()).to_string();
-            response.error = String::from_utf8_lossy(output.stderr.as_slice()).to_string();
-            response.code = output.status.code();
+    let (command, _sandbox) = match command(&request, &settings) {
+        Ok(command) => command,
+        Err(error) => return (Response { id: request.id.clone(), ..*error }, None),
+    };
+    let mut output = None;
+    match exec::collect(command, options(&request, &settings), cancel).await {
+        Ok((outcome, stdout, stderr)) => {
+            let (mut out, mut err) = (Capture::default(), Capture::default());
+            out.feed(&stdout);
+            err.feed(&stderr);
+            output = Some((out, err));
+            response.set_output(stdout, stderr, request.version);
+            finish(&mut response, &outcome);
         }
         Err(e) => {
+            warn!("[{uuid}] - Failed to run: {e}");
             response.error = format!("{e}");
         }
     }
-    info!("[{uuid}] - Got {response:?}");
-    let response = serde_json::to_vec(&response)?;
-    let sock = stream.as_raw_fd();
-    match stream.write_all(&response).await {
-        Ok(_) => {
-            info!(
-                "[{uuid}][{sock}] - Send response({} bytes) successfully.",
-                response.len()
-            )
+    info!("[{uuid}] - Got {}", response.summary());
+    (response, output)
+}
+
+/// Send output chunks as they arrive, then the exit status. Input frames of a
+/// request with `stdin` are read from `reader` meanwhile.
+async fn stream_request(
+    request: Request,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Context,
+    reader: &mut (impl AsyncBufRead + std::marker::Unpin),
+    writer: &mut (impl AsyncWriteExt + std::marker::Unpin),
+) -> Result<()> {
+    let (stdin, half_close) = (request.stdin, request.half_close);
+    let mut ended = !stdin;
+    if let Some(response) = ctx.query(&request, peer) {
+        protocol::send(writer, &StreamFrame::Exit(response)).await?;
+        return skip_input(reader, ended).await;
+    }
+    let settings = ctx.settings();
+    let mut record = Record::new(uuid, peer, &request, &settings.redactor);
+    let mut typed = Capture::default();
+    let (input_tx, input_rx) = mpsc::channel(16);
+    let disconnected = async {
+        if stdin {
+            ended = relay_stdin(reader, input_tx, &mut typed, half_close, uuid).await;
+            if !ended {
+                return;
+            }
         }
-        Err(e) => {
-            error!("[{uuid}][{sock}] - Failed to send response: {e}");
+        closed(reader, half_close).await
+    };
+    let input = stdin.then_some(input_rx);
+    let (response, sent, output) = stream_command(request, peer, uuid, ctx, disconnected, input, writer).await;
+    if stdin {
+        record.input = Some(typed.finish(&settings.redactor));
+    }
+    ctx.audit(record.finish(&response, output, &settings.redactor));
+    sent?;
+    protocol::send(writer, &StreamFrame::Exit(response)).await?;
+    skip_input(reader, ended).await
+}
+
+/// Relay input frames from `reader` to `stdin` until `eof`, return whether
+/// the input ended rather than the client being gone
+async fn relay_stdin(
+    reader: &mut (impl AsyncRead + std::marker::Unpin),
+    stdin: mpsc::Sender<Vec<u8>>,
+    typed: &mut Capture,
+    half_close: bool,
+    uuid: Uuid,
+) -> bool {
+    loop {
+        let (data, encoding) = match protocol::recv::<_, SessionFrame>(reader).await {
+            Ok(Some(SessionFrame::Input { data, encoding })) => (data, encoding),
+            Ok(Some(SessionFrame::Resize { .. })) => continue,
+            Ok(Some(SessionFrame::Eof)) => return true,
+            Ok(None) => return half_close,
+            Err(e) => {
+                warn!("[{uuid}] - Invalid input frame: {e}");
+                return false;
+            }
+        };
+        match protocol::decode(&data, encoding) {
+            Ok(data) => {
+                typed.feed(&data);
+                // input sent after the command exited is dropped
+                let _ = stdin.send(data).await;
+            }
+            Err(e) => {
+                warn!("[{uuid}] - Invalid input frame: {e}");
+                return false;
+            }
         }
     }
-    stream.shutdown().await?;
-    Ok(())
 }
 
-async fn run_cmd(cmd: &str, input: Option<&str>) -> Result<Output> {
-    info!("Run command: {cmd}");
-    let mut command = Command::new("bash");
-    let command = command
-        .arg("-c")
-        .arg(cmd)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped());
-    match input {
-        None => command.output().await.map_err(anyhow::Error::from),
-        Some(input) => {
-            debug!("Command Input: {input}");
-            let mut child = command.spawn().map_err(anyhow::Error::from)?;
-            match child.stdin {
-                Some(ref mut stdin) => {
-                    stdin.write_all(input.as_bytes()).await?;
-                    child.wait_with_output().await.map_err(anyhow::Error::from)
+/// Skip input frames the command did not wait for up to their end, unless it
+/// was read already
+async fn skip_input(reader: &mut (impl AsyncRead + std::marker::Unpin), ended: bool) -> Result<()> {
+    if ended {
+        return Ok(());
+    }
+    loop {
+        match protocol::recv::<_, SessionFrame>(reader).await? {
+            Some(SessionFrame::Eof) | None => return Ok(()),
+            Some(_) => (),
+        }
+    }
+}
+
+/// Run the command of `request` with its output sent to `writer` and `input`
+/// as its stdin if given, answer with the exit status, whether output could be
+/// sent, and the captures of output if it ran
+async fn stream_command(
+    mut request: Request,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Context,
+    disconnected: impl Future<Output = ()>,
+    input: Option<mpsc::Receiver<Vec<u8>>>,
+    writer: &mut (impl AsyncWriteExt + std::marker::Unpin),
+) -> (Response, Result<()>, Option<(Capture, Capture)>) {
+    let settings = ctx.settings();
+    let (_registration, cancelled) = match ctx.register(&mut request, peer) {
+        Ok(registered) => registered,
+        Err(response) => return (*response, Ok(()), None),
+    };
+    let id = request.id.clone().unwrap_or_default();
+    if let Err(e) = protocol::send(writer, &StreamFrame::Started { id }).await {
+        return (Response { id: request.id.clone(), version: Some(protocol::VERSION), ..Default::default() }, Err(e), None);
+    }
+    let cancel = async {
+        tokio::select! {
+            _ = cancelled => info!("[{uuid}] - Request {:?} is cancelled", request.id),
+            _ = disconnected => info!("[{uuid}] - Client is gone, cancel request {:?}", request.id),
+        }
+    };
+    tokio::pin!(cancel);
+    let _permit = match wait_slot(&request, peer, uuid, ctx, &mut cancel).await {
+        Ok(permit) => permit,
+        Err(error) => return (Response { id: request.id.clone(), version: Some(protocol::VERSION), ..*error }, Ok(()), None),
+    };
+    let (command, _sandbox) = match command(&request, &settings) {
+        Ok(command) => command,
+        Err(error) => return (Response { id: request.id.clone(), version: Some(protocol::VERSION), ..*error }, Ok(()), None),
+    };
+    let mut options = options(&request, &settings);
+    if let Some(input) = input {
+        options.input = Stdin::Stream(input);
+    }
+    let (tx, mut rx) = mpsc::channel(16);
+    let execute = exec::execute(command, options, cancel, tx);
+    let mut captures = (Capture::default(), Capture::default());
+    let forward = async {
+        let (mut stdout, mut stderr) = (Decoder::default(), Decoder::default());
+        let mut sent = 0;
+        while let Some(chunk) = rx.recv().await {
+            let frame = match chunk.stream {
+                Stream::Stdout => {
+                    captures.0.feed(&chunk.data);
+                    let (data, encoding) = stdout.decode(&chunk.data);
+                    StreamFrame::Stdout { data, encoding }
+                }
+                Stream::Stderr => {
+                    captures.1.feed(&chunk.data);
+                    let (data, encoding) = stderr.decode(&chunk.data);
+                    StreamFrame::Stderr { data, encoding }
                 }
-                None => Err(anyhow::anyhow!("STDIN not catched!!!")),
+            };
+            // a slow client holds back reads of the command output
+            protocol::send(writer, &frame).await?;
+            sent += chunk.data.len();
+        }
+        for (frame, (data, encoding)) in [(Stream::Stdout, stdout.finish()), (Stream::Stderr, stderr.finish())] {
+            if !data.is_empty() {
+                let frame = match frame {
+                    Stream::Stdout => StreamFrame::Stdout { data, encoding },
+                    Stream::Stderr => StreamFrame::Stderr { data, encoding },
+                };
+                protocol::send(writer, &frame).await?;
             }
         }
+        anyhow::Ok(sent)
+    };
+    let (outcome, sent) = tokio::join!(execute, forward);
+    let mut response = Response {
+        version: Some(protocol::VERSION),
+        id: request.id.clone(),
+        ..Default::default()
+    };
+    match outcome {
+        Ok(outcome) => finish(&mut response, &outcome),
+        Err(e) => {
+            warn!("[{uuid}] - Failed to run: {e}");
+            response.error = format!("{e}");
+        }
+    }
+    match &sent {
+        Ok(sent) => info!("[{uuid}] - Streamed {sent} bytes, got {}", response.summary()),
+        Err(e) => warn!("[{uuid}] - Failed to stream output: {e}, got {}", response.summary()),
+    }
+    (response, sent.map(|_| ()), Some(captures))
+}
+
+/// Put or get the file of `request`, with its data read from `reader` or sent to `writer`
+async fn transfer_request(
+    request: Request,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Context,
+    reader: &mut (impl AsyncRead + std::marker::Unpin),
+    writer: &mut (impl AsyncWriteExt + std::marker::Unpin),
+) -> Result<()> {
+    let settings = ctx.settings();
+    let record = Record::new(uuid, peer, &request, &settings.redactor);
+    let result = transfer_file(request, peer, uuid, ctx, reader, writer).await;
+    let response = match &result {
+        Ok(response) => response.clone(),
+        Err(e) => Response::error(ErrorKind::Internal, format!("Transfer failed: {e}")),
+    };
+    ctx.audit(record.finish(&response, None, &settings.redactor));
+    result.map(|_| ())
+}
+
+/// Transfer the file of `request` once it has a slot like a command, return
+/// the last response sent. Failures of the connection are errors, and so is a
+/// cancel once data is sent as the connection cannot go on.
+async fn transfer_file(
+    mut request: Request,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Context,
+    reader: &mut (impl AsyncRead + std::marker::Unpin),
+    writer: &mut (impl AsyncWriteExt + std::marker::Unpin),
+) -> Result<Response> {
+    let (_registration, cancelled) = match ctx.register(&mut request, peer) {
+        Ok(registered) => registered,
+        Err(response) => return refuse(&request, *response, writer).await,
+    };
+    tokio::pin!(cancelled);
+    let _permit = match wait_slot(&request, peer, uuid, ctx, cancelled.as_mut()).await {
+        Ok(permit) => permit,
+        Err(response) => return refuse(&request, *response, writer).await,
+    };
+    let (path, owner) = match prepare_transfer(&request, peer, &ctx.settings()).await {
+        Ok(prepared) => prepared,
+        Err(response) => return refuse(&request, *response, writer).await,
+    };
+    let response = tokio::select! {
+        response = copy_file(&request, &path, owner, reader, writer) => response?,
+        _ = cancelled => {
+            info!("[{uuid}] - Request {:?} is cancelled", request.id);
+            return Err(anyhow::anyhow!("Transfer is cancelled"));
+        }
+    };
+    info!("[{uuid}] - {} {}: {response:?}", request.command_line(), path.display());
+    Ok(response)
+}
+
+/// Answer a transfer of `request` which is not done with `response`
+async fn refuse(request: &Request, response: Response, writer: &mut (impl AsyncWriteExt + std::marker::Unpin)) -> Result<Response> {
+    let response = Response { version: Some(protocol::VERSION), id: request.id.clone(), ..response };
+    protocol::send(writer, &response).await?;
+    Ok(response)
+}
+
+/// Put or get the file at `path` for `request`, return the last response
+async fn copy_file(
+    request: &Request,
+    path: &Path,
+    owner: Owner,
+    reader: &mut (impl AsyncRead + std::marker::Unpin),
+    writer: &mut (impl AsyncWriteExt + std::marker::Unpin),
+) -> Result<Response> {
+    let reply = |response: Response| Response { version: Some(protocol::VERSION), id: request.id.clone(), ..response };
+    let response = match &request.put {
+        Some(put) => {
+            // the client sends the data once the server is ready for it
+            protocol::send(writer, &reply(Response::default())).await?;
+            let response = match transfer::receive(reader, put, path, owner).await? {
+                Ok(file) => reply(Response { file: Some(file), ..Default::default() }),
+                Err((kind, error)) => reply(Response::error(kind, error)),
+            };
+            protocol::send(writer, &response).await?;
+            response
+        }
+        None => match transfer::open(path).await {
+            Ok((file, info)) => {
+                protocol::send(writer, &reply(Response { file: Some(info.clone()), ..Default::default() })).await?;
+                let sha256 = transfer::send(file, info.size, writer).await?;
+                reply(Response { file: Some(FileInfo { sha256, ..info }), ..Default::default() })
+            }
+            Err(e) => {
+                let kind = match e.kind() {
+                    std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+                    // not a regular file
+                    std::io::ErrorKind::InvalidInput => ErrorKind::Invalid,
+                    _ => ErrorKind::Internal,
+                };
+                let response = reply(Response::error(kind, format!("Failed to open {}: {e}", path.display())));
+                protocol::send(writer, &response).await?;
+                response
+            }
+        },
+    };
+    Ok(response)
+}
+
+/// Resolve the path of the transfer of `request`, which must be allowed with
+/// symlinks resolved too, and the owner of a file it writes
+async fn prepare_transfer(request: &Request, peer: &Peer, settings: &Settings) -> std::result::Result<(PathBuf, Owner), Box<Response>> {
+    let (given, write) = match (&request.put, &request.get) {
+        (Some(put), _) => (put.path.as_str(), true),
+        (None, Some(get)) => (get.path.as_str(), false),
+        (None, None) => return Err(Box::new(Response::error(ErrorKind::Invalid, "Request transfers no file"))),
+    };
+    let path = transfer::resolve(given, write).await.map_err(|e| {
+        let kind = match e.kind() {
+            std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+            _ => ErrorKind::Internal,
+        };
+        Response::error(kind, format!("Failed to resolve {given}: {e}"))
+    })?;
+    if let Some(policy) = &settings.policy
+        && path != Path::new(given)
+    {
+        let mut resolved = request.clone();
+        let text = path.to_string_lossy().into_owned();
+        match (&mut resolved.put, &mut resolved.get) {
+            (Some(put), _) => put.path = text,
+            (None, Some(get)) => get.path = text,
+            (None, None) => (),
+        }
+        policy.authorize(peer, &resolved).map_err(|denied| Response::error(denied.kind, format!("{given} resolves to {}: {denied}", path.display())))?;
+    }
+    let profile = match &request.profile {
+        Some(name) => match settings.policy.as_ref().and_then(|p| p.profiles.get(name)) {
+            Some(profile) => Some(profile),
+            None => return Err(Box::new(Response::error(ErrorKind::NotFound, format!("No profile {name}")))),
+        },
+        None => None,
+    };
+    let failed = |e: std::io::Error| Response::error(ErrorKind::Internal, format!("Failed to find owner of {given}: {e}"));
+    // files are owned by the user of the profile, like the ones its commands write
+    let (uid, gid) = profile.map(|profile| profile.owner()).transpose().map_err(failed)?.unwrap_or_default();
+    if uid.is_some() || gid.is_some() {
+        return Ok((path, Owner { uid, gid, required: true, ..Default::default() }));
+    }
+    let put = request.put.as_ref();
+    let user = put.and_then(|put| put.user.as_deref());
+    let group = put.and_then(|put| put.group.as_deref());
+    let uid = user.map(profile::lookup_uid).transpose().map_err(failed)?;
+    let gid = group.map(profile::lookup_group).transpose().map_err(failed)?;
+    // peers may give files to themselves, to others only as their rule lets them
+    if let Some(policy) = &settings.policy {
+        for (name, id, own) in [(user, uid, peer.uid()), (group, gid, peer.gid())] {
+            if let Some(name) = name
+                && id != own
+            {
+                let proven = policy.authenticate(peer, request).map_err(|denied| Response::error(denied.kind, denied))?;
+                policy.permit_owner(proven, request, name).map_err(|denied| Response::error(denied.kind, denied))?;
+            }
+        }
+    }
+    Ok((path, Owner { uid, gid, created: (peer.uid(), peer.gid()), required: false }))
+}
+
+/// Run `request` in a terminal of `tty`, with session frames read from
+/// `reader` as its input and its output sent to `writer`, then the exit status
+async fn session_request(
+    request: Request,
+    tty: Tty,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Context,
+    reader: &mut (impl AsyncRead + std::marker::Unpin),
+    writer: &mut (impl AsyncWriteExt + std::marker::Unpin),
+) -> Result<()> {
+    if let Some(response) = ctx.query(&request, peer) {
+        return protocol::send(writer, &StreamFrame::Exit(response)).await;
+    }
+    let settings = ctx.settings();
+    let mut record = Record::new(uuid, peer, &request, &settings.redactor);
+    let (response, sent, captures) = session_command(request, tty, peer, uuid, ctx, reader, writer).await;
+    let output = captures.map(|(typed, output)| {
+        // keystrokes are not redacted by rules, only their digest is kept
+        record.input = Some(typed.finish_hidden(&settings.redactor));
+        (output, Capture::default())
+    });
+    ctx.audit(record.finish(&response, output, &settings.redactor));
+    sent?;
+    protocol::send(writer, &StreamFrame::Exit(response)).await
+}
+
+/// Run the session of `request`, answer with the exit status, whether output
+/// could be sent, and the captures of typed input and of output if it ran
+async fn session_command(
+    mut request: Request,
+    tty: Tty,
+    peer: &Peer,
+    uuid: Uuid,
+    ctx: &Context,
+    reader: &mut (impl AsyncRead + std::marker::Unpin),
+    writer: &mut (impl AsyncWriteExt + std::marker::Unpin),
+) -> (Response, Result<()>, Option<(Capture, Capture)>) {
+    let settings = ctx.settings();
+    let (_registration, cancelled) = match ctx.register(&mut request, peer) {
+        Ok(registered) => registered,
+        Err(response) => return (*response, Ok(()), None),
+    };
+    let id = request.id.clone().unwrap_or_default();
+    if let Err(e) = protocol::send(writer, &StreamFrame::Started { id }).await {
+        return (Response { id: request.id.clone(), version: Some(protocol::VERSION), ..Default::default() }, Err(e), None);
+    }
+    let (input_tx, input_rx) = mpsc::channel(16);
+    let mut typed = Capture::default();
+    // the client is gone once its connection is closed, unless it only closed its write side
+    let relay_input = async {
+        loop {
+            let frame = match protocol::recv::<_, SessionFrame>(reader).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) if request.half_close => {
+                    let _ = input_tx.send(Input::Eof).await;
+                    return std::future::pending().await;
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("[{uuid}] - Invalid session frame: {e}");
+                    return;
+                }
+            };
+            let input = match frame {
+                SessionFrame::Input { data, encoding } => match protocol::decode(&data, encoding) {
+                    Ok(data) => {
+                        typed.feed(&data);
+                        Input::Data(data)
+                    }
+                    Err(e) => {
+                        warn!("[{uuid}] - Invalid session frame: {e}");
+                        return;
+                    }
+                },
+                SessionFrame::Resize { rows, cols } => Input::Resize { rows, cols },
+                SessionFrame::Eof => Input::Eof,
+            };
+            // input sent after the command exited is dropped
+            let _ = input_tx.send(input).await;
+        }
+    };
+    let mut cancel = Box::pin(async {
+        tokio::select! {
+            _ = cancelled => info!("[{uuid}] - Request {:?} is cancelled", request.id),
+            _ = relay_input => info!("[{uuid}] - Client is gone, cancel request {:?}", request.id),
+        }
+    });
+    let _permit = match wait_slot(&request, peer, uuid, ctx, &mut cancel).await {
+        Ok(permit) => permit,
+        Err(error) => return (Response { id: request.id.clone(), version: Some(protocol::VERSION), ..*error }, Ok(()), None),
+    };
+    let (mut command, _sandbox) = match command(&request, &settings) {
+        Ok(command) => command,
+        Err(error) => return (Response { id: request.id.clone(), version: Some(protocol::VERSION), ..*error }, Ok(()), None),
+    };
+    if !request.env.contains_key("TERM")
+        && let Ok(term) = tty.term()
+    {
+        command.env("TERM", term);
     }
+    let (tx, mut rx) = mpsc::channel(16);
+    let session = exec::session(command, (tty.rows, tty.cols), options(&request, &settings), cancel.as_mut(), input_rx, tx);
+    let mut output = Capture::default();
+    let forward = async {
+        let mut decoder = Decoder::default();
+        let mut sent = 0;
+        while let Some(chunk) = rx.recv().await {
+            output.feed(&chunk.data);
+            let (data, encoding) = decoder.decode(&chunk.data);
+            protocol::send(writer, &StreamFrame::Stdout { data, encoding }).await?;
+            sent += chunk.data.len();
+        }
+        let (data, encoding) = decoder.finish();
+        if !data.is_empty() {
+            protocol::send(writer, &StreamFrame::Stdout { data, encoding }).await?;
+        }
+        anyhow::Ok(sent)
+    };
+    let (outcome, sent) = tokio::join!(session, forward);
+    drop(cancel);
+    let mut response = Response {
+        version: Some(protocol::VERSION),
+        id: request.id.clone(),
+        ..Default::default()
+    };
+    match outcome {
+        Ok(outcome) => finish(&mut response, &outcome),
+        Err(e) => {
+            warn!("[{uuid}] - Failed to run: {e}");
+            response.error = format!("{e}");
+        }
+    }
+    match &sent {
+        Ok(sent) => info!("[{uuid}] - Session sent {sent} bytes, got {}", response.summary()),
+        Err(e) => warn!("[{uuid}] - Failed to send session output: {e}, got {}", response.summary()),
+    }
+    (response, sent.map(|_| ()), Some((typed, output)))
+}
+
+fn bash(cmd: &str) -> Command {
+    info!("Run command: {cmd}");
+    let mut command = Command::new("bash");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+async fn run_cmd(cmd: &str, input: Option<&str>) -> Result<Output> {
+    let input = input.map_or(Stdin::Null, |input| Stdin::Data(input.as_bytes().to_vec()));
+    let (outcome, stdout, stderr) = exec::collect(bash(cmd), Options::new(input), std::future::pending()).await?;
+    Ok(Output {
+        status: outcome.status,
+        stdout,
+        stderr,
+    })
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Log rollback file size (Mb)
-    #[arg(long, default_value_t = 50)]
-    logsize: u64,
-    /// Max rollback file number
-    #[arg(long, default_value_t = 50)]
-    logmax: u32,
-    /// Log file path, the direcotry will be created automatically
-    #[arg(long, default_value = "/var/log/forwarder/")]
-    logdir: String,
+    /// TOML config file, the flags below override its settings
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Unix socket to listen on, /var/run/forwarder/forwarder.sock by default
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// Log rollback file size (Mb), 50 by default
+    #[arg(long)]
+    logsize: Option<u64>,
+    /// Max rollback file number, 50 by default
+    #[arg(long)]
+    logmax: Option<u32>,
+    /// Log file path, the direcotry will be created automatically, /var/log/forwarder/ by default
+    #[arg(long)]
+    logdir: Option<String>,
+    /// Where to log, file, journald, syslog or stderr, repeated for several sinks, file by default
+    #[arg(long, value_enum)]
+    log_sink: Vec<logging::Sink>,
+    /// Level of modules without one of their own, info by default
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+    /// Level of a module, as MODULE=LEVEL, e.g. forwarder::exec=debug
+    #[arg(long, value_parser = module_level)]
+    log_module: Vec<(String, LevelFilter)>,
+    /// Format of the file and stderr sinks, pattern by default
+    #[arg(long, value_enum)]
+    log_format: Option<logging::Format>,
     /// Enable TCP listener
     #[arg(long, group = "tcprpc")]
     tcp: bool,
-    /// Tcp listen host
-    #[arg(long, default_value = "127.0.0.1", requires = "tcprpc")]
-    host: String,
-    /// Tcp listen port
-    #[arg(long, default_value_t = 7788, requires = "tcprpc")]
-    port: u16,
+    /// Tcp listen host, 127.0.0.1 by default
+    #[arg(long, requires = "tcprpc")]
+    host: Option<String>,
+    /// Tcp listen port, 7788 by default
+    #[arg(long, requires = "tcprpc")]
+    port: Option<u16>,
+    /// PEM certificate chain to serve TLS on the TCP listener with
+    #[arg(long, requires_all = ["tcprpc", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM CA bundle to require client certificates issued by, the certificate names the owner
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// Policy file of allowed peers and commands, everything is allowed without it
+    #[arg(long)]
+    policy: Option<PathBuf>,
+    /// Max output (Mb) of one command, output beyond it is dropped, 8 by default which is also the largest allowed
+    #[arg(long)]
+    max_output: Option<usize>,
+    /// cgroup v2 directory delegated to forwarder, transient cgroups of profiles are created in it,
+    /// /sys/fs/cgroup/forwarder by default
+    #[arg(long)]
+    cgroup_root: Option<PathBuf>,
+    /// Seconds to keep results of finished jobs, 3600 by default
+    #[arg(long)]
+    job_retention: Option<u64>,
+    /// Directory to keep jobs across restarts, jobs are kept in memory only without it
+    #[arg(long)]
+    job_dir: Option<PathBuf>,
+    /// Max commands running at once, further requests are queued, 64 by default
+    #[arg(long)]
+    max_running: Option<usize>,
+    /// Max commands of one owner running at once, 16 by default
+    #[arg(long)]
+    max_running_per_owner: Option<usize>,
+    /// Max requests waiting to run, further requests are rejected, 256 by default
+    #[arg(long)]
+    max_queued: Option<usize>,
+    /// Regex of secrets to redact from audit records besides the default ones,
+    /// only its group `secret` is redacted if it has one
+    #[arg(long)]
+    audit_redact: Vec<String>,
+    /// Seconds to wait for running commands on SIGTERM before cancelling them, 30 by default
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+    /// Seconds a connection may idle between requests before it is closed, 300 by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: Option<u64>,
+    /// Serve `/metrics` and `/healthz` over HTTP on this address, e.g. 127.0.0.1:9788
+    #[arg(long, value_name = "ADDR")]
+    metrics: Option<String>,
 }
 
-const SOCK: &str = "/var/run/forwarder/forwarder.sock";
-#[tokio::main]
-async fn main() -> Result<()> {
-    let opt = Cli::parse();
-    if let Err(e) = logging::init(&opt.logdir, opt.logsize, opt.logmax) {
-        println!("Failed to create logfile.");
-        return Err(e);
+/// `MODULE=LEVEL` of `--log-module`
+fn module_level(arg: &str) -> Result<(String, LevelFilter), String> {
+    let (module, level) = arg.split_once('=').ok_or("expected MODULE=LEVEL")?;
+    Ok((module.to_string(), level.parse().map_err(|_| format!("invalid level `{level}`"))?))
+}
+
+impl Cli {
+    /// Settings of the config file, or the default ones, overridden by flags
+    fn config(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(socket) = &self.socket {
+            config.socket = socket.clone();
+        }
+        if let Some(size) = self.logsize {
+            config.log.size = size;
+        }
+        if let Some(max) = self.logmax {
+            config.log.max = max;
+        }
+        if let Some(dir) = &self.logdir {
+            config.log.dir = dir.clone();
+        }
+        if !self.log_sink.is_empty() {
+            config.log.sinks = self.log_sink.clone();
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        config.log.modules.extend(self.log_module.iter().cloned());
+        if let Some(format) = self.log_format {
+            config.log.format = format;
+        }
+        if self.tcp {
+            let tcp = config.tcp.get_or_insert_with(TcpConfig::default);
+            if let Some(host) = &self.host {
+                tcp.host = host.clone();
+            }
+            if let Some(port) = self.port {
+                tcp.port = port;
+            }
+            if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+                tcp.tls = Some(TlsFiles { cert: cert.clone(), key: key.clone(), client_ca: self.tls_client_ca.clone() });
+            }
+        }
+        if let Some(policy) = &self.policy {
+            config.policy_file = Some(policy.clone());
+            config.policy = None;
+        }
+        if let Some(max_output) = self.max_output {
+            config.max_output = max_output;
+        }
+        if let Some(cgroup_root) = &self.cgroup_root {
+            config.cgroup_root = cgroup_root.clone();
+        }
+        if let Some(retention) = self.job_retention {
+            config.jobs.retention_secs = retention;
+        }
+        if let Some(dir) = &self.job_dir {
+            config.jobs.dir = Some(dir.clone());
+        }
+        if let Some(running) = self.max_running {
+            config.limits.running = running;
+        }
+        if let Some(per_owner) = self.max_running_per_owner {
+            config.limits.per_owner = per_owner;
+        }
+        if let Some(queued) = self.max_queued {
+            config.limits.queued = queued;
+        }
+        config.audit.redact.extend(self.audit_redact.iter().cloned());
+        if let Some(timeout) = self.shutdown_timeout {
+            config.shutdown_timeout_secs = timeout;
+        }
+        if let Some(timeout) = self.idle_timeout {
+            config.idle_timeout_secs = timeout;
+        }
+        if let Some(listen) = &self.metrics {
+            config.metrics = Some(MetricsConfig { listen: listen.clone() });
+        }
+        Ok(config)
     }
+}
 
-    let path = Path::new(SOCK);
+/// Bind the Unix socket of `config` with its permissions, replacing a stale one
+fn bind_unix(config: &Config) -> Result<UnixListener> {
+    let path = config.socket.as_path();
     if path.exists() {
-        fs::remove_file(SOCK)?;
-    } else {
-        let dir = path.parent().unwrap();
-        if !dir.try_exists()? {
-            fs::create_dir_all(dir)?;
+        fs::remove_file(path)?;
+    } else if let Some(dir) = path.parent()
+        && !dir.try_exists()?
+    {
+        fs::create_dir_all(dir)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(group) = &config.socket_group {
+        let gid = profile::lookup_group(group).map_err(|e| anyhow::anyhow!("Failed to find group {group}: {e}"))?;
+        std::os::unix::fs::chown(path, None, Some(gid))?;
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(config.socket_mode))?;
+    Ok(listener)
+}
+
+async fn serve_unix(listener: UnixListener, ctx: Arc<Context>) -> Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let uuid = Uuid::new_v4();
+        let peer = match stream.peer_cred() {
+            Ok(cred) => Peer::Unix { uid: cred.uid(), gid: cred.gid(), pid: cred.pid() },
+            Err(e) => {
+                error!("[{uuid}] Failed to get peer credentials: {e}");
+                continue;
+            }
+        };
+        info!("[{uuid}] Accept connection from {addr:?} {peer}");
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(stream, peer, uuid, ctx).await {
+                error!("[{uuid}] {e}");
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, tls: Option<Arc<Tls>>, ctx: Arc<Context>) -> Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let uuid = Uuid::new_v4();
+        info!("[{uuid}] Accept connection from {addr:?}");
+        let ctx = ctx.clone();
+        let acceptor = tls.as_ref().map(|tls| tls.acceptor());
+        tokio::spawn(async move {
+            let result = match acceptor {
+                None => handle_stream(stream, Peer::Tcp(addr), uuid, ctx).await,
+                Some(acceptor) => match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let identity = stream.get_ref().1.peer_certificates().and_then(|certs| tls::identity(&certs[0]));
+                        let peer = Peer::Tls { addr, identity };
+                        info!("[{uuid}] TLS peer {peer}");
+                        handle_stream(stream, peer, uuid, ctx).await
+                    }
+                    Ok(Err(e)) => Err(anyhow::anyhow!("TLS handshake failed: {e}")),
+                    Err(_) => Err(anyhow::anyhow!("TLS handshake timed out")),
+                },
+            };
+            if let Err(e) = result {
+                error!("[{uuid}] {e}");
+            }
+        });
+    }
+}
+
+/// Answer scrapes of metrics and health checks, which report unhealthy once stopping
+async fn serve_metrics(listener: TcpListener, ctx: Arc<Context>) -> Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let answer = metrics::answer(stream, |path| match path {
+                "/metrics" => (200, ctx.metrics.render(&ctx.limiter.stats())),
+                "/healthz" if ctx.stopping.load(Ordering::Relaxed) => (503, "stopping\n".to_string()),
+                "/healthz" => (200, "ok\n".to_string()),
+                _ => (404, "not found\n".to_string()),
+            });
+            match tokio::time::timeout(SCRAPE_TIMEOUT, answer).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => debug!("Failed to answer scrape of {addr}: {e}"),
+                Err(_) => debug!("Scrape of {addr} timed out"),
+            }
+        });
+    }
+}
+
+/// Apply the config read again to requests accepted from now on, `current` is
+/// the one the server started with
+fn reload(opt: &Cli, current: &Config, ctx: &Context) -> Result<()> {
+    let config = opt.config()?;
+    *ctx.settings.write().unwrap() = Arc::new(Settings::new(&config)?);
+    ctx.limiter.set_limits(config.limits);
+    let restart = current.restart_needed(&config);
+    if !restart.is_empty() {
+        warn!("Changes of {} apply after restart", restart.join(", "));
+    }
+    Ok(())
+}
+
+/// Stop accepting commands and wait for running ones until `timeout`, then cancel them
+async fn shutdown(ctx: &Context, listeners: Vec<JoinHandle<Result<()>>>, socket: Option<&Path>, timeout: Duration) {
+    systemd::stopping();
+    ctx.stopping.store(true, Ordering::Relaxed);
+    for listener in listeners {
+        listener.abort();
+    }
+    if let Some(path) = socket
+        && let Err(e) = fs::remove_file(path)
+    {
+        warn!("Failed to remove {}: {e}", path.display());
+    }
+    let deadline = Instant::now() + timeout;
+    let mut last = 0;
+    loop {
+        let running = ctx.running.lock().unwrap().len();
+        if running == 0 {
+            return;
+        }
+        if running != last {
+            info!("Wait for {running} running requests");
+            systemd::status(&format!("Stopping, {running} requests running"));
+            last = running;
         }
+        if Instant::now() >= deadline {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
-    let mut handlers: Vec<JoinHandle<Result<()>>> = Vec::new();
+    let cancelled: Vec<_> = ctx.running.lock().unwrap().drain().collect();
+    warn!("Cancel {} requests still running after {timeout:?}", cancelled.len());
+    for (_, request) in cancelled {
+        let _ = request.cancel.send(());
+    }
+    // commands surviving SIGTERM are killed after the grace period
+    let deadline = Instant::now() + exec::KILL_GRACE + Duration::from_secs(1);
+    while ctx.limiter.stats().running > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
 
-    let unix_listener = UnixListener::bind(SOCK)?;
-    let mut perms = fs::metadata(SOCK)?.permissions();
-    perms.set_mode(0o666);
-    fs::set_permissions(SOCK, perms)?;
-    handlers.push(tokio::spawn(async move {
+fn main() -> Result<()> {
+    let opt = Cli::parse();
+    let config = opt.config()?;
+    if let Err(e) = logging::init(&config.log) {
+        println!("Failed to set up logging.");
+        return Err(e);
+    }
+    // both unset their variables, which is only sound before other threads exist
+    let activated = systemd::listen_fds()?;
+    let watchdog = systemd::watchdog();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve(opt, config, activated, watchdog))
+}
+
+async fn serve(opt: Cli, config: Config, activated: Activated, watchdog: Option<Duration>) -> Result<()> {
+    let ctx = Arc::new(Context {
+        settings: RwLock::new(Arc::new(Settings::new(&config)?)),
+        running: Mutex::new(HashMap::new()),
+        jobs: Jobs::new(Duration::from_secs(config.jobs.retention_secs), config.jobs.dir.as_deref())?,
+        job_timeout: Duration::from_secs(config.jobs.timeout_secs),
+        idle_timeout: config.idle_timeout(),
+        limiter: Limiter::new(config.limits),
+        stopping: AtomicBool::new(false),
+        metrics: Metrics::default(),
+    });
+    let purge_ctx = ctx.clone();
+    tokio::spawn(async move {
         loop {
-            let (stream, addr) = unix_listener.accept().await?;
-            let uuid = Uuid::new_v4();
-            info!("[{uuid}] Accept connection from {addr:?}");
-            tokio::spawn(async move {
-                match handle_stream(stream, uuid).await {
-                    Err(e) => error!("[{uuid}] {e}"),
-                    _ => (),
-                }
-            });
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            purge_ctx.jobs.purge();
         }
-        Ok(())
-    }));
+    });
+
+    let mut handlers: Vec<JoinHandle<Result<()>>> = Vec::new();
+    // a socket passed by systemd is left to it
+    let bound = match activated.unix {
+        Some(listener) => {
+            handlers.push(tokio::spawn(serve_unix(UnixListener::from_std(listener)?, ctx.clone())));
+            None
+        }
+        None => {
+            let listener = bind_unix(&config)?;
+            info!("Listen on {}", config.socket.display());
+            handlers.push(tokio::spawn(serve_unix(listener, ctx.clone())));
+            Some(config.socket.clone())
+        }
+    };
 
-    if opt.tcp {
-        let host = format!("{}:{}", opt.host, opt.port);
-        let tcp_listener = TcpListener::bind(&host).await?;
+    let tcp_listener = match (activated.tcp, &config.tcp) {
+        (Some(listener), _) => Some(TcpListener::from_std(listener)?),
+        (None, Some(tcp)) => Some(TcpListener::bind((tcp.host.as_str(), tcp.port)).await?),
+        (None, None) => None,
+    };
+    let mut tls = None;
+    if let Some(listener) = tcp_listener {
+        let addr = listener.local_addr()?;
+        match config.tcp.as_ref().and_then(|tcp| tcp.tls.clone()) {
+            Some(files) => tls = Some(Arc::new(Tls::load(files)?)),
+            None => warn!("TCP listener {addr} serves plaintext"),
+        }
+        info!("Listen on {addr}");
+        handlers.push(tokio::spawn(serve_tcp(listener, tls.clone(), ctx.clone())));
+    }
 
-        handlers.push(tokio::spawn(async move {
+    // not stopped on shutdown, health checks see the server stopping
+    if let Some(metrics) = &config.metrics {
+        let listener = TcpListener::bind(metrics.listen.as_str()).await?;
+        info!("Serve metrics on {}", listener.local_addr()?);
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(listener, ctx).await {
+                error!("Metrics listener failed: {e}");
+            }
+        });
+    }
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reload_ctx = ctx.clone();
+    let current = config.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            systemd::reloading();
+            match reload(&opt, &current, &reload_ctx) {
+                Ok(()) => info!("Reloaded config"),
+                Err(e) => error!("Failed to reload config, keep the current one: {e}"),
+            }
+            if let Some(tls) = &tls
+                && let Err(e) = tls.reload()
+            {
+                error!("Failed to reload TLS certificate, keep the current one: {e}");
+            }
+            systemd::ready();
+        }
+    });
+    if let Some(interval) = watchdog {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
             loop {
-                let (stream, addr) = tcp_listener.accept().await?;
-                let uuid = Uuid::new_v4();
-                info!("[{uuid}] Accept connection from {addr:?}");
-                tokio::spawn(async move {
-                    match handle_stream(stream, uuid).await {
-                        Err(e) => error!("[{uuid}] {e}"),
-                        _ => (),
-                    }
-                });
+                interval.tick().await;
+                systemd::ping();
             }
-            Ok(())
-        }));
+        });
     }
+    systemd::ready();
 
-    for handle in handlers {
-        handle.await?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let served = async {
+        for handle in &mut handlers {
+            handle.await??;
+        }
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        result = served => return result,
+        _ = terminate.recv() => info!("Got SIGTERM, shutting down"),
+        _ = interrupt.recv() => info!("Got SIGINT, shutting down"),
     }
+    shutdown(&ctx, handlers, bound.as_deref(), config.shutdown_timeout()).await;
+    info!("Stopped");
     Ok(())
 }
 
@@ -214,6 +1656,44 @@ async fn main() -> Result<()> {
 mod tests {
     use super::*;
 
+    fn context(config: &Config) -> Arc<Context> {
+        Arc::new(Context {
+            settings: RwLock::new(Arc::new(Settings::new(config).unwrap())),
+            running: Mutex::new(HashMap::new()),
+            jobs: Jobs::new(Duration::from_secs(60), None).unwrap(),
+            job_timeout: Duration::from_secs(60),
+            idle_timeout: config.idle_timeout(),
+            limiter: Limiter::new(config.limits),
+            stopping: AtomicBool::new(false),
+            metrics: Metrics::default(),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_connection() {
+        let ctx = context(&Config { idle_timeout_secs: 60, ..Config::default() });
+        let peer = Peer::Tcp("127.0.0.1:7788".parse().unwrap());
+
+        // nothing is sent
+        let (mut client, server) = tokio::net::UnixStream::pair().unwrap();
+        let started = tokio::time::Instant::now();
+        handle_stream(server, peer.clone(), Uuid::new_v4(), ctx.clone()).await.unwrap();
+        assert!(started.elapsed() >= READ_TIMEOUT && started.elapsed() < ctx.idle_timeout);
+        assert_eq!(client.read(&mut [0; 16]).await.unwrap(), 0);
+
+        // nothing is sent after an answered request
+        let (mut client, server) = tokio::net::UnixStream::pair().unwrap();
+        let handler = tokio::spawn(handle_stream(server, peer, Uuid::new_v4(), ctx.clone()));
+        client.write_all(&[0, 0, 0, 1, b'x']).await.unwrap();
+        let data = protocol::read_frame(&mut client, protocol::MAX_FRAME).await.unwrap().unwrap();
+        let response: Response = serde_json::from_slice(&data).unwrap();
+        assert_eq!(response.kind, Some(ErrorKind::Invalid));
+        let started = tokio::time::Instant::now();
+        handler.await.unwrap().unwrap();
+        assert!(started.elapsed() >= ctx.idle_timeout);
+        assert_eq!(client.read(&mut [0; 16]).await.unwrap(), 0);
+    }
+
     #[tokio::test]
     async fn test_run_cmd() {
         let output = run_cmd("echo test", None).await.unwrap();
@@ -241,6 +1721,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn test_run_cmd_glob() {
         let output = run_cmd("find . -name Car*", None).await.unwrap();
         assert_eq!(output.status.success(), false);
@@ -0,0 +1,291 @@
+//! Prometheus metrics and health of the server.
+//!
+//! With a metrics listener configured, `GET /metrics` answers the counters of
+//! finished requests, by authenticated owner and outcome, the duration of commands, their
+//! output and the state of the limiter in the Prometheus text format, and
+//! `GET /healthz` answers `ok` until the server is stopping. Requests are
+//! counted from their audit records, so a metric covers what is audited.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::audit::{PeerRecord, Record};
+use crate::auth::ErrorKind;
+use crate::exec::Termination;
+use crate::limit::Stats;
+
+/// Upper bounds (seconds) of the buckets of command durations
+const BUCKETS: [f64; 12] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+/// Upper bound of the request line and headers of a scrape
+const MAX_HEAD: usize = 8192;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations of each bucket, not cumulated
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| value <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Finished requests by authenticated owner and outcome
+    requests: Mutex<BTreeMap<(String, &'static str), u64>>,
+    duration: Mutex<Histogram>,
+    stdout_bytes: AtomicU64,
+    stderr_bytes: AtomicU64,
+    /// Connections closed as a request was not read in time
+    read_timeouts: AtomicU64,
+}
+
+/// Outcome label of a finished request
+fn outcome(record: &Record) -> &'static str {
+    match (record.kind, record.status) {
+        (Some(ErrorKind::Invalid), _) => "invalid",
+        (Some(ErrorKind::Unauthenticated), _) => "unauthenticated",
+        (Some(ErrorKind::Denied), _) => "denied",
+        (Some(ErrorKind::NotFound), _) => "not_found",
+        (Some(ErrorKind::Running), _) => "running",
+        (Some(ErrorKind::Busy), _) => "busy",
+        (Some(ErrorKind::Internal), _) => "internal",
+        (None, Some(Termination::Exited)) if record.code == Some(0) => "success",
+        (None, Some(Termination::Exited)) => "failure",
+        (None, Some(Termination::Signaled)) => "signaled",
+        (None, Some(Termination::TimedOut)) => "timed_out",
+        (None, Some(Termination::Cancelled)) => "cancelled",
+        (None, None) if record.file.is_some() => "success",
+        // the command could not be spawned
+        (None, None) => "error",
+    }
+}
+
+/// Owner label of `record`, who sent it as far as it is proven: the uid or
+/// certificate identity, the owner of other TCP peers when their token
+/// `proven` it, so that clients cannot add labels at will
+fn owner(record: &Record, proven: bool) -> String {
+    match &record.peer {
+        PeerRecord::Unix { uid, .. } => format!("uid {uid}"),
+        PeerRecord::Tls { identity: Some(identity), .. } => identity.clone(),
+        _ if proven => record.owner.clone(),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Escape `value` as a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+impl Metrics {
+    /// Count the request of the finished `record`, whose owner is `proven` by a policy
+    pub fn observe(&self, record: &Record, proven: bool) {
+        *self.requests.lock().unwrap().entry((owner(record, proven), outcome(record))).or_default() += 1;
+        if record.status.is_some() {
+            let secs = record.end_ms.saturating_sub(record.start_ms) as f64 / 1000.0;
+            self.duration.lock().unwrap().observe(secs);
+        }
+        if let Some(stdout) = &record.stdout {
+            self.stdout_bytes.fetch_add(stdout.bytes as u64, Ordering::Relaxed);
+        }
+        if let Some(stderr) = &record.stderr {
+            self.stderr_bytes.fetch_add(stderr.bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn read_timeout(&self) {
+        self.read_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Metrics in the Prometheus text format, with the limiter in `stats`
+    pub fn render(&self, stats: &Stats) -> String {
+        let mut text = String::new();
+        let _ = self.write(&mut text, stats);
+        text
+    }
+
+    fn write(&self, text: &mut String, stats: &Stats) -> std::fmt::Result {
+        writeln!(text, "# HELP forwarder_requests_total Finished requests by authenticated owner and outcome.")?;
+        writeln!(text, "# TYPE forwarder_requests_total counter")?;
+        for ((owner, outcome), count) in self.requests.lock().unwrap().iter() {
+            writeln!(text, "forwarder_requests_total{{owner=\"{}\",outcome=\"{outcome}\"}} {count}", escape(owner))?;
+        }
+
+        let duration = self.duration.lock().unwrap();
+        writeln!(text, "# HELP forwarder_command_duration_seconds Time commands ran for.")?;
+        writeln!(text, "# TYPE forwarder_command_duration_seconds histogram")?;
+        let mut cumulated = 0;
+        for (bound, count) in BUCKETS.iter().zip(duration.counts) {
+            cumulated += count;
+            writeln!(text, "forwarder_command_duration_seconds_bucket{{le=\"{bound}\"}} {cumulated}")?;
+        }
+        writeln!(text, "forwarder_command_duration_seconds_bucket{{le=\"+Inf\"}} {}", duration.count)?;
+        writeln!(text, "forwarder_command_duration_seconds_sum {}", duration.sum)?;
+        writeln!(text, "forwarder_command_duration_seconds_count {}", duration.count)?;
+        drop(duration);
+
+        writeln!(text, "# HELP forwarder_output_bytes_total Output of commands, including output beyond the cap.")?;
+        writeln!(text, "# TYPE forwarder_output_bytes_total counter")?;
+        writeln!(text, "forwarder_output_bytes_total{{stream=\"stdout\"}} {}", self.stdout_bytes.load(Ordering::Relaxed))?;
+        writeln!(text, "forwarder_output_bytes_total{{stream=\"stderr\"}} {}", self.stderr_bytes.load(Ordering::Relaxed))?;
+
+        let gauges = [
+            ("forwarder_active_children", "Commands running.", stats.running),
+            ("forwarder_queue_depth", "Requests waiting for a slot to run.", stats.queued),
+        ];
+        for (name, help, value) in gauges {
+            writeln!(text, "# HELP {name} {help}")?;
+            writeln!(text, "# TYPE {name} gauge")?;
+            writeln!(text, "{name} {value}")?;
+        }
+        let counters = [
+            ("forwarder_queue_rejected_total", "Requests rejected as the queue was full.", stats.rejected),
+            ("forwarder_read_timeouts_total", "Connections closed as a request was not read in time.", self.read_timeouts.load(Ordering::Relaxed)),
+        ];
+        for (name, help, value) in counters {
+            writeln!(text, "# HELP {name} {help}")?;
+            writeln!(text, "# TYPE {name} counter")?;
+            writeln!(text, "{name} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Answer one HTTP request of `stream` with the status and body `route` gives
+/// for its path, only `GET` is served
+pub async fn answer(stream: impl AsyncRead + AsyncWrite + Unpin, route: impl FnOnce(&str) -> (u16, String)) -> io::Result<()> {
+    // lines are read to their end, the head is bounded while it is read
+    let mut stream = BufReader::new(stream).take(MAX_HEAD as u64 + 1);
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let request = line.clone();
+    // headers are not needed, read to their end so that the client is not reset
+    while line != "\r\n" && line != "\n" {
+        if stream.limit() == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Request head is too large"));
+        }
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            break;
+        }
+    }
+    let (status, body) = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", path, _] => route(path.split('?').next().unwrap_or_default()),
+        [_, _, _] => (405, "method not allowed\n".to_string()),
+        _ => (400, "bad request\n".to_string()),
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let stream = stream.get_mut().get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{Capture, Redactor};
+    use crate::auth::Peer;
+    use crate::protocol::{Request, Response};
+    use tokio::net::{TcpListener, TcpStream};
+    use uuid::Uuid;
+
+    fn record(peer: &Peer, owner: &str, response: &Response, stdout: &[u8]) -> Record {
+        let redactor = Redactor::new(&[]).unwrap();
+        let request = Request { owner: owner.to_string(), bash: "true".to_string(), ..Default::default() };
+        let mut capture = Capture::default();
+        capture.feed(stdout);
+        Record::new(Uuid::new_v4(), peer, &request, &redactor).finish(response, Some((capture, Capture::default())), &redactor)
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let ok = Response { status: Some(Termination::Exited), code: Some(0), ..Default::default() };
+        let (unix, tcp) = (Peer::Unix { uid: 0, gid: 0, pid: None }, Peer::Tcp("127.0.0.1:5000".parse().unwrap()));
+        metrics.observe(&record(&unix, "deploy", &ok, b"done\n"), true);
+        metrics.observe(&record(&tcp, "deploy", &ok, b""), true);
+        metrics.observe(&record(&tcp, "ci\"", &Response::error(ErrorKind::Denied, "no"), b""), false);
+        let tls = Peer::Tls { addr: "127.0.0.1:5000".parse().unwrap(), identity: Some("ci\"".to_string()) };
+        metrics.observe(&record(&tls, "root", &Response::error(ErrorKind::Denied, "no"), b""), false);
+        metrics.read_timeout();
+        let text = metrics.render(&Stats { running: 2, queued: 1, ..Default::default() });
+        for line in [
+            "forwarder_requests_total{owner=\"uid 0\",outcome=\"success\"} 1",
+            "forwarder_requests_total{owner=\"deploy\",outcome=\"success\"} 1",
+            "forwarder_requests_total{owner=\"unknown\",outcome=\"denied\"} 1",
+            "forwarder_requests_total{owner=\"ci\\\"\",outcome=\"denied\"} 1",
+            "forwarder_command_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "forwarder_command_duration_seconds_count 2",
+            "forwarder_output_bytes_total{stream=\"stdout\"} 5",
+            "forwarder_active_children 2",
+            "forwarder_queue_depth 1",
+            "forwarder_read_timeouts_total 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing in\n{text}");
+        }
+    }
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            for _ in 0..3 {
+                let (stream, _) = listener.accept().await.unwrap();
+                answer(stream, |path| match path {
+                    "/metrics" => (200, Metrics::default().render(&Stats::default())),
+                    _ => (404, "not found\n".to_string()),
+                })
+                .await
+                .unwrap();
+            }
+        });
+        let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP forwarder_requests_total"));
+        assert!(get(addr, "GET /missing HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404 "));
+        assert!(get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405 "));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_head() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let answered = tokio::spawn(answer(server, |_| (200, String::new())));
+        // one endless line is not buffered whole
+        let line = vec![b'a'; 1024];
+        while client.write_all(&line).await.is_ok() && !answered.is_finished() {}
+        assert_eq!(answered.await.unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
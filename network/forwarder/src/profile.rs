@@ -0,0 +1,413 @@
+//! Execution profiles isolating commands from the forwarder.
+//!
+//! A profile runs the command as another user, with rlimits, in a transient
+//! cgroup v2 and with a clean environment. Profiles are named in the policy
+//! file and picked per owner or per request:
+//!
+//! ```json
+//! "profiles": {
+//!   "sandbox": {
+//!     "user": "nobody",
+//!     "rlimits": { "cpu_secs": 60, "memory": 536870912, "open_files": 256, "processes": 64 },
+//!     "cgroup": { "memory_max": 268435456, "cpu_max": "50000 100000", "pids_max": 32 },
+//!     "clean_env": true,
+//!     "env": { "LANG": "C.UTF-8" },
+//!     "cwd": "/tmp"
+//!   }
+//! }
+//! ```
+//!
+//! Supplementary groups are dropped when switching user.
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rlimits {
+    /// CPU time in seconds, RLIMIT_CPU
+    pub cpu_secs: Option<u64>,
+    /// Address space in bytes, RLIMIT_AS
+    pub memory: Option<u64>,
+    /// RLIMIT_NOFILE
+    pub open_files: Option<u64>,
+    /// Processes of the user, RLIMIT_NPROC
+    pub processes: Option<u64>,
+}
+
+impl Rlimits {
+    fn list(&self) -> Vec<(libc::__rlimit_resource_t, u64)> {
+        [
+            (libc::RLIMIT_CPU, self.cpu_secs),
+            (libc::RLIMIT_AS, self.memory),
+            (libc::RLIMIT_NOFILE, self.open_files),
+            (libc::RLIMIT_NPROC, self.processes),
+        ]
+        .into_iter()
+        .filter_map(|(resource, limit)| Some((resource, limit?)))
+        .collect()
+    }
+}
+
+/// Caps of the transient cgroup, written into its control files
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cgroup {
+    /// `memory.max` in bytes
+    pub memory_max: Option<u64>,
+    /// `cpu.max`, quota and period in microseconds like `50000 100000`
+    pub cpu_max: Option<String>,
+    /// `pids.max`
+    pub pids_max: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// User name or uid to run as
+    pub user: Option<String>,
+    /// Group name or gid, primary group of `user` by default
+    pub group: Option<String>,
+    #[serde(default)]
+    pub rlimits: Rlimits,
+    /// Run in a transient cgroup under the cgroup root of the server
+    pub cgroup: Option<Cgroup>,
+    /// Start from an empty environment instead of the one of the server
+    #[serde(default)]
+    pub clean_env: bool,
+    /// Environment set before the one of the request
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Working directory when the request has none
+    pub cwd: Option<String>,
+}
+
+/// User resolved from the passwd database
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct User {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String,
+}
+
+impl Profile {
+    /// Uid and gid the profile runs as, which own files it writes
+    pub fn owner(&self) -> io::Result<(Option<u32>, Option<u32>)> {
+        let user = self.user.as_deref().map(lookup_user).transpose()?;
+        let gid = match (&self.group, &user) {
+            (Some(group), _) => Some(lookup_group(group)?),
+            (None, user) => user.as_ref().map(|user| user.gid),
+        };
+        Ok((user.map(|user| user.uid), gid))
+    }
+
+    /// Resolve the user and create the cgroup, for request `id`
+    pub fn prepare(&self, id: &str, cgroup_root: &Path) -> io::Result<Sandbox> {
+        let user = self.user.as_deref().map(lookup_user).transpose()?;
+        let gid = match (&self.group, &user) {
+            (Some(group), _) => Some(lookup_group(group)?),
+            (None, Some(user)) => Some(user.gid),
+            (None, None) => None,
+        };
+        let cgroup = match &self.cgroup {
+            Some(caps) => Some(TransientCgroup::create(&cgroup_root.join(id), caps)?),
+            None => None,
+        };
+        Ok(Sandbox {
+            profile: self.clone(),
+            user,
+            gid,
+            cgroup,
+        })
+    }
+}
+
+/// Profile ready to be applied, the cgroup is removed on drop
+#[derive(Debug)]
+pub struct Sandbox {
+    profile: Profile,
+    user: Option<User>,
+    gid: Option<u32>,
+    cgroup: Option<TransientCgroup>,
+}
+
+impl Sandbox {
+    /// Set up `command` to run in the sandbox, `env` and `cwd` of the request
+    /// take precedence over the ones of the profile.
+    pub fn apply(&self, command: &mut Command, env: &HashMap<String, String>, cwd: Option<&str>) {
+        if self.profile.clean_env {
+            command.env_clear();
+            command.env("PATH", DEFAULT_PATH);
+            if let Some(user) = &self.user {
+                command.env("HOME", &user.home).env("USER", &user.name).env("LOGNAME", &user.name);
+            }
+        }
+        command.envs(&self.profile.env).envs(env);
+        let cwd = cwd.or(self.profile.cwd.as_deref());
+        // the directory is entered as the user, which std would do as the server
+        let chdir = match (&self.user, cwd) {
+            (Some(_), Some(cwd)) => CString::new(cwd).ok(),
+            (None, Some(cwd)) => {
+                command.current_dir(cwd);
+                None
+            }
+            (_, None) => None,
+        };
+
+        let rlimits = self.profile.rlimits.list();
+        let procs = self.cgroup.as_ref().map(|c| c.procs.as_raw_fd());
+        let (uid, gid) = (self.user.as_ref().map(|user| user.uid), self.gid);
+        // std switches user before running `pre_exec`, after which the cgroup
+        // and raising rlimits are not allowed, so the user is switched here.
+        // Only async-signal-safe calls between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                let check = |result: libc::c_int| if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) };
+                if let Some(fd) = procs {
+                    // `0` moves the writing process
+                    if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) != 1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                for (resource, limit) in &rlimits {
+                    let rlim = libc::rlimit {
+                        rlim_cur: *limit,
+                        rlim_max: *limit,
+                    };
+                    check(libc::setrlimit(*resource, &rlim))?;
+                }
+                if let Some(gid) = gid {
+                    check(libc::setgid(gid))?;
+                }
+                if let Some(uid) = uid {
+                    check(libc::setgroups(0, std::ptr::null()))?;
+                    check(libc::setuid(uid))?;
+                }
+                if let Some(dir) = &chdir {
+                    check(libc::chdir(dir.as_ptr()))?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// cgroup created for one request
+#[derive(Debug)]
+struct TransientCgroup {
+    path: PathBuf,
+    /// `cgroup.procs` opened before fork
+    procs: OwnedFd,
+}
+
+impl TransientCgroup {
+    fn create(path: &Path, caps: &Cgroup) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+            // controllers may already be enabled, or not be available at all,
+            // then writing the caps below fails
+            let _ = fs::write(parent.join("cgroup.subtree_control"), "+memory +cpu +pids");
+        }
+        fs::create_dir(path)?;
+        let cgroup = TransientCgroup {
+            path: path.to_path_buf(),
+            procs: File::options().write(true).open(path.join("cgroup.procs"))?.into(),
+        };
+        let caps = [
+            ("memory.max", caps.memory_max.map(|m| m.to_string())),
+            ("cpu.max", caps.cpu_max.clone()),
+            ("pids.max", caps.pids_max.map(|p| p.to_string())),
+        ];
+        for (file, value) in caps {
+            if let Some(value) = value {
+                fs::write(path.join(file), value)
+                    .map_err(|e| io::Error::new(e.kind(), format!("Failed to set {file} of {}: {e}", path.display())))?;
+            }
+        }
+        Ok(cgroup)
+    }
+}
+
+impl Drop for TransientCgroup {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        // processes which left the process group of the command are still in the cgroup
+        let _ = fs::write(path.join("cgroup.kill"), "1");
+        // killed processes leave the cgroup after a while, which is waited for
+        // off the async workers
+        let remove = move || {
+            for _ in 0..50 {
+                match fs::remove_dir(&path) {
+                    Err(e) if e.raw_os_error() == Some(libc::EBUSY) => std::thread::sleep(Duration::from_millis(10)),
+                    Err(e) => {
+                        warn!("Failed to remove cgroup {}: {e}", path.display());
+                        return;
+                    }
+                    Ok(()) => return,
+                }
+            }
+            warn!("cgroup {} is still busy", path.display());
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+            Err(_) => remove(),
+        }
+    }
+}
+
+// forwarder is linked statically, where NSS of glibc can not load its modules,
+// so users and groups are read from the files directly.
+const PASSWD: &str = "/etc/passwd";
+const GROUP: &str = "/etc/group";
+
+/// Find the entry of `name`, or of the id if `name` is a number, in passwd or group file
+fn lookup<'a>(content: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    content
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 3 && (fields[0] == name || fields[2] == name))
+}
+
+fn lookup_user(name: &str) -> io::Result<User> {
+    let content = fs::read_to_string(PASSWD)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid entry of user {name} in {PASSWD}"));
+    let Some(fields) = lookup(&content, name).filter(|f| f.len() >= 6) else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No user {name}")));
+    };
+    Ok(User {
+        name: fields[0].to_string(),
+        uid: fields[2].parse().map_err(|_| invalid())?,
+        gid: fields[3].parse().map_err(|_| invalid())?,
+        home: fields[5].to_string(),
+    })
+}
+
+/// Uid of user `name`, which may be a uid already
+pub fn lookup_uid(name: &str) -> io::Result<u32> {
+    match name.parse::<u32>() {
+        Ok(uid) => Ok(uid),
+        Err(_) => Ok(lookup_user(name)?.uid),
+    }
+}
+
+/// Gid of group `name`, which may be a gid already
+pub fn lookup_group(name: &str) -> io::Result<u32> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(gid);
+    }
+    let content = fs::read_to_string(GROUP)?;
+    let Some(fields) = lookup(&content, name) else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No group {name}")));
+    };
+    fields[2]
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid entry of group {name} in {GROUP}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{self, Options, Stdin};
+
+    async fn run(profile: &Profile, cmd: &str) -> String {
+        let sandbox = profile.prepare("test", Path::new("/nonexistent")).unwrap();
+        let mut command = Command::new("bash");
+        command.arg("-c").arg(cmd);
+        sandbox.apply(&mut command, &HashMap::new(), None);
+        let (_, stdout, stderr) = exec::collect(command, Options::new(Stdin::Null), std::future::pending()).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&stderr), "");
+        String::from_utf8(stdout).unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert_eq!(lookup_user("0").unwrap(), root);
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        assert_eq!(lookup_user("no-such-user").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_rlimits_and_env() {
+        let profile = Profile {
+            rlimits: Rlimits {
+                open_files: Some(64),
+                cpu_secs: Some(10),
+                ..Default::default()
+            },
+            clean_env: true,
+            env: HashMap::from([("LANG".to_string(), "C".to_string())]),
+            cwd: Some("/".to_string()),
+            ..Default::default()
+        };
+        let output = run(&profile, "ulimit -n; ulimit -t; pwd; env | grep -v '^_=' | grep -v '^SHLVL=' | grep -v '^PWD=' | sort").await;
+        assert_eq!(output, format!("64\n10\n/\nLANG=C\nPATH={DEFAULT_PATH}\n"));
+    }
+
+    #[tokio::test]
+    async fn test_user() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let profile = Profile {
+            user: Some("nobody".to_string()),
+            rlimits: Rlimits {
+                open_files: Some(64),
+                ..Default::default()
+            },
+            clean_env: true,
+            cwd: Some("/tmp".to_string()),
+            ..Default::default()
+        };
+        let nobody = lookup_user("nobody").unwrap();
+        let output = run(&profile, "id -u; id -g; id -G; echo $USER; ulimit -n; pwd").await;
+        assert_eq!(output, format!("{0}\n{1}\n{1}\nnobody\n64\n/tmp\n", nobody.uid, nobody.gid));
+    }
+
+    #[tokio::test]
+    async fn test_cgroup() {
+        // needs a writable cgroup v2 hierarchy
+        let root = ["/sys/fs/cgroup", "/sys/fs/cgroup/unified"]
+            .iter()
+            .map(Path::new)
+            .find(|p| p.join("cgroup.procs").exists() && unsafe { libc::geteuid() } == 0);
+        let Some(root) = root else {
+            return;
+        };
+        let root = root.join(format!("forwarder-test-{}", std::process::id()));
+        // the process joins the cgroup before it drops privileges
+        let profile = Profile {
+            user: Some("nobody".to_string()),
+            cgroup: Some(Cgroup::default()),
+            ..Default::default()
+        };
+        let sandbox = profile.prepare("job", &root).unwrap();
+        let mut command = Command::new("cat");
+        command.arg("/proc/self/cgroup");
+        sandbox.apply(&mut command, &HashMap::new(), None);
+        let (_, stdout, _) = exec::collect(command, Options::new(Stdin::Null), std::future::pending()).await.unwrap();
+        let name = format!("forwarder-test-{}/job", std::process::id());
+        assert!(String::from_utf8_lossy(&stdout).lines().any(|l| l.starts_with("0::") && l.ends_with(&name)));
+        drop(sandbox);
+        for _ in 0..100 {
+            if !root.join("job").exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!root.join("job").exists());
+        fs::remove_dir(&root).unwrap();
+    }
+}
@@ -0,0 +1,595 @@
+//! Wire protocol between forwarder and its clients.
+//!
+//! A frame is a 4 bytes big-endian length followed by a JSON document of that
+//! length. One connection carries any number of request frames and each of them
+//! is answered by one response frame, in order.
+//!
+//! Clients written before framing send one bare JSON request and read the
+//! response until the connection is closed. Such a connection is told apart by
+//! its first byte `{` or JSON whitespace, which never starts a frame as frames
+//! are smaller than [`MAX_FRAME`].
+//!
+//! A request runs either `argv`, executed directly without a shell, or the
+//! command line `bash` with `bash -c`, which the policy may forbid.
+//!
+//! Output is sent as text when it is UTF-8, in base64 with its [`Encoding`]
+//! otherwise, so binary output reaches framed clients unchanged. Legacy
+//! clients get invalid bytes replaced.
+//!
+//! A framed client closing its connection, or only its write side, while its
+//! request runs is gone and the request is cancelled, unless the request is
+//! `half_close`.
+//!
+//! Input, like output, is text or base64. A streaming request with `stdin`
+//! is followed by [`SessionFrame`]s of its input up to `eof`, which the
+//! client sends even when the command exits first, so that the connection
+//! can carry the next request. The server skips input left after `exit`.
+//!
+//! A request with `tty` starts an interactive session on a framed connection:
+//! the client sends [`SessionFrame`]s while the command runs in a terminal and
+//! is answered like a streaming request, with `stdout` frames of the terminal
+//! output and then `exit`.
+//!
+//! A request with `put` or `get` transfers a file on a framed connection
+//! instead of running a command, see [`crate::transfer`].
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::BytesMut;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::auth::ErrorKind;
+use crate::exec::Termination;
+use crate::limit::Stats;
+use crate::pty::Tty;
+use crate::transfer::{FileInfo, Get, Put};
+
+/// Protocol version spoken by this build
+pub const VERSION: u32 = 1;
+/// Upper bound of a frame or a legacy request
+pub const MAX_FRAME: usize = 64 << 20;
+/// Upper bound of a request of a peer which is not authenticated yet, like TCP
+/// peers before their token is checked
+pub const MAX_ANONYMOUS_FRAME: usize = 1 << 20;
+/// Upper bound of the output of one response, JSON escapes a byte into up to
+/// 6 bytes and the response has to fit in one frame
+pub const MAX_OUTPUT: usize = MAX_FRAME / 8;
+/// Unix socket forwarder listens on
+pub const SOCK: &str = "/var/run/forwarder/forwarder.sock";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Request {
+    /// Protocol version of the client, 0 for legacy clients
+    #[serde(default)]
+    pub version: u32,
+    /// Id used to cancel the request, generated by server when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Cancel the running request of this id instead of running a command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancel: Option<String>,
+    /// Run the command as a job, answered with its id at once
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub submit: bool,
+    /// Get [`JobInfo`] of the job of this id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Get the response of the finished job of this id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+    /// List jobs of the owner
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub list: bool,
+    /// Get [`Stats`] of running and queued commands
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stats: bool,
+    /// Command line run by `bash -c`, when `argv` is empty
+    #[serde(default)]
+    pub bash: String,
+    /// Program and its arguments, executed without shell
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub argv: Vec<String>,
+    pub input: Option<String>,
+    /// Encoding of `input` when it is not text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_encoding: Option<Encoding>,
+    /// Input is sent as [`SessionFrame`]s after the request, up to `eof`,
+    /// streaming requests only
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stdin: bool,
+    pub owner: String,
+    /// Authenticates the owner on TCP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Working directory of the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Environment variables set for the command
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Answer with [`StreamFrame`]s as output arrives, framed connections only
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    /// Client may close its write side after the request on a framed
+    /// connection, which otherwise cancels the request as the client is gone
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub half_close: bool,
+    /// Cap of stdout and stderr in bytes, lowered to the cap of the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output: Option<usize>,
+    /// Execution profile of the policy to run with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Kill the command after running this long
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Run the command in a terminal as an interactive session, framed connections only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tty: Option<Tty>,
+    /// Write a file instead of running a command, framed connections only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub put: Option<Put>,
+    /// Read a file instead of running a command, framed connections only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub get: Option<Get>,
+}
+
+impl Request {
+    /// Whether the command runs with `bash -c`
+    pub fn shell(&self) -> bool {
+        self.argv.is_empty() && self.transfer().is_none()
+    }
+
+    /// `input` as the bytes the command reads
+    pub fn input_bytes(&self) -> io::Result<Option<Vec<u8>>> {
+        self.input.as_deref().map(|input| decode(input, self.input_encoding)).transpose()
+    }
+
+    /// `put` or `get` and the path of a file transfer
+    pub fn transfer(&self) -> Option<(&'static str, &str)> {
+        match (&self.put, &self.get) {
+            (Some(put), _) => Some(("put", &put.path)),
+            (None, Some(get)) => Some(("get", &get.path)),
+            (None, None) => None,
+        }
+    }
+
+    /// Command of the request for logs and records, `argv` quoted like a shell would need it
+    pub fn command_line(&self) -> String {
+        if let Some((op, path)) = self.transfer() {
+            return format!("{op} {}", shell_quote(&[path]));
+        }
+        match self.shell() {
+            true => self.bash.clone(),
+            false => shell_quote(&self.argv),
+        }
+    }
+}
+
+/// Quote each of `args` as needed and join them into a command line
+pub fn shell_quote<S: AsRef<str>>(args: &[S]) -> String {
+    let quote = |arg: &str| {
+        if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c)) {
+            arg.to_string()
+        } else {
+            format!("'{}'", arg.replace('\'', r"'\''"))
+        }
+    };
+    args.iter().map(|arg| quote(arg.as_ref())).collect::<Vec<_>>().join(" ")
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Response {
+    /// Protocol version of the server, not sent to legacy clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Id of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub output: String,
+    pub error: String,
+    /// Encoding of `output` and `error`, text when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<Encoding>,
+    /// Exit code, `None` when the command was killed by a signal
+    pub code: Option<i32>,
+    /// How the command ended, `None` when it did not run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Termination>,
+    /// Signal which killed the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    /// Set when the request failed before running the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ErrorKind>,
+    /// Output exceeded the cap and was partly dropped
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Job submitted or asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<Box<JobInfo>>,
+    /// Jobs listed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<JobInfo>,
+    /// Stats asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Box<Stats>>,
+    /// File got, or written once a put completed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Finished,
+    /// Server restarted while the job was running
+    Lost,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub owner: String,
+    /// Uid of the Unix peer which submitted the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    pub bash: String,
+    pub state: JobState,
+    /// Unix time in seconds
+    pub submitted: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished: Option<u64>,
+    /// How the command ended, once finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Termination>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<i32>,
+}
+
+impl Response {
+    pub fn error(kind: ErrorKind, error: impl ToString) -> Self {
+        Response {
+            error: error.to_string(),
+            kind: Some(kind),
+            ..Default::default()
+        }
+    }
+
+    /// Set the output of a command for a client of `version`, in base64 when
+    /// either stream is not UTF-8 and the client is not a legacy one
+    pub fn set_output(&mut self, stdout: Vec<u8>, stderr: Vec<u8>, version: u32) {
+        let text = std::str::from_utf8(&stdout).is_ok() && std::str::from_utf8(&stderr).is_ok();
+        if text || version == 0 {
+            self.output = String::from_utf8_lossy(&stdout).into_owned();
+            self.error = String::from_utf8_lossy(&stderr).into_owned();
+        } else {
+            (self.output, self.error) = (BASE64.encode(stdout), BASE64.encode(stderr));
+            self.encoding = Some(Encoding::Base64);
+        }
+    }
+
+    /// stdout and stderr of the command as it wrote them
+    pub fn output_bytes(&self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        Ok((decode(&self.output, self.encoding)?, decode(&self.error, self.encoding)?))
+    }
+    /// How the command ended and the size of its output, which is left out
+    /// as it may hold secrets, for logs
+    pub fn summary(&self) -> String {
+        let mut summary = format!("status {:?} code {:?} signal {:?}", self.status, self.code, self.signal);
+        if let Some(kind) = self.kind {
+            summary += &format!(", {kind:?}");
+        }
+        summary + &format!(", {} bytes of output and {} of error", self.output.len(), self.error.len())
+    }
+}
+
+/// Encoding of output which is not UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Base64,
+}
+
+/// `data` as text if it is UTF-8, in base64 otherwise
+pub fn encode(data: &[u8]) -> (String, Option<Encoding>) {
+    match std::str::from_utf8(data) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (BASE64.encode(data), Some(Encoding::Base64)),
+    }
+}
+
+/// Bytes of `data` sent with `encoding`
+pub fn decode(data: &str, encoding: Option<Encoding>) -> io::Result<Vec<u8>> {
+    match encoding {
+        None => Ok(data.as_bytes().to_vec()),
+        Some(Encoding::Base64) => BASE64.decode(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Frames answering a streaming request, `Started` once the request is
+/// accepted, output chunks and then one `Exit` whose `output` and `error` are empty
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamFrame {
+    /// Id of the request, to cancel it from another connection
+    Started { id: String },
+    Stdout {
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+    },
+    Stderr {
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+    },
+    Exit(Response),
+}
+
+/// Frames of the client while its session, or streaming request with
+/// `stdin`, runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionFrame {
+    /// Typed on the terminal, or read from stdin
+    Input {
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+    },
+    /// Window of the terminal is resized
+    Resize { rows: u16, cols: u16 },
+    /// No more input, the terminal is hung up or stdin is closed
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Length-prefixed frames
+    Framed,
+    /// One bare JSON request per connection
+    Legacy,
+}
+
+/// Peek at the first byte to find the protocol of a connection, `None` if it
+/// is closed before sending anything.
+pub async fn detect<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Mode>> {
+    let buf = reader.fill_buf().await?;
+    Ok(match buf.first() {
+        None => None,
+        Some(b'{' | b' ' | b'\t' | b'\n' | b'\r') => Some(Mode::Legacy),
+        Some(_) => Some(Mode::Framed),
+    })
+}
+
+/// Read one frame of up to `max` bytes, `None` if connection is closed between frames.
+/// The buffer grows as data arrives, a large length alone does not allocate.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    if reader.read(&mut len[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..]).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame of {len} bytes is too large")));
+    }
+    let mut data = Vec::with_capacity(len.min(8192));
+    if reader.take(len as u64).read_to_end(&mut data).await? < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(Some(data))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Frame of {} bytes is too large", data.len())));
+    }
+    writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    writer.write_all(data).await?;
+    writer.flush().await
+}
+
+/// Read a bare JSON document of up to `max` bytes, until it is complete or
+/// connection is closed.
+pub async fn read_legacy<R: AsyncRead + Unpin>(reader: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let mut data = BytesMut::with_capacity(4096);
+    let mut scanner = Scanner::default();
+    loop {
+        let start = data.len();
+        if reader.read_buf(&mut data).await? == 0 {
+            break;
+        }
+        // only new bytes are scanned, the document is parsed once it is complete
+        if scanner.scan(&data[start..]) {
+            break;
+        }
+        if data.len() > max {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Request is too large"));
+        }
+        data.reserve(4096);
+    }
+    Ok(data.to_vec())
+}
+
+/// Finds the end of a JSON document by nesting of brackets outside of strings
+#[derive(Default)]
+struct Scanner {
+    depth: usize,
+    string: bool,
+    escape: bool,
+}
+
+impl Scanner {
+    /// Scan the next bytes of the document, tells whether it is complete
+    fn scan(&mut self, data: &[u8]) -> bool {
+        for &byte in data {
+            if self.string {
+                match byte {
+                    _ if self.escape => self.escape = false,
+                    b'\\' => self.escape = true,
+                    b'"' => self.string = false,
+                    _ => (),
+                }
+                if !self.string && self.depth == 0 {
+                    return true;
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    // unbalanced, left to the parser to reject
+                    if self.depth <= 1 {
+                        return true;
+                    }
+                    self.depth -= 1;
+                }
+                _ => (),
+            }
+        }
+        false
+    }
+}
+
+/// Serialize `message` into one frame
+pub async fn send<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    write_frame(writer, &serde_json::to_vec(message)?).await?;
+    Ok(())
+}
+
+/// Read one frame as `T`, `None` if connection is closed
+pub async fn recv<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    match read_frame(reader, MAX_FRAME).await? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_frames() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = BufReader::new(server);
+        let writer = tokio::spawn(async move {
+            for bash in ["echo 1", "echo 2"] {
+                let request = Request { version: VERSION, bash: bash.to_string(), ..Default::default() };
+                send(&mut client, &request).await.unwrap();
+            }
+        });
+
+        assert_eq!(detect(&mut server).await.unwrap(), Some(Mode::Framed));
+        let first: Request = recv(&mut server).await.unwrap().unwrap();
+        let second: Request = recv(&mut server).await.unwrap().unwrap();
+        assert_eq!((first.bash.as_str(), second.bash.as_str()), ("echo 1", "echo 2"));
+        writer.await.unwrap();
+        assert!(recv::<_, Request>(&mut server).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_buffer_size() {
+        // request of exactly 4096 bytes used to wait for read timeout
+        let mut request = r#"{"bash":"echo ","owner":"test","input":""}"#.to_string();
+        request.insert_str(12, &"x".repeat(4096 - request.len()));
+        assert_eq!(request.len(), 4096);
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = BufReader::new(server);
+        let data = request.clone();
+        tokio::spawn(async move {
+            client.write_all(data.as_bytes()).await.unwrap();
+            // keep the connection open
+            std::future::pending::<()>().await;
+        });
+        assert_eq!(detect(&mut server).await.unwrap(), Some(Mode::Legacy));
+        let data = tokio::time::timeout(std::time::Duration::from_secs(1), read_legacy(&mut server, MAX_FRAME))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, request.as_bytes());
+        let request: Request = serde_json::from_slice(&data).unwrap();
+        assert_eq!(request.version, 0);
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_large() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&(MAX_FRAME as u32 + 1).to_be_bytes()).await.unwrap();
+        let e = read_frame(&mut server, MAX_FRAME).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&(MAX_ANONYMOUS_FRAME as u32 + 1).to_be_bytes()).await.unwrap();
+        let e = read_frame(&mut server, MAX_ANONYMOUS_FRAME).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_output() {
+        let mut response = Response::default();
+        response.set_output(b"text".to_vec(), vec![0xff], 0);
+        assert_eq!((response.output.as_str(), response.error.as_str(), response.encoding), ("text", "\u{fffd}", None));
+
+        let mut response = Response::default();
+        response.set_output(b"text".to_vec(), vec![0xff], VERSION);
+        assert_eq!(response.encoding, Some(Encoding::Base64));
+        assert_eq!(response.output_bytes().unwrap(), (b"text".to_vec(), vec![0xff]));
+
+        // output escaped the most still fits in a frame
+        let mut response = Response::default();
+        response.set_output(vec![1; MAX_OUTPUT], vec![], VERSION);
+        assert!(serde_json::to_vec(&response).unwrap().len() < MAX_FRAME);
+    }
+
+    #[tokio::test]
+    async fn test_frame_cut() {
+        // closed within the length prefix
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0, 0]).await.unwrap();
+        drop(client);
+        let e = read_frame(&mut server, MAX_FRAME).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        // closed within the data
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0, 0, 0, 10, b'{']).await.unwrap();
+        drop(client);
+        let e = read_frame(&mut server, MAX_FRAME).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_legacy() {
+        let request = " \n{\"bash\":\"echo '}' \\\"{\\\"\",\"owner\":\"test\",\"env\":{\"A\":\"]\"}}";
+        let (mut client, server) = tokio::io::duplex(8);
+        let mut server = BufReader::new(server);
+        tokio::spawn(async move {
+            client.write_all(request.as_bytes()).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        assert_eq!(detect(&mut server).await.unwrap(), Some(Mode::Legacy));
+        let data = read_legacy(&mut server, MAX_FRAME).await.unwrap();
+        assert_eq!(data, request.as_bytes());
+        let request: Request = serde_json::from_slice(&data).unwrap();
+        assert_eq!(request.bash, "echo '}' \"{\"");
+        assert_eq!(request.env["A"], "]");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote(&["ls | wc -l"]), "'ls | wc -l'");
+        let request = Request { argv: vec!["echo".to_string(), "it's".to_string()], ..Default::default() };
+        assert_eq!(request.command_line(), r"echo 'it'\''s'");
+    }
+}
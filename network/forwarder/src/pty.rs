@@ -0,0 +1,111 @@
+//! Pseudo-terminals of interactive sessions.
+//!
+//! The command of a session gets the terminal side as stdin, stdout and stderr
+//! and as its controlling terminal, forwarder reads and writes the master
+//! side. See [`crate::exec::session`].
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+/// Terminal asked for by a session request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tty {
+    pub rows: u16,
+    pub cols: u16,
+    /// `$TERM` of the command, `xterm` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub term: Option<String>,
+}
+
+impl Tty {
+    /// `$TERM` of the command, a name of the terminfo database
+    pub fn term(&self) -> Result<&str, String> {
+        let term = self.term.as_deref().unwrap_or("xterm");
+        if term.is_empty() || term.len() > 64 || !term.chars().all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c)) {
+            return Err(format!("Invalid terminal type `{term}`"));
+        }
+        Ok(term)
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+/// Open a terminal of `rows` and `cols`, as its master and its terminal side
+pub fn open(rows: u16, cols: u16) -> io::Result<(OwnedFd, OwnedFd)> {
+    unsafe {
+        let master = OwnedFd::from_raw_fd(check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC))?);
+        check(libc::grantpt(master.as_raw_fd()))?;
+        check(libc::unlockpt(master.as_raw_fd()))?;
+        let mut name = [0 as libc::c_char; 128];
+        match libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) {
+            0 => (),
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+        let path = CStr::from_ptr(name.as_ptr());
+        let terminal = OwnedFd::from_raw_fd(check(libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC))?);
+        resize(terminal.as_raw_fd(), rows, cols)?;
+        Ok((master, terminal))
+    }
+}
+
+/// Set the window size of the terminal of `fd`, its foreground process group gets SIGWINCH
+pub fn resize(fd: RawFd, rows: u16, cols: u16) -> io::Result<()> {
+    let size = libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 };
+    check(unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &size) })?;
+    Ok(())
+}
+
+/// Window size of the terminal of `fd`, as rows and columns
+pub fn size(fd: RawFd) -> io::Result<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) })?;
+    Ok((size.ws_row, size.ws_col))
+}
+
+/// Make the terminal inherited as stdin the controlling terminal of a new
+/// session, called in the child between fork and exec
+pub fn attach() -> io::Result<()> {
+    unsafe {
+        check(libc::setsid())?;
+        check(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+    }
+    Ok(())
+}
+
+pub fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = check(libc::fcntl(fd, libc::F_GETFL))?;
+        check(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+    }
+    Ok(())
+}
+
+/// Local terminal switched to raw mode, restored on drop
+pub struct RawMode {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+impl RawMode {
+    /// Pass keys of the terminal `fd` as they are typed, without echo or signals
+    pub fn enable(fd: RawFd) -> io::Result<Self> {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        check(unsafe { libc::tcgetattr(fd, &mut termios) })?;
+        let saved = termios;
+        unsafe { libc::cfmakeraw(&mut termios) };
+        check(unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) })?;
+        Ok(RawMode { fd, saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved) };
+    }
+}
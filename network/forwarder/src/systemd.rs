@@ -0,0 +1,90 @@
+//! Integration with systemd: socket activation, readiness and watchdog.
+//!
+//! Sockets passed by a `.socket` unit are served instead of binding ones,
+//! a Unix stream socket as the local socket and a TCP one as the TCP
+//! listener. Notifications are sent to `$NOTIFY_SOCKET` and are no-ops when
+//! the server does not run under systemd, so units may use `Type=notify` or
+//! `Type=notify-reload` with `WatchdogSec=`.
+use log::{info, warn};
+use sd_notify::NotifyState;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+
+/// Sockets passed by systemd
+#[derive(Debug, Default)]
+pub struct Activated {
+    pub unix: Option<UnixListener>,
+    pub tcp: Option<TcpListener>,
+}
+
+/// Take sockets passed with `LISTEN_FDS`, must be called before other threads are started
+pub fn listen_fds() -> io::Result<Activated> {
+    let mut activated = Activated::default();
+    for fd in sd_notify::listen_fds()? {
+        match family(fd)? {
+            libc::AF_UNIX if activated.unix.is_none() => {
+                let listener = unsafe { UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                activated.unix = Some(listener);
+            }
+            libc::AF_INET | libc::AF_INET6 if activated.tcp.is_none() => {
+                let listener = unsafe { TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                activated.tcp = Some(listener);
+            }
+            family => warn!("Ignore socket {fd} of family {family} passed by systemd"),
+        }
+    }
+    if activated.unix.is_some() || activated.tcp.is_some() {
+        info!("Serve sockets passed by systemd: {activated:?}");
+    }
+    Ok(activated)
+}
+
+fn family(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int)
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("Failed to notify systemd of {state:?}: {e}");
+    }
+}
+
+pub fn ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+/// Tell that the configuration is being reloaded, [`ready`] tells it is done
+pub fn reloading() {
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(e) => warn!("Failed to read monotonic clock: {e}"),
+    }
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+pub fn status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+/// Interval to ping the watchdog at, half its timeout, if systemd expects pings
+pub fn watchdog() -> Option<Duration> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(true, &mut usec).then(|| Duration::from_micros(usec / 2))
+}
+
+pub fn ping() {
+    notify(&[NotifyState::Watchdog]);
+}
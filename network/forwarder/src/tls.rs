@@ -0,0 +1,220 @@
+//! TLS of the TCP listener.
+//!
+//! The server certificate chain and key are read from PEM files. With a CA
+//! bundle, clients must present a certificate issued by it, and the identity
+//! of the certificate, its CN or else its first SAN, is the owner of their
+//! requests. [`Tls::reload`] reads the files again, connections accepted
+//! afterwards use the new certificates.
+use anyhow::{Result, anyhow};
+use log::info;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    /// Certificate chain of the server
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle which client certificates must be issued by, clients are not
+    /// asked for certificates without it
+    pub client_ca: Option<PathBuf>,
+}
+
+pub struct Tls {
+    files: TlsFiles,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).map_err(|e| anyhow!("Failed to open {}: {e}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).map_err(|e| anyhow!("Failed to open {}: {e}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?.ok_or_else(|| anyhow!("No private key in {}", path.display()))
+}
+
+fn load_roots(path: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn server_config(files: &TlsFiles) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match &files.client_ca {
+        Some(ca) => builder.with_client_cert_verifier(WebPkiClientVerifier::builder_with_provider(load_roots(ca)?, provider()).build()?),
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_single_cert(load_certs(&files.cert)?, load_key(&files.key)?)?)
+}
+
+impl Tls {
+    pub fn load(files: TlsFiles) -> Result<Self> {
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(&files)?));
+        Ok(Tls { files, acceptor: RwLock::new(acceptor) })
+    }
+
+    /// Read the certificates again, the current ones are kept if that fails
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(&self.files)?));
+        *self.acceptor.write().unwrap() = acceptor;
+        info!("Reloaded TLS certificate {}", self.files.cert.display());
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+}
+
+/// Owner named by a client certificate, its CN or else its first DNS, email or URI SAN
+pub fn identity(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    if let Some(cn) = cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()) {
+        return Some(cn.to_string());
+    }
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => Some(name.to_string()),
+        _ => None,
+    })
+}
+
+/// Connector of clients trusting `ca`, presenting the certificate `cert` and `key` if given
+pub fn connector(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<TlsConnector> {
+    let verifier = WebPkiServerVerifier::builder_with_provider(load_roots(ca)?, provider()).build()?;
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_webpki_verifier(verifier);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::fs;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    struct Pki {
+        dir: PathBuf,
+    }
+
+    impl Pki {
+        /// CA `name` with a server certificate for localhost and a client certificate of `deploy`
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("forwarder-tls-{name}-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let ca_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+            let ca = params.self_signed(&ca_key).unwrap();
+            fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+            for (file, cn, purpose) in [("server", "localhost", ExtendedKeyUsagePurpose::ServerAuth), ("client", "deploy", ExtendedKeyUsagePurpose::ClientAuth)] {
+                let key = KeyPair::generate().unwrap();
+                let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+                params.distinguished_name.push(DnType::CommonName, cn);
+                params.extended_key_usages = vec![purpose];
+                let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+                fs::write(dir.join(format!("{file}.pem")), cert.pem()).unwrap();
+                fs::write(dir.join(format!("{file}.key")), key.serialize_pem()).unwrap();
+            }
+            Pki { dir }
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Handshake a client with `tls`, return the identity seen by the server
+    async fn handshake(tls: &Tls, connector: TlsConnector) -> Result<Option<String>> {
+        let (client, server) = tokio::io::duplex(16384);
+        let acceptor = tls.acceptor();
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await?;
+            let identity = stream.get_ref().1.peer_certificates().and_then(|certs| identity(&certs[0]));
+            stream.write_all(b"ok").await?;
+            stream.shutdown().await?;
+            anyhow::Ok(identity)
+        });
+        let mut stream = connector.connect(ServerName::try_from("localhost")?, client).await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        assert_eq!(reply, "ok");
+        server.await?
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let pki = Pki::new("ca");
+        let files = TlsFiles { cert: pki.path("server.pem"), key: pki.path("server.key"), client_ca: Some(pki.path("ca.pem")) };
+        let tls = Tls::load(files).unwrap();
+
+        let client = connector(&pki.path("ca.pem"), Some((&pki.path("client.pem"), &pki.path("client.key")))).unwrap();
+        assert_eq!(handshake(&tls, client).await.unwrap(), Some("deploy".to_string()));
+        // a certificate is required
+        let anonymous = connector(&pki.path("ca.pem"), None).unwrap();
+        assert!(handshake(&tls, anonymous).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let pki = Pki::new("old");
+        let files = TlsFiles { cert: pki.path("server.pem"), key: pki.path("server.key"), client_ca: None };
+        let tls = Tls::load(files).unwrap();
+        let old_client = connector(&pki.path("ca.pem"), None).unwrap();
+        assert_eq!(handshake(&tls, old_client.clone()).await.unwrap(), None);
+
+        // certificates are replaced in place by a new CA
+        let new = Pki::new("new");
+        for file in ["ca.pem", "server.pem", "server.key"] {
+            fs::copy(new.path(file), pki.path(file)).unwrap();
+        }
+        // a broken file keeps the old certificate
+        fs::write(pki.path("server.key"), "").unwrap();
+        assert!(tls.reload().is_err());
+        assert!(handshake(&tls, old_client.clone()).await.is_ok());
+        fs::copy(new.path("server.key"), pki.path("server.key")).unwrap();
+        tls.reload().unwrap();
+        assert!(handshake(&tls, old_client).await.is_err());
+        assert!(handshake(&tls, connector(&pki.path("ca.pem"), None).unwrap()).await.is_ok());
+    }
+}
@@ -0,0 +1,419 @@
+//! File transfers of `put` and `get` requests.
+//!
+//! The data of a file follows its request on the same framed connection, in
+//! raw frames of at most [`CHUNK`] bytes, then one frame with its SHA-256 in
+//! hex, which is computed as the data is sent:
+//!
+//! - a request with [`Put`] is answered with an empty response once the
+//!   server is ready to receive, or with an error. The client then sends
+//!   `size` bytes of data frames and the checksum, and is answered with the
+//!   [`FileInfo`] of the written file.
+//! - a request with [`Get`] is answered with the [`FileInfo`] of the file,
+//!   followed by `size` bytes of data frames and the checksum.
+//!
+//! Paths are absolute, a rule allows them by its `paths`, which are matched as
+//! given and with symlinks resolved. Files are then opened at the resolved
+//! path without following symlinks, so that one swapped in after the check
+//! fails the transfer instead of redirecting it. A put file is written next
+//! to its path and renamed over it once complete, unless `in_place` is asked
+//! for. It keeps the mode and owner of the file it replaces unless the request
+//! gives others, setuid, setgid and sticky bits are never set. A new file is
+//! owned by the peer, the owner of a profile with a user takes precedence, and
+//! owners are only changed where the server is permitted to.
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt::Write as _;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::auth::ErrorKind;
+use crate::protocol;
+
+/// Upper bound of the data in one frame
+pub const CHUNK: usize = 256 << 10;
+/// Permission bits a put file may get, without setuid, setgid and sticky bits
+const MODE_BITS: u32 = 0o777;
+
+/// File written by a request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Put {
+    pub path: String,
+    pub size: u64,
+    /// Permission bits, the ones of the replaced file or 0o644 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// User name or uid to own the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Group name or gid to own the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Write into the file instead of replacing it at once, readers may see it partly written
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub in_place: bool,
+}
+
+/// File read by a request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Get {
+    pub path: String,
+}
+
+/// File sent or written
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub size: u64,
+    /// SHA-256 of the data in hex, a file to be sent gets it after its data
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sha256: String,
+    /// Permission bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Owner of a written file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Uid and gid of a file which did not exist, a replaced one keeps its owner
+    pub created: (Option<u32>, Option<u32>),
+    /// Fail the put when the owner cannot be set, it is skipped where not permitted otherwise
+    pub required: bool,
+}
+
+/// Why `path` cannot be transferred
+pub fn check_path(path: &str) -> Result<(), String> {
+    if !path.starts_with('/') || path.ends_with('/') || path.split('/').any(|c| c == "." || c == "..") {
+        return Err(format!("Path `{path}` is not absolute and normalized"));
+    }
+    Ok(())
+}
+
+/// `path` with symlinks resolved, but the file itself when it is written as it
+/// is replaced rather than followed
+pub async fn resolve(path: impl AsRef<Path>, write: bool) -> io::Result<PathBuf> {
+    let path = path.as_ref();
+    if !write {
+        return fs::canonicalize(path).await;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => Ok(fs::canonicalize(dir).await?.join(name)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} names no file", path.display()))),
+    }
+}
+
+fn hex(hash: &[u8]) -> String {
+    let mut hex = String::with_capacity(hash.len() * 2);
+    for byte in hash {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+/// Open `path` from `dir` with `flags`, failing on any symlink on the way
+fn openat2(dir: RawFd, path: &Path, flags: libc::c_int, mode: u32, resolve: u64) -> io::Result<std::fs::File> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // open_how is non-exhaustive
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.mode = mode as u64;
+    how.resolve = resolve | libc::RESOLVE_NO_SYMLINKS;
+    let fd = unsafe { libc::syscall(libc::SYS_openat2, dir, path.as_ptr(), &how, std::mem::size_of::<libc::open_how>()) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { std::fs::File::from_raw_fd(fd as RawFd) })
+}
+
+/// Open the regular file at `path`, which must not go through symlinks, to be
+/// sent with its info
+pub async fn open(path: impl AsRef<Path>) -> io::Result<(File, FileInfo)> {
+    let path = path.as_ref();
+    // opening a FIFO would wait for a writer
+    let file = openat2(libc::AT_FDCWD, path, libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOCTTY, 0, 0)?;
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a regular file", path.display())));
+    }
+    let info = FileInfo { size: meta.len(), sha256: String::new(), mode: meta.mode() & 0o7777, uid: meta.uid(), gid: meta.gid() };
+    Ok((File::from_std(file), info))
+}
+
+/// Send `size` bytes of `file` in data frames and their checksum, return it
+pub async fn send<W: AsyncWrite + Unpin>(file: File, size: u64, writer: &mut W) -> io::Result<String> {
+    let (mut file, mut buf, mut sent) = (file.take(size), vec![0; CHUNK], 0);
+    let mut hasher = Sha256::new();
+    loop {
+        match file.read(&mut buf).await? {
+            0 => break,
+            n => {
+                hasher.update(&buf[..n]);
+                protocol::write_frame(writer, &buf[..n]).await?;
+                sent += n as u64;
+            }
+        }
+    }
+    if sent < size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("File shrank to {sent} of {size} bytes while it was sent")));
+    }
+    let sha256 = hex(&hasher.finalize());
+    protocol::write_frame(writer, sha256.as_bytes()).await?;
+    Ok(sha256)
+}
+
+/// Directory of a written file, opened without following symlinks so that
+/// the file is created where its path was checked. A temporary file left in
+/// it is removed on drop.
+struct Place {
+    dir: std::fs::File,
+    name: OsString,
+    /// Regular file at the path before it was written
+    existing: Option<std::fs::Metadata>,
+    temp: Option<OsString>,
+}
+
+impl Place {
+    fn new(path: &Path) -> io::Result<Place> {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} names no file", path.display())));
+        };
+        let dir = openat2(libc::AT_FDCWD, dir, libc::O_PATH | libc::O_DIRECTORY, 0, 0)?;
+        let mut place = Place { dir, name: name.to_os_string(), existing: None, temp: None };
+        place.existing = place.open(name, libc::O_PATH, 0).and_then(|file| file.metadata()).ok().filter(|meta| meta.is_file());
+        Ok(place)
+    }
+
+    fn open(&self, name: &OsStr, flags: libc::c_int, mode: u32) -> io::Result<std::fs::File> {
+        openat2(self.dir.as_raw_fd(), Path::new(name), flags, mode, libc::RESOLVE_BENEATH)
+    }
+
+    /// Move the temporary file over the path
+    fn rename(&mut self) -> io::Result<()> {
+        let Some(temp) = &self.temp else {
+            return Ok(());
+        };
+        let (from, to) = (CString::new(temp.as_bytes())?, CString::new(self.name.as_bytes())?);
+        let dir = self.dir.as_raw_fd();
+        if unsafe { libc::renameat(dir, from.as_ptr(), dir, to.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.temp = None;
+        Ok(())
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        if let Some(temp) = &self.temp
+            && let Ok(temp) = CString::new(temp.as_bytes())
+        {
+            unsafe { libc::unlinkat(self.dir.as_raw_fd(), temp.as_ptr(), 0) };
+        }
+    }
+}
+
+/// Read the data frames of `put` and their checksum from `reader` into the
+/// file at `path`, which must not go through symlinks but for its last part.
+/// Errors of the connection are returned as such, the data is still read
+/// when the file fails so that the connection can answer the failure.
+pub async fn receive<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    put: &Put,
+    path: &Path,
+    owner: Owner,
+) -> io::Result<Result<FileInfo, (ErrorKind, String)>> {
+    let mut target = create(put, path);
+    let (mut hasher, mut received) = (Sha256::new(), 0);
+    while received < put.size {
+        let Some(data) = protocol::read_frame(reader, protocol::MAX_FRAME).await? else {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Connection closed after {received} of {} bytes", put.size)));
+        };
+        received += data.len() as u64;
+        if received > put.size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Got more than {} bytes", put.size)));
+        }
+        hasher.update(&data);
+        if let Ok((file, _)) = &mut target
+            && let Err(e) = file.write_all(&data).await
+        {
+            target = Err((ErrorKind::Internal, format!("Failed to write {}: {e}", path.display())));
+        }
+    }
+    let Some(checksum) = protocol::read_frame(reader, 64).await? else {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the checksum"));
+    };
+    let checksum = String::from_utf8_lossy(&checksum);
+    let sha256 = hex(&hasher.finalize());
+    if !sha256.eq_ignore_ascii_case(&checksum) {
+        return Ok(Err((ErrorKind::Invalid, format!("Checksum of data is {sha256} instead of {checksum}"))));
+    }
+    Ok(match target {
+        Ok((file, place)) => install(file, place, put, path, owner, sha256).await,
+        Err(e) => Err(e),
+    })
+}
+
+fn create(put: &Put, path: &Path) -> Result<(File, Place), (ErrorKind, String)> {
+    let failed = |e: io::Error| (ErrorKind::Internal, format!("Failed to create {}: {e}", path.display()));
+    let mut place = Place::new(path).map_err(failed)?;
+    let file = match put.in_place {
+        true => place.open(&place.name, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC, 0o600),
+        false => {
+            let temp = OsString::from(format!(".{}.{}.part", place.name.to_string_lossy(), Uuid::new_v4().simple()));
+            let file = place.open(&temp, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL, 0o600);
+            place.temp = Some(temp);
+            file
+        }
+    };
+    match file {
+        Ok(file) => Ok((File::from_std(file), place)),
+        Err(e) => {
+            place.temp = None;
+            Err(failed(e))
+        }
+    }
+}
+
+/// Give the complete and checked data its mode and owner and move it to `path`
+async fn install(file: File, mut place: Place, put: &Put, path: &Path, owner: Owner, sha256: String) -> Result<FileInfo, (ErrorKind, String)> {
+    let failed = |e: io::Error| (ErrorKind::Internal, format!("Failed to write {}: {e}", path.display()));
+    let existing = place.existing.take();
+    let (uid, gid) = match &existing {
+        Some(meta) => (owner.uid.or(Some(meta.uid())), owner.gid.or(Some(meta.gid()))),
+        None => (owner.uid.or(owner.created.0), owner.gid.or(owner.created.1)),
+    };
+    if uid.is_some() || gid.is_some() {
+        match std::os::unix::fs::fchown(&file, uid, gid) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied && !owner.required => {
+                warn!("Keep owner of {}, not permitted to give it to {uid:?}:{gid:?}", path.display());
+            }
+            Err(e) => return Err(failed(e)),
+        }
+    }
+    // after chown, which clears setuid and setgid bits
+    let mode = put.mode.or(existing.as_ref().map(|meta| meta.mode())).unwrap_or(0o644) & MODE_BITS;
+    file.set_permissions(std::fs::Permissions::from_mode(mode)).await.map_err(failed)?;
+    file.sync_all().await.map_err(failed)?;
+    place.rename().map_err(failed)?;
+    let meta = file.metadata().await.map_err(failed)?;
+    Ok(FileInfo { size: put.size, sha256, mode, uid: meta.uid(), gid: meta.gid() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(path: &Path, data: &[u8]) -> Put {
+        Put { path: path.display().to_string(), size: data.len() as u64, ..Default::default() }
+    }
+
+    /// Put `data` with the checksum of `sent`
+    async fn transfer(put: &Put, data: &[u8], sent: &[u8], owner: Owner) -> Result<FileInfo, (ErrorKind, String)> {
+        let (mut client, mut server) = tokio::io::duplex(1 << 20);
+        for chunk in data.chunks(3) {
+            protocol::write_frame(&mut client, chunk).await.unwrap();
+        }
+        protocol::write_frame(&mut client, hex(&Sha256::digest(sent)).as_bytes()).await.unwrap();
+        receive(&mut server, put, Path::new(&put.path), owner).await.unwrap()
+    }
+
+    #[test]
+    fn test_check_path() {
+        assert!(check_path("/etc/app.conf").is_ok());
+        for path in ["etc/app.conf", "/etc/../root/x", "/srv/./x", "/srv/"] {
+            assert!(check_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_get() {
+        let dir = std::env::temp_dir().join(format!("forwarder-transfer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        // a replaced file keeps its mode, the data is checked before it is replaced
+        let data = b"new content\n";
+        let info = transfer(&put(&path, data), data, data, Owner::default()).await.unwrap();
+        assert_eq!((info.size, info.mode), (12, 0o640));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        let mut wrong = put(&path, b"other content");
+        wrong.mode = Some(0o600);
+        assert_eq!(transfer(&wrong, b"other_content", b"other content", Owner::default()).await.unwrap_err().0, ErrorKind::Invalid);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // the checksum is computed as the file is sent
+        let (file, got) = open(&path).await.unwrap();
+        assert_eq!(got, FileInfo { sha256: String::new(), ..info.clone() });
+        let (mut client, mut server) = tokio::io::duplex(1 << 20);
+        assert_eq!(send(file, got.size, &mut server).await.unwrap(), info.sha256);
+        assert_eq!(protocol::read_frame(&mut client, protocol::MAX_FRAME).await.unwrap().unwrap(), data);
+        assert_eq!(protocol::read_frame(&mut client, protocol::MAX_FRAME).await.unwrap().unwrap(), info.sha256.as_bytes());
+
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert_eq!(resolve(link.to_str().unwrap(), false).await.unwrap(), path.canonicalize().unwrap());
+        assert_eq!(resolve(link.to_str().unwrap(), true).await.unwrap(), dir.canonicalize().unwrap().join("link"));
+        assert!(open(&dir).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_symlinks() {
+        let dir = std::env::temp_dir().join(format!("forwarder-symlinks-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("real")).unwrap();
+        std::fs::write(dir.join("real/file"), "data").unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("swapped")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real/file"), dir.join("real/link")).unwrap();
+
+        // a symlink anywhere on the checked path is not followed
+        assert!(open(dir.join("real/file")).await.is_ok());
+        assert!(open(dir.join("swapped/file")).await.is_err());
+        assert!(open(dir.join("real/link")).await.is_err());
+        let data = b"new";
+        let err = transfer(&put(&dir.join("swapped/file"), data), data, data, Owner::default()).await.unwrap_err();
+        assert!(err.1.starts_with("Failed to create"), "{err:?}");
+        let mut in_place = put(&dir.join("real/link"), data);
+        in_place.in_place = true;
+        assert!(transfer(&in_place, data, data, Owner::default()).await.is_err());
+        assert_eq!(std::fs::read(dir.join("real/file")).unwrap(), b"data");
+        // replacing the link itself leaves its target alone
+        transfer(&put(&dir.join("real/link"), data), data, data, Owner::default()).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("real/file")).unwrap(), b"data");
+        assert_eq!(std::fs::read(dir.join("real/link")).unwrap(), data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mode_owner() {
+        let dir = std::env::temp_dir().join(format!("forwarder-owner-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut setuid = put(&dir.join("setuid"), b"#!/bin/sh\n");
+        setuid.mode = Some(0o4755);
+        let owner = Owner { created: (Some(65534), Some(65534)), ..Default::default() };
+        let info = transfer(&setuid, b"#!/bin/sh\n", b"#!/bin/sh\n", owner).await.unwrap();
+        assert_eq!(info.mode, 0o755);
+        assert_eq!(std::fs::metadata(dir.join("setuid")).unwrap().mode() & 0o7777, 0o755);
+        if unsafe { libc::geteuid() } == 0 {
+            // a new file is owned by the peer, a replaced one keeps its owner
+            assert_eq!((info.uid, info.gid), (65534, 65534));
+            std::fs::write(dir.join("root"), "old").unwrap();
+            let info = transfer(&put(&dir.join("root"), b"new"), b"new", b"new", owner).await.unwrap();
+            assert_eq!(info.uid, 0);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
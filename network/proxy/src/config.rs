@@ -0,0 +1,275 @@
+//! Routes of the proxy.
+//!
+//! ```toml
+//! [[route]]
+//! listen = "0.0.0.0:2222"
+//! upstream = "10.0.0.5:22"
+//!
+//! [[route]]
+//! listen = "unix:/run/proxy/db.sock"
+//! upstream = ["10.0.0.7:5432", "10.0.0.8:5432"]
+//! connect_timeout_ms = 500
+//! retries = 3
+//!
+//! [[route]]
+//! listen = "udp:0.0.0.0:53"
+//! upstream = ["udp:10.0.0.2:53", "udp:10.0.0.3:53"]
+//! idle_secs = 30
+//! ```
+//!
+//! An endpoint is `host:port` of TCP or `unix:<path>` of a Unix socket, on
+//! either side, or `udp:host:port` on both sides. Each client of a UDP route
+//! has a session with a socket of its own to an upstream, closed after
+//! `idle_secs` without datagrams, new clients are dropped while
+//! `max_sessions` are open. Upstreams are tried in their order, those which failed to
+//! connect are tried last until `down_secs` passed. A client is closed once
+//! `retries` more rounds over the upstreams, `backoff_ms` apart and doubling,
+//! failed too. On SIGHUP the file is read again: listeners of removed routes
+//! are closed, new ones are bound and changed upstreams apply to connections
+//! accepted afterwards. Established connections are kept in every case.
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Endpoint {
+    /// `host:port`, resolved when it is bound or connected to
+    Tcp(String),
+    Unix(PathBuf),
+    /// `host:port` of datagrams
+    Udp(String),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = |addr: &str| match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(addr.to_string()),
+            _ => Err(format!("Invalid endpoint `{s}`, expected host:port, unix:<path> or udp:host:port")),
+        };
+        if let Some(path) = s.strip_prefix("unix:") {
+            return match path {
+                "" => Err(format!("Missing path of `{s}`")),
+                path => Ok(Endpoint::Unix(PathBuf::from(path))),
+            };
+        }
+        match s.strip_prefix("udp:") {
+            Some(udp) => Ok(Endpoint::Udp(addr(udp)?)),
+            None => Ok(Endpoint::Tcp(addr(s)?)),
+        }
+    }
+}
+
+impl TryFrom<String> for Endpoint {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{addr}"),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Udp(addr) => write!(f, "udp:{addr}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub listen: Endpoint,
+    /// One endpoint or a list of them, in failover order
+    #[serde(rename = "upstream", deserialize_with = "one_or_many")]
+    pub upstreams: Vec<Endpoint>,
+    /// Milliseconds to wait for an upstream to accept
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// Rounds over the upstreams after the first one failed
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Milliseconds to wait before the first retry, doubled for the next ones
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    /// Seconds an upstream which failed to connect is tried last
+    #[serde(default = "default_down_secs")]
+    pub down_secs: u64,
+    /// Seconds a UDP session is kept without datagrams
+    #[serde(default = "default_idle_secs")]
+    pub idle_secs: u64,
+    /// Max UDP sessions open at once
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_backoff_ms() -> u64 {
+    100
+}
+
+fn default_down_secs() -> u64 {
+    10
+}
+
+fn default_idle_secs() -> u64 {
+    60
+}
+
+fn default_max_sessions() -> usize {
+    1024
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Endpoint>, D::Error> {
+    struct OneOrMany;
+
+    impl<'de> Visitor<'de> for OneOrMany {
+        type Value = Vec<Endpoint>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an endpoint or a list of endpoints")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            Ok(vec![s.parse().map_err(E::custom)?])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut endpoints = Vec::new();
+            while let Some(endpoint) = seq.next_element()? {
+                endpoints.push(endpoint);
+            }
+            Ok(endpoints)
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany)
+}
+
+impl Route {
+    /// Route to `upstreams` with the default timeouts and retries
+    pub fn new(listen: Endpoint, upstreams: Vec<Endpoint>) -> Self {
+        Route {
+            listen,
+            upstreams,
+            connect_timeout_ms: default_connect_timeout_ms(),
+            retries: default_retries(),
+            backoff_ms: default_backoff_ms(),
+            down_secs: default_down_secs(),
+            idle_secs: default_idle_secs(),
+            max_sessions: default_max_sessions(),
+        }
+    }
+
+    /// Datagrams are only forwarded to datagrams, sessions must be able to open
+    pub fn check(&self) -> Result<(), String> {
+        if self.upstreams.is_empty() {
+            return Err(format!("`{}` has no upstream", self.listen));
+        }
+        if self.idle_secs == 0 || self.max_sessions == 0 {
+            return Err(format!("`{}` needs `idle_secs` and `max_sessions` above 0", self.listen));
+        }
+        let udp = matches!(self.listen, Endpoint::Udp(_));
+        match self.upstreams.iter().find(|upstream| matches!(upstream, Endpoint::Udp(_)) != udp) {
+            Some(upstream) => Err(format!("`{}` can't forward to `{upstream}`, UDP only goes to UDP", self.listen)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn backoff(&self) -> Duration {
+        Duration::from_millis(self.backoff_ms)
+    }
+
+    pub fn down_period(&self) -> Duration {
+        Duration::from_secs(self.down_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_secs)
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let upstreams: Vec<_> = self.upstreams.iter().map(Endpoint::to_string).collect();
+        write!(f, "{} -> {}", self.listen, upstreams.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "route")]
+    pub routes: Vec<Route>,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        let config: Config = toml::from_str(&content).map_err(|e| format!("Invalid config {}: {e}", path.display()))?;
+        let mut listens = HashSet::new();
+        for route in &config.routes {
+            if !listens.insert(&route.listen) {
+                return Err(format!("Invalid config {}: `{}` is listened on twice", path.display(), route.listen));
+            }
+            route.check().map_err(|e| format!("Invalid config {}: {e}", path.display()))?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("proxy-config-{}.toml", std::process::id()));
+        let route = "[[route]]\nlisten = \"127.0.0.1:2222\"\nupstream = \"unix:/run/app.sock\"\n";
+        fs::write(&path, route).unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.routes, [Route::new(Endpoint::Tcp("127.0.0.1:2222".to_string()), vec![Endpoint::Unix("/run/app.sock".into())])]);
+
+        fs::write(&path, "[[route]]\nlisten = \"unix:/run/a.sock\"\nupstream = [\"h1:22\", \"h2:22\"]\nretries = 0\n").unwrap();
+        let failover = &Config::load(&path).unwrap().routes[0];
+        assert_eq!((failover.upstreams.len(), failover.retries, failover.connect_timeout_ms), (2, 0, 1000));
+        assert_eq!(failover.to_string(), "unix:/run/a.sock -> h1:22, h2:22");
+        fs::write(&path, "[[route]]\nlisten = \"h:22\"\nupstream = []\n").unwrap();
+        assert!(Config::load(&path).unwrap_err().contains("has no upstream"));
+        fs::write(&path, "[[route]]\nlisten = \"h:22\"\nupstream = [\"h:x\"]\n").unwrap();
+        assert!(Config::load(&path).unwrap_err().contains("Invalid endpoint `h:x`"));
+        fs::write(&path, "[[route]]\nlisten = \"udp:0.0.0.0:53\"\nupstream = [\"udp:[::1]:53\", \"h:53\"]\n").unwrap();
+        assert!(Config::load(&path).unwrap_err().contains("can't forward to `h:53`"));
+        fs::write(&path, "[[route]]\nlisten = \"udp:0.0.0.0:53\"\nupstream = \"udp:h:53\"\nmax_sessions = 0\n").unwrap();
+        assert!(Config::load(&path).unwrap_err().contains("above 0"));
+
+        fs::write(&path, format!("{route}{route}")).unwrap();
+        assert!(Config::load(&path).unwrap_err().contains("listened on twice"));
+        fs::write(&path, "[[route]]\nlisten = \"2222\"\nupstream = \"unix:\"\n").unwrap();
+        assert!(Config::load(&path).unwrap_err().contains("Invalid endpoint `2222`"));
+        fs::remove_file(&path).unwrap();
+        assert_eq!("[::1]:22".parse(), Ok(Endpoint::Tcp("[::1]:22".to_string())));
+        assert_eq!("udp:h:53".parse(), Ok(Endpoint::Udp("h:53".to_string())));
+    }
+}
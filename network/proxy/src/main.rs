@@ -1,47 +1,347 @@
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
-use tokio::io;
+mod config;
+mod udp;
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::timeout;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 
 use clap::Parser;
 
+use config::{Config, Endpoint, Route};
+
 #[derive(Parser)]
 struct Opts {
+    /// TOML file of routes, read again on SIGHUP
+    #[arg(short, long, conflicts_with_all = ["port", "dest", "bind"])]
+    config: Option<PathBuf>,
+
     /// port number to listen
-    #[arg(short, long)]
-    port: u32,
+    #[arg(short, long, required_unless_present = "config", requires = "dest")]
+    port: Option<u16>,
+
+    /// Address to listen on with --port
+    #[arg(short, long, default_value = "0.0.0.0")]
+    bind: String,
+
+    /// Listen on UDP with --port, destinations of host:port are then UDP too
+    #[arg(short, long, requires = "port")]
+    udp: bool,
 
-    /// Destination address, e.g.: 127.0.0.1:22
-    #[arg(short, long)]
-    dest: String,
+    /// Destination address, e.g.: 127.0.0.1:22 or unix:/run/app.sock, repeated for
+    /// upstreams to fail over to in order
+    #[arg(short, long, requires = "port")]
+    dest: Vec<Endpoint>,
+
+    /// Milliseconds to wait for a destination to accept, 1000 by default
+    #[arg(long, requires = "port")]
+    connect_timeout_ms: Option<u64>,
+
+    /// Rounds over the destinations after the first one failed, 2 by default
+    #[arg(long, requires = "port")]
+    retries: Option<u32>,
 }
 
-const CONNECTION_TIME: u64 = 1;
+/// Pause of the accept loop after an error, like running out of descriptors
+const ACCEPT_PAUSE: Duration = Duration::from_millis(100);
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let opts = Opts::parse();
-    let address = format!("0.0.0.0:{}", opts.port);
-    let listener = TcpListener::bind(&address).await?;
-    println!("Proxy started with {address}");
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
 
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// Socket file is removed with the listener
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    async fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            Endpoint::Unix(path) => {
+                // a socket left by a previous run is replaced, one still served or other files are not
+                if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    match UnixStream::connect(path).await {
+                        Ok(_) => {
+                            let message = format!("{} is served by another process", path.display());
+                            return Err(io::Error::new(io::ErrorKind::AddrInUse, message));
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                        Err(_) => (),
+                    }
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+            Endpoint::Udp(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{endpoint} is not a stream"))),
+        }
+    }
+
+    /// Next connection and a description of its peer
+    async fn accept(&self) -> io::Result<(Box<dyn Io>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr.to_string()))
+            }
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                let peer = match stream.peer_cred() {
+                    Ok(cred) => format!("uid={} pid={}", cred.uid(), cred.pid().unwrap_or_default()),
+                    Err(_) => "unknown peer".to_string(),
+                };
+                Ok((Box::new(stream), peer))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+async fn connect(endpoint: &Endpoint) -> io::Result<Box<dyn Io>> {
+    match endpoint {
+        Endpoint::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr).await?)),
+        Endpoint::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+        Endpoint::Udp(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{endpoint} is not a stream"))),
+    }
+}
+
+/// Upstreams which failed to connect, tried last until their down period is over
+#[derive(Default)]
+struct Health {
+    down: Mutex<HashMap<Endpoint, Instant>>,
+}
+
+impl Health {
+    /// `upstreams` up in their order, then the ones down
+    fn order<'a>(&self, upstreams: &'a [Endpoint]) -> Vec<&'a Endpoint> {
+        let down = self.down.lock().unwrap();
+        let now = Instant::now();
+        let (up, down): (Vec<_>, Vec<_>) = upstreams.iter().partition(|upstream| down.get(*upstream).is_none_or(|until| *until <= now));
+        up.into_iter().chain(down).collect()
+    }
+
+    /// Mark `upstream` down for `period`, tells whether it was up
+    fn failed(&self, upstream: &Endpoint, period: Duration) -> bool {
+        let now = Instant::now();
+        let until = self.down.lock().unwrap().insert(upstream.clone(), now + period);
+        until.is_none_or(|until| until <= now)
+    }
+
+    /// Mark `upstream` up, tells whether it was down
+    fn connected(&self, upstream: &Endpoint) -> bool {
+        self.down.lock().unwrap().remove(upstream).is_some()
+    }
+}
+
+/// Connection to the first upstream of `route` which accepts, trying them
+/// again `retries` times with a doubling backoff
+async fn connect_upstream(route: &Route, health: &Health) -> Option<(Box<dyn Io>, Endpoint)> {
+    let mut backoff = route.backoff();
+    for attempt in 0..=route.retries {
+        if attempt > 0 {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        for upstream in health.order(&route.upstreams) {
+            let error = match timeout(route.connect_timeout(), connect(upstream)).await {
+                Ok(Ok(stream)) => {
+                    if health.connected(upstream) {
+                        println!("Upstream {upstream} is up again");
+                    }
+                    return Some((stream, upstream.clone()));
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => format!("no answer in {:?}", route.connect_timeout()),
+            };
+            eprintln!("Error: Failed to connect {upstream}: {error}");
+            if health.failed(upstream, route.down_period()) {
+                eprintln!("Error: Upstream {upstream} is down, tried last for {:?}", route.down_period());
+            }
+        }
+    }
+    None
+}
+
+/// Listener of a route, its upstreams may change while it runs
+struct Running {
+    route: watch::Sender<Route>,
+    task: JoinHandle<()>,
+}
+
+/// Forward connections of `listener` to the upstreams of the current route,
+/// a client is closed when none of them can be connected
+async fn serve(listener: Listener, route: watch::Receiver<Route>) {
+    let health = Arc::new(Health::default());
     loop {
-        let dest = opts.dest.clone();
-        let (mut src, source) = listener.accept().await?;
-        println!("connected from {source:?}");
-        match timeout(Duration::from_secs(CONNECTION_TIME), TcpStream::connect(&dest)).await {
-            Ok(Ok(mut dst)) => {
-                tokio::spawn(async move {
+        let listen = route.borrow().listen.clone();
+        let (mut src, source) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Error: Failed to accept on {listen}: {e}");
+                tokio::time::sleep(ACCEPT_PAUSE).await;
+                continue;
+            }
+        };
+        println!("connected from {source} to {listen}");
+        let (route, health) = (route.borrow().clone(), health.clone());
+        tokio::spawn(async move {
+            match connect_upstream(&route, &health).await {
+                Some((mut dst, upstream)) => {
                     let _ = io::copy_bidirectional(&mut src, &mut dst).await;
-                    println!("disconnected: {source:?}");
-                });
+                    println!("disconnected: {source} from {upstream}");
+                }
+                None => eprintln!("Error: No upstream of {listen} could be connected, close {source}"),
             }
-            _ => {
-                eprintln!("Error: Failed to connect {dest}, please check network.");
-                break
+        });
+    }
+}
+
+async fn start(route: &Route) -> io::Result<Running> {
+    let (sender, receiver) = watch::channel(route.clone());
+    let task = match &route.listen {
+        Endpoint::Udp(addr) => tokio::spawn(udp::serve(UdpSocket::bind(addr).await?, receiver)),
+        listen => tokio::spawn(serve(Listener::bind(listen).await?, receiver)),
+    };
+    println!("Proxy started with {route}");
+    Ok(Running { route: sender, task })
+}
+
+/// Apply the routes of `config` to the running ones, connections already
+/// established are left as they are
+async fn reload(routes: &mut HashMap<Endpoint, Running>, config: Config) {
+    let removed: Vec<_> = routes.keys().filter(|listen| !config.routes.iter().any(|route| route.listen == **listen)).cloned().collect();
+    // closed first, a new route may listen on the same port of another address
+    for listen in removed {
+        if let Some(running) = routes.remove(&listen) {
+            running.task.abort();
+            let _ = running.task.await;
+            println!("Proxy stopped with {listen}");
+        }
+    }
+    for route in &config.routes {
+        match routes.get(&route.listen) {
+            Some(running) => {
+                if *running.route.borrow() != *route {
+                    println!("Proxy changed with {route}");
+                    running.route.send_replace(route.clone());
+                }
             }
+            None => match start(route).await {
+                Ok(running) => {
+                    routes.insert(route.listen.clone(), running);
+                }
+                Err(e) => eprintln!("Error: Failed to listen on {}: {e}", route.listen),
+            },
         }
     }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opts = Opts::parse();
+    let config = match (&opts.config, opts.port) {
+        (Some(path), _) => Config::load(path)?,
+        (None, Some(port)) => {
+            let address = match opts.bind.contains(':') {
+                true => format!("[{}]:{port}", opts.bind),
+                false => format!("{}:{port}", opts.bind),
+            };
+            let (listen, dest) = match opts.udp {
+                true => {
+                    let dest = opts.dest.iter().map(|dest| match dest {
+                        Endpoint::Tcp(addr) => Endpoint::Udp(addr.clone()),
+                        dest => dest.clone(),
+                    });
+                    (Endpoint::Udp(address), dest.collect())
+                }
+                false => (Endpoint::Tcp(address), opts.dest.clone()),
+            };
+            let mut route = Route::new(listen, dest);
+            route.connect_timeout_ms = opts.connect_timeout_ms.unwrap_or(route.connect_timeout_ms);
+            route.retries = opts.retries.unwrap_or(route.retries);
+            route.check()?;
+            Config { routes: vec![route] }
+        }
+        _ => unreachable!("clap requires --config or --port and --dest"),
+    };
+
+    let mut routes = HashMap::new();
+    for route in &config.routes {
+        routes.insert(route.listen.clone(), start(route).await?);
+    }
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => match &opts.config {
+                Some(path) => match Config::load(path) {
+                    Ok(config) => reload(&mut routes, config).await,
+                    Err(e) => eprintln!("Error: Failed to reload, the routes are kept: {e}"),
+                },
+                None => println!("No config file to reload"),
+            },
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+    }
+    // stopping every route drops its listener, which removes the socket file
+    reload(&mut routes, Config { routes: Vec::new() }).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let dir = std::env::temp_dir().join(format!("proxy-bind-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let listen = Endpoint::Unix(dir.join("s.sock"));
+        // left by a process that is gone, the file stays when the listener closes
+        drop(std::os::unix::net::UnixListener::bind(dir.join("s.sock")).unwrap());
+        let listener = Listener::bind(&listen).await.unwrap();
+        let e = Listener::bind(&listen).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(listener.accept().await.is_ok());
+        drop(listener);
+        assert!(!dir.join("s.sock").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = Endpoint::Tcp(listener.local_addr().unwrap().to_string());
+        // a port nothing listens on anymore
+        let down = Endpoint::Tcp(TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string());
+        let health = Health::default();
+        let mut route = Route::new(Endpoint::Tcp("127.0.0.1:0".to_string()), vec![down.clone(), up.clone()]);
+        let (_, upstream) = connect_upstream(&route, &health).await.unwrap();
+        assert_eq!(upstream, up);
+        assert_eq!(health.order(&route.upstreams), [&up, &down]);
+
+        route.upstreams = vec![down.clone()];
+        route.backoff_ms = 1;
+        assert!(connect_upstream(&route, &health).await.is_none());
+        drop(listener);
+        assert!(!health.failed(&down, route.down_period()));
+        assert!(health.connected(&down));
+    }
+}
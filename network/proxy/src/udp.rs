@@ -0,0 +1,264 @@
+//! Forwarding of datagrams.
+//!
+//! Each source address sending to a UDP route gets a session: a socket of its
+//! own connected to an upstream, so that answers go back to the client which
+//! asked. A session is closed after the idle timeout of its route, it is kept
+//! on its upstream when the route changes, like established TCP connections.
+//! An upstream answering with an ICMP error moves the session to the next one.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io;
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until, timeout};
+
+use crate::Health;
+use crate::config::{Endpoint, Route};
+
+/// Largest datagram of UDP
+const MAX_DATAGRAM: usize = 65535;
+
+/// Datagrams of a client waiting for its session, more are dropped
+const QUEUE_LEN: usize = 64;
+
+struct Session {
+    socket: UdpSocket,
+    upstream: Endpoint,
+}
+
+/// Datagrams of a client on their way to its session
+struct Queue {
+    sender: mpsc::Sender<Vec<u8>>,
+    /// Datagrams dropped as the queue was full
+    dropped: Arc<AtomicU64>,
+}
+
+/// Socket of an ephemeral port connected to `addr`
+async fn connect(addr: &str) -> io::Result<UdpSocket> {
+    let target = lookup_host(addr).await?.next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// Session to the first upstream of `route` but `failed` which resolves, a
+/// datagram reaching it is not known until it answers
+async fn open(route: &Route, health: &Health, failed: Option<&Endpoint>) -> Option<Session> {
+    for upstream in health.order(&route.upstreams) {
+        let Endpoint::Udp(addr) = upstream else { continue };
+        if Some(upstream) == failed {
+            continue;
+        }
+        let error = match timeout(route.connect_timeout(), connect(addr)).await {
+            Ok(Ok(socket)) => return Some(Session { socket, upstream: upstream.clone() }),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("no address in {:?}", route.connect_timeout()),
+        };
+        eprintln!("Error: Failed to connect {upstream}: {error}");
+        if health.failed(upstream, route.down_period()) {
+            eprintln!("Error: Upstream {upstream} is down, tried last for {:?}", route.down_period());
+        }
+    }
+    None
+}
+
+/// Move `session` of `client` off its upstream, which reported `error`
+async fn reopen(session: &mut Session, client: SocketAddr, route: &Route, health: &Health, error: io::Error) {
+    eprintln!("Error: Failed to reach {}: {error}", session.upstream);
+    if health.failed(&session.upstream, route.down_period()) {
+        eprintln!("Error: Upstream {} is down, tried last for {:?}", session.upstream, route.down_period());
+    }
+    if let Some(next) = open(route, health, Some(&session.upstream)).await {
+        println!("session of {client} on {} moved from {} to {}", route.listen, session.upstream, next.upstream);
+        *session = next;
+    }
+}
+
+/// Send `datagrams` of `client` to an upstream of `route` and its answers back
+/// until neither side sent for the idle timeout, gives the client back.
+/// `dropped` counts the datagrams which did not fit in the queue.
+async fn relay(
+    listener: Arc<UdpSocket>,
+    client: SocketAddr,
+    route: Route,
+    health: Arc<Health>,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+) -> SocketAddr {
+    let listen = &route.listen;
+    let Some(mut session) = open(&route, &health, None).await else {
+        datagrams.close();
+        let dropped = dropped.load(Ordering::Relaxed) + datagrams.len() as u64;
+        eprintln!("Error: No upstream of {listen} could be connected, {dropped} datagrams of {client} dropped");
+        return client;
+    };
+    println!("session of {client} on {listen} to {}", session.upstream);
+    let (mut from_client, mut from_upstream) = (0u64, 0u64);
+    let mut deadline = Instant::now() + route.idle_timeout();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            Some(datagram) = datagrams.recv() => {
+                deadline = Instant::now() + route.idle_timeout();
+                from_client += 1;
+                // an ICMP error of a datagram sent before may come up here, like port unreachable
+                if let Err(e) = session.socket.send(&datagram).await {
+                    reopen(&mut session, client, &route, &health, e).await;
+                }
+            }
+            received = session.socket.recv(&mut buf) => match received {
+                Ok(len) => {
+                    deadline = Instant::now() + route.idle_timeout();
+                    from_upstream += 1;
+                    if health.connected(&session.upstream) {
+                        println!("Upstream {} is up again", session.upstream);
+                    }
+                    if let Err(e) = listener.send_to(&buf[..len], client).await {
+                        eprintln!("Error: Failed to send to {client}: {e}");
+                    }
+                }
+                // or here
+                Err(e) => reopen(&mut session, client, &route, &health, e).await,
+            },
+            _ = sleep_until(deadline) => break,
+        }
+    }
+    // datagrams which came in since still go out, their answers are lost
+    datagrams.close();
+    while let Ok(datagram) = datagrams.try_recv() {
+        from_client += 1;
+        let _ = session.socket.send(&datagram).await;
+    }
+    println!(
+        "session of {client} on {listen} closed: {from_client} datagrams from client, {from_upstream} from {}, {} dropped as the queue was full",
+        session.upstream,
+        dropped.load(Ordering::Relaxed)
+    );
+    client
+}
+
+/// Forward datagrams of `listener` to the upstreams of the current route,
+/// with a session per client
+pub async fn serve(listener: UdpSocket, route: watch::Receiver<Route>) {
+    let listener = Arc::new(listener);
+    let health = Arc::new(Health::default());
+    let mut sessions: HashMap<SocketAddr, Queue> = HashMap::new();
+    // sessions end with the route
+    let mut relays = JoinSet::new();
+    // datagrams of new clients dropped since the session limit was reached
+    let mut dropped = 0u64;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let listen = route.borrow().listen.clone();
+        tokio::select! {
+            received = listener.recv_from(&mut buf) => {
+                let (len, client) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("Error: Failed to receive on {listen}: {e}");
+                        continue;
+                    }
+                };
+                // a session which just went idle is replaced
+                if let Some(queue) = sessions.get(&client)
+                    && !queue.sender.is_closed()
+                {
+                    // dropped like by a full socket buffer
+                    if let Err(mpsc::error::TrySendError::Full(_)) = queue.sender.try_send(buf[..len].to_vec()) {
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    continue;
+                }
+                let route = route.borrow().clone();
+                let open = sessions.values().filter(|queue| !queue.sender.is_closed()).count();
+                if open >= route.max_sessions {
+                    if dropped == 0 {
+                        eprintln!("Error: {open} sessions open on {listen}, datagrams of new clients are dropped");
+                    }
+                    dropped += 1;
+                    continue;
+                }
+                let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+                let _ = sender.try_send(buf[..len].to_vec());
+                let dropped = Arc::new(AtomicU64::new(0));
+                sessions.insert(client, Queue { sender, dropped: dropped.clone() });
+                relays.spawn(relay(listener.clone(), client, route, health.clone(), receiver, dropped));
+            }
+            Some(Ok(client)) = relays.join_next() => {
+                if sessions.get(&client).is_some_and(|queue| queue.sender.is_closed()) {
+                    sessions.remove(&client);
+                }
+                if dropped > 0 {
+                    println!("{listen} accepts new clients again, {dropped} datagrams were dropped");
+                    dropped = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sessions() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen = listener.local_addr().unwrap();
+        let mut route = Route::new(Endpoint::Udp(listen.to_string()), vec![Endpoint::Udp(upstream.local_addr().unwrap().to_string())]);
+        (route.idle_secs, route.max_sessions) = (1, 1);
+        let (_sender, receiver) = watch::channel(route);
+        let server = tokio::spawn(serve(listener, receiver));
+
+        let (first, second) = (UdpSocket::bind("127.0.0.1:0").await.unwrap(), UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        first.send_to(b"ping", listen).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (len, session) = upstream.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        upstream.send_to(b"pong", session).await.unwrap();
+        assert_eq!(first.recv(&mut buf).await.unwrap(), 4);
+
+        // over the limit until the first session is idle
+        second.send_to(b"ping", listen).await.unwrap();
+        assert!(timeout(Duration::from_millis(300), upstream.recv_from(&mut buf)).await.is_err());
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        second.send_to(b"ping", listen).await.unwrap();
+        let (_, other) = upstream.recv_from(&mut buf).await.unwrap();
+        assert_ne!(other, session);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let up = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // a port nothing listens on anymore, answering with port unreachable
+        let down = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen = listener.local_addr().unwrap();
+        let upstreams = vec![Endpoint::Udp(down.to_string()), Endpoint::Udp(up.local_addr().unwrap().to_string())];
+        let (_sender, receiver) = watch::channel(Route::new(Endpoint::Udp(listen.to_string()), upstreams));
+        let server = tokio::spawn(serve(listener, receiver));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 16];
+        // the first datagram is lost to the upstream down, the client sends again
+        let received = timeout(Duration::from_secs(5), async {
+            loop {
+                client.send_to(b"ping", listen).await.unwrap();
+                if let Ok(received) = timeout(Duration::from_millis(100), up.recv_from(&mut buf)).await {
+                    break received.unwrap();
+                }
+            }
+        });
+        let (len, session) = received.await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        up.send_to(b"pong", session).await.unwrap();
+        assert_eq!(client.recv(&mut buf).await.unwrap(), 4);
+        server.abort();
+    }
+}
@@ -0,0 +1,166 @@
+//! Discovery tasks. They are generic over `PacketIo` to run on AF_PACKET
+//! sockets or on a simulated fabric.
+use std::collections::{HashMap, HashSet};
+
+use std::sync::{Arc, RwLock};
+
+use log::{debug, error};
+use pnet::datalink::MacAddr;
+use tokio::sync::mpsc;
+
+use crate::l3::{self, Answer, Query};
+use crate::packet::{self, Message, Peer, Probe};
+use crate::{Interface, PacketIo};
+
+/// NICs to send from, updated when NICs are added or removed
+pub type Nics = Arc<RwLock<Vec<Interface>>>;
+
+/// Receive buffer, large enough for jumbo frames
+const FRAME_LEN: usize = 65536;
+
+/// Receive on `ifindex`, answer requests and probes of peers through `packet`
+/// and send what peers tell to `topo`.
+///
+/// Peers found are probed with frames of `sizes`, and with `l3` the addresses
+/// they report with ARP or NDP then echo requests of the IP packet sizes of `l3`.
+pub async fn recv_packet<S: PacketIo>(
+    mut sock: S,
+    packet: mpsc::UnboundedSender<(u32, Peer, Message)>,
+    topo: mpsc::UnboundedSender<(u32, Peer, Message)>,
+    ifindex: u32,
+    sizes: Vec<u16>,
+    l3: Option<Vec<u16>>,
+) {
+    if let Err(e) = sock.set_promiscuous(true, ifindex).and_then(|_| sock.bind(ifindex)) {
+        error!("Failed to receive on {ifindex}: {e}");
+        return;
+    }
+    // peer (MAC, VLAN) which are already probed
+    let mut probed = HashSet::new();
+    // peers whose addresses are probed, by MAC and VLAN tag of their frames
+    let mut reaching: HashMap<(MacAddr, u16), Peer> = HashMap::new();
+    // addresses echo requests are sent to
+    let mut echoed = HashSet::new();
+    let mut buf = vec![0u8; FRAME_LEN];
+    loop {
+        let meta = match sock.recv(&mut buf).await {
+            Ok(meta) => meta,
+            Err(e) => {
+                // NIC is removed
+                error!("Stop receiving on {ifindex}: {e}");
+                return;
+            }
+        };
+        if meta.outgoing {
+            continue;
+        }
+        let ifindex = meta.ifindex;
+        let frame = &buf[..meta.caplen];
+        // VLAN the frame is received in, when NIC stripped its tag
+        let stripped = meta.vlan_tci.map(|tci| tci & 0xfff).filter(|vlan| *vlan != 0);
+        let tag = stripped.unwrap_or_else(|| l3::tag(frame));
+        if let Some((mut peer, message)) = packet::parse(frame) {
+            if let Some(vlan) = stripped {
+                peer.vlan = vlan;
+            }
+            debug!("Receive {message:?} at {} from {peer:?}, {meta:?}", ifindex);
+            match &message {
+                Message::Request => packet.send((ifindex, peer.clone(), Message::Reply)).unwrap(),
+                Message::Probe(probe) => {
+                    packet.send((ifindex, peer, Message::ProbeReply(probe.clone()))).unwrap();
+                    continue;
+                },
+                // addresses are reported with each frame size, they are probed once
+                Message::ProbeReply(probe) if l3.is_some() && reaching.insert((peer.mac, tag), peer.clone()).is_none() => {
+                    for addr in &probe.addrs {
+                        packet.send((ifindex, peer.clone(), Message::Reach(Query::Neighbor(*addr)))).unwrap();
+                    }
+                },
+                _ => (),
+            }
+            if matches!(message, Message::Request | Message::Reply) && probed.insert((peer.mac, peer.vlan)) {
+                for size in &sizes {
+                    let probe = Probe { size: *size, ..Default::default() };
+                    packet.send((ifindex, peer.clone(), Message::Probe(probe))).unwrap();
+                }
+            }
+            topo.send((ifindex, peer, message)).unwrap();
+        } else if let Some((mac, answer)) = l3::parse(frame) {
+            let (Some(peer), Some(mtus)) = (reaching.get(&(mac, tag)), &l3) else {
+                continue;
+            };
+            debug!("Receive {answer:?} at {} from {peer:?}, {meta:?}", ifindex);
+            // the peer learned the source of the query, it answers echo requests to it
+            if let Answer::Neighbor(addr) = answer {
+                if echoed.insert((mac, tag, addr)) {
+                    for size in mtus {
+                        packet.send((ifindex, peer.clone(), Message::Reach(Query::Echo(addr, *size)))).unwrap();
+                    }
+                }
+            }
+            topo.send((ifindex, peer.clone(), Message::Reached(answer))).unwrap();
+        }
+    }
+}
+
+pub async fn send_packet<S: PacketIo>(
+    mut sock: S,
+    mut rx: mpsc::UnboundedReceiver<(u32, Peer, Message)>,
+    progress: mpsc::UnboundedSender<u32>,
+    host: String,
+    nics: Nics,
+) {
+    while let Some((ifindex, peer, mut message)) = rx.recv().await {
+        let Some(interface) = nics.read().unwrap().iter().find(|n| n.index == ifindex).cloned() else {
+            continue;
+        };
+        let vlan = peer.vlan;
+        let mac = peer.mac;
+        if let Message::ProbeReply(probe) = &mut message {
+            probe.addrs = sock.addrs(&interface, vlan);
+        }
+        let size = match &message {
+            Message::Probe(probe) => probe.size as usize + 18,
+            Message::ProbeReply(probe) => 256 + probe.addrs.len() * 17,
+            Message::Reach(Query::Echo(_, size)) => *size as usize + 18,
+            _ => 256,
+        };
+        let mut buf = vec![0u8; size.max(256)];
+        let len = match &message {
+            Message::Reach(query) => match l3::build(&mut buf, &interface, mac, vlan, query, &sock.addrs(&interface, vlan)) {
+                Some(len) => len,
+                None => {
+                    debug!("{}: No source for {query:?} in VLAN {vlan}", interface.name);
+                    continue;
+                },
+            },
+            _ => packet::build(&mut buf, &interface, mac, vlan, &host, &message).max(256),
+        };
+        debug!("{}: Send {message:?} with VLAN {vlan} MAC({mac})", interface.name);
+        if let Err(e) = sock.set_promiscuous(true, ifindex) {
+            error!("{}: {e}", interface.name);
+            continue;
+        }
+        //sock.bind(ifindex).unwrap();
+        let _len = sock.send(&buf[..len], ifindex).await;
+        if let Message::Request = message {
+            let _ = progress.send(ifindex);
+        }
+    }
+}
+
+/// Broadcast probes for all `vlans` out of each of `nics`
+pub fn probe(ptx: &mpsc::UnboundedSender<(u32, Peer, Message)>, nics: &[Interface], vlans: &[(u16, u16)]) {
+    for nic in nics {
+        for (start, end) in vlans {
+            for vlan in *start..=*end {
+                let peer = Peer {
+                    mac: MacAddr::broadcast(),
+                    vlan,
+                    ..Default::default()
+                };
+                ptx.send((nic.index, peer, Message::Request)).unwrap();
+            }
+        }
+    }
+}
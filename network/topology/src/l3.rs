@@ -0,0 +1,431 @@
+//! L3 probing of the addresses a peer reports on a VLAN.
+//!
+//! Frames are sent by the packet socket of discovery, tagged like the frames
+//! of the topology protocol, so no VLAN interface is needed. An ARP request or
+//! an NDP neighbor solicitation checks that an address answers in the VLAN,
+//! then echo requests of the sizes asked check the MTU to it: ICMP ones with
+//! DF, ICMPv6 ones are never fragmented on the way. Answers of the kernel of
+//! the peer are received by the `ETH_P_ALL` sockets of discovery.
+//!
+//! The source is an address the local host has in the VLAN. Without one, an
+//! IPv4 address is checked with an ARP probe from 0.0.0.0, which the peer
+//! answers but does not learn, so no echo is sent to it. IPv6 then uses the
+//! link-local address of the NIC MAC, which the peer learns from the source
+//! link-layer address option of the solicitation.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use pnet::datalink::MacAddr;
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperation, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmp::{self, MutableIcmpPacket};
+use pnet::packet::icmpv6::ndp::NeighborAdvertPacket;
+use pnet::packet::icmpv6::{self, MutableIcmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::vlan::{MutableVlanPacket, VlanPacket};
+use pnet::packet::{MutablePacket, Packet};
+
+use crate::packet::now;
+use crate::Interface;
+
+/// Identifier of echo requests of this tool, to tell their replies apart
+const ECHO_ID: u16 = 0x7470;
+
+const ARP_LEN: usize = 28;
+const IPV4_LEN: usize = 20;
+const IPV6_LEN: usize = 40;
+/// ICMP echo header and the sending time
+const ECHO_LEN: usize = 16;
+/// Neighbor solicitation or advertisement with a link-layer address option
+const NDP_LEN: usize = 32;
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+const NEIGHBOR_SOLICIT: u8 = 135;
+const NEIGHBOR_ADVERT: u8 = 136;
+const SOURCE_LINK_ADDR: u8 = 1;
+const TARGET_LINK_ADDR: u8 = 2;
+
+/// Probe of an address of a peer, sent as `Message::Reach`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// ARP request or NDP neighbor solicitation
+    Neighbor(IpAddr),
+    /// Echo request of an IP packet of the size
+    Echo(IpAddr, u16),
+}
+
+/// Answer of a peer, received as `Message::Reached`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Answer {
+    /// ARP reply or NDP neighbor advertisement of the address
+    Neighbor(IpAddr),
+    /// Echo reply of an IP packet of `size` bytes
+    Echo { addr: IpAddr, size: u16, rtt: Duration },
+}
+
+/// Link-local address of `mac` by modified EUI-64
+pub fn link_local(mac: MacAddr) -> Ipv6Addr {
+    let m = mac.octets();
+    Ipv6Addr::new(
+        0xfe80,
+        0,
+        0,
+        0,
+        u16::from_be_bytes([m[0] ^ 2, m[1]]),
+        u16::from_be_bytes([m[2], 0xff]),
+        u16::from_be_bytes([0xfe, m[3]]),
+        u16::from_be_bytes([m[4], m[5]]),
+    )
+}
+
+fn source_v4(local: &[IpAddr]) -> Option<Ipv4Addr> {
+    local.iter().find_map(|addr| match addr {
+        IpAddr::V4(addr) => Some(*addr),
+        IpAddr::V6(_) => None,
+    })
+}
+
+fn source_v6(local: &[IpAddr], mac: MacAddr) -> Ipv6Addr {
+    local
+        .iter()
+        .find_map(|addr| match addr {
+            IpAddr::V6(addr) if addr.is_unicast_link_local() => Some(*addr),
+            _ => None,
+        })
+        .unwrap_or_else(|| link_local(mac))
+}
+
+/// VLAN of the 802.1Q tag of `buf`, 0 when it is untagged
+pub fn tag(buf: &[u8]) -> u16 {
+    header(buf).map(|(vlan, _, _)| vlan).unwrap_or_default()
+}
+
+/// VLAN tag, ethertype and start of the payload of an ethernet frame
+fn header(buf: &[u8]) -> Option<(u16, EtherType, usize)> {
+    let eth = EthernetPacket::new(buf)?;
+    if eth.get_ethertype() == EtherTypes::Vlan {
+        let vlan = VlanPacket::new(buf.get(14..)?)?;
+        Some((vlan.get_vlan_identifier(), vlan.get_ethertype(), 18))
+    } else {
+        Some((0, eth.get_ethertype(), 14))
+    }
+}
+
+/// Write ethernet header, tagged with `vlan` unless it is 0
+fn ethernet(buf: &mut [u8], src: MacAddr, dst: MacAddr, vlan: u16, ethertype: EtherType) {
+    let mut eth = MutableEthernetPacket::new(buf).unwrap();
+    eth.set_destination(dst);
+    eth.set_source(src);
+    if vlan == 0 {
+        eth.set_ethertype(ethertype);
+    } else {
+        eth.set_ethertype(EtherTypes::Vlan);
+        let mut tag = MutableVlanPacket::new(eth.payload_mut()).unwrap();
+        tag.set_vlan_identifier(vlan);
+        tag.set_ethertype(ethertype);
+    }
+}
+
+fn arp(buf: &mut [u8], operation: ArpOperation, sender: (MacAddr, Ipv4Addr), target: (MacAddr, Ipv4Addr)) {
+    let mut arp = MutableArpPacket::new(buf).unwrap();
+    arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp.set_protocol_type(EtherTypes::Ipv4);
+    arp.set_hw_addr_len(6);
+    arp.set_proto_addr_len(4);
+    arp.set_operation(operation);
+    arp.set_sender_hw_addr(sender.0);
+    arp.set_sender_proto_addr(sender.1);
+    arp.set_target_hw_addr(target.0);
+    arp.set_target_proto_addr(target.1);
+}
+
+/// Write ICMP or ICMPv6 echo message of `kind` sent at `stamp`, the rest of `buf` is its data
+fn echo(buf: &mut [u8], kind: u8, stamp: u64) {
+    buf[0] = kind;
+    buf[4..6].copy_from_slice(&ECHO_ID.to_be_bytes());
+    buf[8..16].copy_from_slice(&stamp.to_be_bytes());
+}
+
+/// Write neighbor solicitation or advertisement of `kind` for `target` with
+/// the link-layer address option of `mac`
+fn ndp(buf: &mut [u8], kind: u8, flags: u8, target: Ipv6Addr, option: u8, mac: MacAddr) {
+    buf[0] = kind;
+    buf[4] = flags;
+    buf[8..24].copy_from_slice(&target.octets());
+    buf[24] = option;
+    buf[25] = 1;
+    buf[26..32].copy_from_slice(&mac.octets());
+}
+
+/// Write IPv4 header of `buf` with DF, around the ICMP message in its payload
+fn ipv4(buf: &mut [u8], src: Ipv4Addr, dst: Ipv4Addr) {
+    let len = buf.len() as u16;
+    let mut ip = MutableIpv4Packet::new(buf).unwrap();
+    ip.set_version(4);
+    ip.set_header_length((IPV4_LEN / 4) as u8);
+    ip.set_total_length(len);
+    ip.set_flags(Ipv4Flags::DontFragment);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    ip.set_source(src);
+    ip.set_destination(dst);
+    let checksum = ipv4::checksum(&ip.to_immutable());
+    ip.set_checksum(checksum);
+    let mut icmp = MutableIcmpPacket::new(ip.payload_mut()).unwrap();
+    let checksum = icmp::checksum(&icmp.to_immutable());
+    icmp.set_checksum(checksum);
+}
+
+/// Write IPv6 header of `buf`, around the ICMPv6 message in its payload
+fn ipv6(buf: &mut [u8], src: Ipv6Addr, dst: Ipv6Addr, hop_limit: u8) {
+    let len = (buf.len() - IPV6_LEN) as u16;
+    let mut ip = MutableIpv6Packet::new(buf).unwrap();
+    ip.set_version(6);
+    ip.set_payload_length(len);
+    ip.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ip.set_hop_limit(hop_limit);
+    ip.set_source(src);
+    ip.set_destination(dst);
+    let mut icmp = MutableIcmpv6Packet::new(ip.payload_mut()).unwrap();
+    let checksum = icmpv6::checksum(&icmp.to_immutable(), &src, &dst);
+    icmp.set_checksum(checksum);
+}
+
+/// Build `query` to the peer of `dst_mac` in `vlan` into `buf`, from
+/// `interface` and a source among `local`, the addresses of the local host in
+/// that VLAN. `None` if it has no source for the query or `buf` is too short.
+pub fn build(buf: &mut [u8], interface: &Interface, dst_mac: MacAddr, vlan: u16, query: &Query, local: &[IpAddr]) -> Option<usize> {
+    let mac = MacAddr::from(<[u8; 6]>::from(&interface.mac));
+    let (ethertype, len) = match query {
+        Query::Neighbor(IpAddr::V4(_)) => (EtherTypes::Arp, ARP_LEN),
+        Query::Neighbor(IpAddr::V6(_)) => (EtherTypes::Ipv6, IPV6_LEN + NDP_LEN),
+        Query::Echo(IpAddr::V4(_), size) => (EtherTypes::Ipv4, (*size as usize).max(IPV4_LEN + ECHO_LEN)),
+        Query::Echo(IpAddr::V6(_), size) => (EtherTypes::Ipv6, (*size as usize).max(IPV6_LEN + ECHO_LEN)),
+    };
+    let start = if vlan != 0 { 18 } else { 14 };
+    let buf = buf.get_mut(..start + len)?;
+    buf.fill(0);
+    let packet = &mut buf[start..];
+    match *query {
+        Query::Neighbor(IpAddr::V4(target)) => {
+            let src = source_v4(local).unwrap_or(Ipv4Addr::UNSPECIFIED);
+            arp(packet, ArpOperations::Request, (mac, src), (MacAddr::zero(), target));
+        }
+        Query::Neighbor(IpAddr::V6(target)) => {
+            ndp(&mut packet[IPV6_LEN..], NEIGHBOR_SOLICIT, 0, target, SOURCE_LINK_ADDR, mac);
+            ipv6(packet, source_v6(local, mac), target, 255);
+        }
+        Query::Echo(IpAddr::V4(dst), _) => {
+            // the peer only answers a source it learned from the ARP request
+            let src = source_v4(local)?;
+            echo(&mut packet[IPV4_LEN..], ECHO_REQUEST, now());
+            ipv4(packet, src, dst);
+        }
+        Query::Echo(IpAddr::V6(dst), _) => {
+            echo(&mut packet[IPV6_LEN..], ECHO_REQUEST_V6, now());
+            ipv6(packet, source_v6(local, mac), dst, 64);
+        }
+    }
+    ethernet(buf, mac, dst_mac, vlan, ethertype);
+    Some(start + len)
+}
+
+/// Sending time in an echo message of `kind` to or from this tool
+fn echo_stamp(icmp: &[u8], kind: u8) -> Option<u64> {
+    let id = u16::from_be_bytes(icmp.get(4..6)?.try_into().ok()?);
+    if *icmp.first()? != kind || id != ECHO_ID {
+        return None;
+    }
+    Some(u64::from_be_bytes(icmp.get(8..16)?.try_into().ok()?))
+}
+
+/// Answer in a received frame and the MAC it is sent from. ARP replies and
+/// neighbor advertisements are taken whoever asked, they tell addresses of
+/// the sender all the same.
+pub fn parse(buf: &[u8]) -> Option<(MacAddr, Answer)> {
+    let (_, ethertype, start) = header(buf)?;
+    let packet = &buf[start..];
+    let answer = match ethertype {
+        EtherTypes::Arp => {
+            let arp = ArpPacket::new(packet)?;
+            let addr = arp.get_sender_proto_addr();
+            if arp.get_operation() != ArpOperations::Reply || addr.is_unspecified() {
+                return None;
+            }
+            Answer::Neighbor(IpAddr::V4(addr))
+        }
+        EtherTypes::Ipv4 => {
+            let ip = Ipv4Packet::new(packet)?;
+            if ip.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+                return None;
+            }
+            let stamp = echo_stamp(ip.payload(), ECHO_REPLY)?;
+            Answer::Echo {
+                addr: IpAddr::V4(ip.get_source()),
+                size: ip.get_total_length(),
+                rtt: Duration::from_nanos(now().saturating_sub(stamp)),
+            }
+        }
+        EtherTypes::Ipv6 => {
+            let ip = Ipv6Packet::new(packet)?;
+            if ip.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+                return None;
+            }
+            let icmp = ip.payload();
+            if *icmp.first()? == NEIGHBOR_ADVERT {
+                Answer::Neighbor(IpAddr::V6(NeighborAdvertPacket::new(icmp)?.get_target_addr()))
+            } else {
+                let stamp = echo_stamp(icmp, ECHO_REPLY_V6)?;
+                Answer::Echo {
+                    addr: IpAddr::V6(ip.get_source()),
+                    size: IPV6_LEN as u16 + ip.get_payload_length(),
+                    rtt: Duration::from_nanos(now().saturating_sub(stamp)),
+                }
+            }
+        }
+        _ => return None,
+    };
+    Some((EthernetPacket::new(buf)?.get_source(), answer))
+}
+
+/// Answer of the kernel of a host with `mac` and `addrs` to a query in
+/// `frame`, tagged with `vlan` unless it is 0. Used by the simulated fabric.
+#[cfg(feature = "sim")]
+pub(crate) fn answer(frame: &[u8], mac: MacAddr, vlan: u16, addrs: &[IpAddr]) -> Option<Vec<u8>> {
+    let (_, ethertype, offset) = header(frame)?;
+    let packet = &frame[offset..];
+    let start = if vlan != 0 { 18 } else { 14 };
+    let mut buf = vec![0u8; start + packet.len().max(ARP_LEN)];
+    let reply = &mut buf[start..];
+    let len = match ethertype {
+        EtherTypes::Arp => {
+            let arp = ArpPacket::new(packet)?;
+            let target = arp.get_target_proto_addr();
+            if arp.get_operation() != ArpOperations::Request || !addrs.contains(&IpAddr::V4(target)) {
+                return None;
+            }
+            self::arp(reply, ArpOperations::Reply, (mac, target), (arp.get_sender_hw_addr(), arp.get_sender_proto_addr()));
+            ARP_LEN
+        }
+        EtherTypes::Ipv4 => {
+            let ip = Ipv4Packet::new(packet)?;
+            let len = ip.get_total_length() as usize;
+            if echo_stamp(ip.payload(), ECHO_REQUEST).is_none() || !addrs.contains(&IpAddr::V4(ip.get_destination())) {
+                return None;
+            }
+            reply[..len].copy_from_slice(packet.get(..len)?);
+            reply[IPV4_LEN] = ECHO_REPLY;
+            ipv4(&mut reply[..len], ip.get_destination(), ip.get_source());
+            len
+        }
+        EtherTypes::Ipv6 => {
+            let ip = Ipv6Packet::new(packet)?;
+            let icmp = ip.payload();
+            match *icmp.first()? {
+                NEIGHBOR_SOLICIT => {
+                    let target = Ipv6Addr::from(<[u8; 16]>::try_from(icmp.get(8..24)?).ok()?);
+                    if !addrs.contains(&IpAddr::V6(target)) {
+                        return None;
+                    }
+                    // solicited and override
+                    ndp(&mut reply[IPV6_LEN..], NEIGHBOR_ADVERT, 0x60, target, TARGET_LINK_ADDR, mac);
+                    ipv6(&mut reply[..IPV6_LEN + NDP_LEN], target, ip.get_source(), 255);
+                    IPV6_LEN + NDP_LEN
+                }
+                ECHO_REQUEST_V6 if addrs.contains(&IpAddr::V6(ip.get_destination())) => {
+                    let len = IPV6_LEN + ip.get_payload_length() as usize;
+                    reply[..len].copy_from_slice(packet.get(..len)?);
+                    reply[IPV6_LEN] = ECHO_REPLY_V6;
+                    ipv6(&mut reply[..len], ip.get_destination(), ip.get_source(), 64);
+                    len
+                }
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    ethernet(&mut buf, mac, EthernetPacket::new(frame)?.get_source(), vlan, ethertype);
+    buf.truncate(start + len);
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MacAddress;
+
+    const PEER: MacAddr = MacAddr(2, 0, 0, 0, 0, 2);
+
+    fn interface() -> Interface {
+        Interface {
+            index: 1,
+            name: "eth0".to_string(),
+            mac: MacAddress::from([2, 0, 0, 0, 0, 1]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_link_local() {
+        assert_eq!(link_local(MacAddr::new(0x52, 0x54, 0, 0x12, 0x34, 0x56)), "fe80::5054:ff:fe12:3456".parse::<Ipv6Addr>().unwrap());
+    }
+
+    #[test]
+    fn test_build() {
+        let mut buf = [0u8; 9100];
+        let target: IpAddr = "10.0.20.2".parse().unwrap();
+        // an ARP probe without address in the VLAN
+        let len = build(&mut buf, &interface(), PEER, 20, &Query::Neighbor(target), &[]).unwrap();
+        assert_eq!((len, tag(&buf[..len])), (18 + ARP_LEN, 20));
+        let arp = ArpPacket::new(&buf[18..len]).unwrap();
+        assert_eq!(arp.get_operation(), ArpOperations::Request);
+        assert_eq!((arp.get_sender_proto_addr(), IpAddr::V4(arp.get_target_proto_addr())), (Ipv4Addr::UNSPECIFIED, target));
+        // nothing to answer an echo to
+        assert_eq!(build(&mut buf, &interface(), PEER, 20, &Query::Echo(target, 1500), &[]), None);
+
+        let local: IpAddr = "10.0.20.1".parse().unwrap();
+        let len = build(&mut buf, &interface(), PEER, 0, &Query::Echo(target, 1500), &[local]).unwrap();
+        assert_eq!(len, 14 + 1500);
+        let ip = Ipv4Packet::new(&buf[14..len]).unwrap();
+        assert_eq!((ip.get_flags(), IpAddr::V4(ip.get_source()), ip.get_total_length()), (Ipv4Flags::DontFragment, local, 1500));
+        assert_eq!(ipv4::checksum(&ip), ip.get_checksum());
+        assert!(build(&mut buf[..1000], &interface(), PEER, 0, &Query::Echo(target, 1500), &[local]).is_none());
+
+        // solicited from the link-local address of the MAC
+        let target: IpAddr = "fe80::2".parse().unwrap();
+        let len = build(&mut buf, &interface(), PEER, 20, &Query::Neighbor(target), &[local]).unwrap();
+        let ip = Ipv6Packet::new(&buf[18..len]).unwrap();
+        assert_eq!((ip.get_source(), ip.get_hop_limit()), (link_local(MacAddr::new(2, 0, 0, 0, 0, 1)), 255));
+        let icmp = icmpv6::Icmpv6Packet::new(ip.payload()).unwrap();
+        assert_eq!(icmpv6::checksum(&icmp, &ip.get_source(), &ip.get_destination()), icmp.get_checksum());
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut buf = [0u8; 256];
+        // a request is not an answer
+        let target: IpAddr = "10.0.20.2".parse().unwrap();
+        let len = build(&mut buf, &interface(), PEER, 20, &Query::Neighbor(target), &[]).unwrap();
+        assert!(parse(&buf[..len]).is_none());
+        buf[18 + 7] = 2;
+        buf[18 + 14..18 + 18].copy_from_slice(&[10, 0, 20, 2]);
+        assert_eq!(parse(&buf[..len]), Some((MacAddr::new(2, 0, 0, 0, 0, 1), Answer::Neighbor(target))));
+
+        // echo replies of other tools are not taken
+        let local: IpAddr = "fe80::1".parse().unwrap();
+        let len = build(&mut buf, &interface(), PEER, 0, &Query::Echo("fe80::2".parse().unwrap(), 100), &[local]).unwrap();
+        buf[14 + IPV6_LEN] = ECHO_REPLY_V6;
+        let Some((_, Answer::Echo { addr, size, .. })) = parse(&buf[..len]) else { panic!("not an echo reply") };
+        assert_eq!((addr, size), (local, 100));
+        buf[14 + IPV6_LEN + 4] = 0;
+        assert!(parse(&buf[..len]).is_none());
+        for cut in 0..14 + IPV6_LEN + ECHO_LEN {
+            assert!(parse(&buf[..cut]).is_none(), "parsed {cut} bytes");
+        }
+    }
+}
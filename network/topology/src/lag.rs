@@ -0,0 +1,276 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+use log::debug;
+
+use crate::netlink::link_kind;
+use crate::{show_vlan, Interface, MacAddress, Topo};
+
+const NIC_PATH: &str = "/sys/class/net/";
+const PROC_BONDING: &str = "/proc/net/bonding/";
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum LagKind {
+    Bond,
+    Team,
+}
+
+/// Actor information the switch sent in its LACPDU
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct LacpPartner {
+    pub system: MacAddress,
+    pub key: u16,
+    pub port: u16,
+}
+
+/// Link aggregation (bonding or team device) and its member NICs
+#[derive(Debug, Clone)]
+pub struct Lag {
+    pub name: String,
+    pub kind: LagKind,
+    /// Bonding mode, e.g. `802.3ad` or `active-backup`. Unknown for team devices.
+    pub mode: Option<String>,
+    pub members: Vec<String>,
+    /// LACP partner seen by each member, only for members which negotiated LACP
+    pub partners: HashMap<String, LacpPartner>,
+}
+
+impl Lag {
+    pub fn is_lacp(&self) -> bool {
+        self.mode.as_deref() == Some("802.3ad")
+    }
+}
+
+/// Aggregation of a master with IFLA_INFO_KIND `kind`. Bridges, OVS, VRF and
+/// macvlan masters are not aggregations.
+pub fn lag_kind(kind: &str) -> Option<LagKind> {
+    match kind {
+        "bond" => Some(LagKind::Bond),
+        "team" => Some(LagKind::Team),
+        _ => None,
+    }
+}
+
+/// Find all bond and team devices which have one of `nics` enslaved, with
+/// their members among `nics`.
+pub fn get_lags(nics: &[Interface]) -> Vec<Lag> {
+    let mut masters: Vec<&String> = nics.iter().filter_map(|n| n.master.as_ref()).collect();
+    masters.sort();
+    masters.dedup();
+
+    let mut lags = vec![];
+    for master in masters {
+        let path = Path::new(NIC_PATH).join(master);
+        let kind = match link_kind(master) {
+            Ok(kind) => kind.as_deref().and_then(lag_kind),
+            Err(e) => {
+                // sysfs still tells bonds apart
+                debug!("Failed to get kind of {master}: {e}");
+                path.join("bonding").exists().then_some(LagKind::Bond)
+            },
+        };
+        let probed = |name: &str| nics.iter().any(|n| n.name == name);
+        if kind == Some(LagKind::Bond) {
+            let mode = fs::read_to_string(path.join("bonding/mode")).unwrap_or_default();
+            let members = fs::read_to_string(path.join("bonding/slaves")).unwrap_or_default();
+            let partners = fs::read_to_string(Path::new(PROC_BONDING).join(master))
+                .map(|s| parse_bonding(&s))
+                .unwrap_or_default();
+            lags.push(Lag {
+                name: master.clone(),
+                kind: LagKind::Bond,
+                // mode is like `802.3ad 4`
+                mode: mode.split_whitespace().next().map(String::from),
+                members: members.split_whitespace().filter(|m| probed(m)).map(String::from).collect(),
+                partners,
+            });
+        } else if kind == Some(LagKind::Team) {
+            // teamd does not expose its state in sysfs, only membership is known
+            lags.push(Lag {
+                name: master.clone(),
+                kind: LagKind::Team,
+                mode: None,
+                members: nics
+                    .iter()
+                    .filter(|n| n.master.as_ref() == Some(master))
+                    .map(|n| n.name.clone())
+                    .collect(),
+                partners: HashMap::new(),
+            });
+        }
+    }
+    lags
+}
+
+/// Parse LACP partner of each slave from `/proc/net/bonding/<bond>`.
+pub fn parse_bonding(content: &str) -> HashMap<String, LacpPartner> {
+    let mut partners = HashMap::new();
+    let mut slave: Option<&str> = None;
+    let mut in_partner = false;
+    let mut system = MacAddress::default();
+    let mut key = 0;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix("Slave Interface:") {
+            slave = Some(name.trim());
+            in_partner = false;
+        } else if line.starts_with("details partner lacp pdu:") {
+            in_partner = true;
+        } else if line.starts_with("details ") {
+            in_partner = false;
+        } else if in_partner {
+            let Some((k, v)) = line.split_once(':') else {
+                continue;
+            };
+            let v = v.trim();
+            match k.trim() {
+                "system mac address" => system = v.parse().unwrap_or_default(),
+                "oper key" => key = v.parse().unwrap_or_default(),
+                "port number" => {
+                    // port number is the last field of partner details
+                    in_partner = false;
+                    // All-zero system means no LACPDU was received from the switch
+                    if let (Some(slave), false) = (slave, system == MacAddress::default()) {
+                        partners.insert(
+                            slave.to_string(),
+                            LacpPartner {
+                                system: system.clone(),
+                                key,
+                                port: v.parse().unwrap_or_default(),
+                            },
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    partners
+}
+
+/// Check whether members of each aggregation are wired consistently.
+///
+/// Members of one LAG are expected to reach the same peers with the same
+/// VLANs, and for LACP bonds to negotiate with the same partner system.
+pub fn check(topo: &Topo, lags: &[Lag]) -> Vec<String> {
+    let mut warnings = vec![];
+    for lag in lags {
+        let mut peers: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+        let mut vlans: HashMap<&str, BTreeSet<u16>> = HashMap::new();
+        for ((me, peer), v) in &topo.connection {
+            if lag.members.contains(&me.nic.name) {
+                peers.entry(&me.nic.name).or_default().insert(&peer.host);
+                vlans.entry(&me.nic.name).or_default().extend(v);
+            }
+        }
+
+        if lag.is_lacp() {
+            let mut systems: Vec<_> = lag.partners.values().map(|p| (&p.system, p.key)).collect();
+            systems.sort_by_key(|(mac, key)| (mac.to_string(), *key));
+            systems.dedup();
+            if systems.len() > 1 {
+                let detail: Vec<_> = lag
+                    .members
+                    .iter()
+                    .filter_map(|m| lag.partners.get(m).map(|p| format!("{m} -> {} key {}", p.system, p.key)))
+                    .collect();
+                warnings.push(format!("{}: members negotiate with different LACP partners: {}", lag.name, detail.join(", ")));
+            }
+            for member in &lag.members {
+                if !lag.partners.contains_key(member) {
+                    warnings.push(format!("{}: {member} has no LACP partner", lag.name));
+                }
+            }
+        }
+
+        let reached: Vec<_> = lag.members.iter().filter(|m| peers.contains_key(m.as_str())).collect();
+        let peer_sets: Vec<_> = reached
+            .iter()
+            .map(|m| peers[m.as_str()].iter().copied().collect::<Vec<_>>().join(","))
+            .collect();
+        if differ(&peer_sets) {
+            let detail: Vec<_> = reached.iter().zip(&peer_sets).map(|(m, p)| format!("{m} -> {p}")).collect();
+            warnings.push(format!("{}: members reach different peers: {}", lag.name, detail.join(", ")));
+        }
+        let vlan_sets: Vec<_> = reached
+            .iter()
+            .map(|m| show_vlan(&vlans[m.as_str()].iter().copied().collect::<Vec<_>>()))
+            .collect();
+        if differ(&vlan_sets) {
+            let detail: Vec<_> = reached.iter().zip(&vlan_sets).map(|(m, v)| format!("{m} VLAN {v}")).collect();
+            warnings.push(format!("{}: members carry different VLANs: {}", lag.name, detail.join(", ")));
+        }
+    }
+    warnings
+}
+
+fn differ(sets: &[String]) -> bool {
+    sets.iter().any(|s| s != &sets[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOND: &str = "\
+Bonding Mode: IEEE 802.3ad Dynamic link aggregation
+MII Status: up
+
+Slave Interface: eth0
+MII Status: up
+Aggregator ID: 1
+details actor lacp pdu:
+    system priority: 65535
+    system mac address: 52:54:00:aa:bb:cc
+    port key: 15
+    port priority: 255
+    port number: 1
+details partner lacp pdu:
+    system priority: 32768
+    system mac address: 00:1c:73:11:22:33
+    oper key: 1001
+    port priority: 32768
+    port number: 17
+
+Slave Interface: eth1
+MII Status: up
+Aggregator ID: 2
+details actor lacp pdu:
+    system priority: 65535
+    system mac address: 52:54:00:aa:bb:cc
+    port key: 15
+    port priority: 255
+    port number: 2
+details partner lacp pdu:
+    system priority: 65535
+    system mac address: 00:00:00:00:00:00
+    oper key: 1
+    port priority: 255
+    port number: 1
+";
+
+    #[test]
+    fn test_lag_kind() {
+        assert_eq!(lag_kind("bond"), Some(LagKind::Bond));
+        assert_eq!(lag_kind("team"), Some(LagKind::Team));
+        for kind in ["bridge", "openvswitch", "vrf", "macvlan"] {
+            assert_eq!(lag_kind(kind), None);
+        }
+    }
+
+    #[test]
+    fn test_parse_bonding() {
+        let partners = parse_bonding(BOND);
+        assert_eq!(partners.len(), 1);
+        assert_eq!(
+            partners["eth0"],
+            LacpPartner {
+                system: "00:1c:73:11:22:33".parse().unwrap(),
+                key: 1001,
+                port: 17,
+            }
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use libc::{uname, utsname};
 use std::fs;
+use std::path::Path;
+use std::future::Future;
 use std::io;
 use std::fmt;
 use std::mem;
@@ -7,11 +9,24 @@ use std::os::fd::OwnedFd;
 use std::os::fd::FromRawFd;
 use std::os::fd::AsRawFd;
 use std::str::FromStr;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use log::{debug, error};
 use tokio::io::unix::AsyncFd;
 
 pub mod packet;
+pub mod lag;
+pub mod tui;
+pub mod discovery;
+pub mod l3;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod ring;
+pub mod netlink;
+
+use ring::{RingConfig, RxRing};
 
 pub fn hostname() -> String {
     let mut name = utsname {
@@ -26,15 +41,16 @@ pub fn hostname() -> String {
 
     unsafe {
         if uname(pname) == 0 {
-            String::from_utf8_lossy(std::mem::transmute(&name.nodename[..])).trim_end_matches('\0').to_string()
+            String::from_utf8_lossy(std::mem::transmute::<&[i8], &[u8]>(&name.nodename[..])).trim_end_matches('\0').to_string()
         } else {
             String::from("")
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub enum OperState {
+    #[default]
     Up,
     Down,
 }
@@ -54,12 +70,6 @@ impl FromStr for OperState {
     }
 }
 
-impl Default for OperState {
-    fn default() -> Self {
-        OperState::Up
-    }
-}
-
 #[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct MacAddress(u8, u8, u8, u8, u8, u8);
 
@@ -103,9 +113,11 @@ pub struct Interface {
     pub name: String,
     pub mac: MacAddress,
     pub state: OperState,
+    /// Bond or team device this NIC is enslaved to
+    pub master: Option<String>,
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Clone)]
 pub struct Node {
     pub host: String,
     pub nic: Interface,
@@ -113,28 +125,178 @@ pub struct Node {
 
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} {} {}", self.host, self.nic.name, self.nic.mac)
+        match &self.nic.master {
+            Some(master) => write!(f, "{} {}({}) {}", self.host, master, self.nic.name, self.nic.mac),
+            None => write!(f, "{} {} {}", self.host, self.nic.name, self.nic.mac),
+        }
     }
 }
 
+/// Answers of a peer to probes on one VLAN.
+///
+/// Probe frames of the topology protocol ask the peer for its addresses, with
+/// `--l3` they are then checked with ARP or NDP and echo requests, see `l3`.
+#[derive(Debug, Default, Clone)]
+pub struct VlanProbe {
+    /// Addresses the peer reports on this VLAN
+    pub addrs: Vec<IpAddr>,
+    /// Addresses of the peer which answered ARP, NDP or echo requests on this VLAN
+    pub reached: BTreeSet<IpAddr>,
+    /// Minimal round trip time of probe frames and echo requests
+    pub rtt: Option<Duration>,
+    /// Largest frame payload which reached the peer
+    pub frame: u16,
+    /// Largest IP packet answered without fragmenting
+    pub mtu: Option<u16>,
+}
+
+impl fmt::Display for VlanProbe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "L2 ok")?;
+        if !self.reached.is_empty() {
+            let addrs: Vec<_> = self.reached.iter().map(|a| a.to_string()).collect();
+            write!(f, ", L3 {}", addrs.join(" "))?;
+        }
+        let reported: Vec<_> = self.addrs.iter().filter(|a| !self.reached.contains(a)).map(|a| a.to_string()).collect();
+        if !reported.is_empty() {
+            write!(f, ", peer reports {}", reported.join(" "))?;
+        }
+        if let Some(mtu) = self.mtu {
+            write!(f, ", MTU {mtu}")?;
+        }
+        if let Some(rtt) = self.rtt {
+            write!(f, ", RTT {:.3}ms", rtt.as_secs_f64() * 1000.0)?;
+        }
+        if self.frame > 0 {
+            write!(f, ", L2 frames of {} bytes pass", self.frame)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
 pub struct Topo {
     pub connection: HashMap<(Node, Node), Vec<u16>>,
+    /// Probe answers of each VLAN of a connection
+    pub probes: HashMap<(Node, Node), BTreeMap<u16, VlanProbe>>,
+}
+
+impl Topo {
+    /// Record that `peer` answered on local `nic`
+    pub fn add(&mut self, host: &str, nic: &Interface, peer: packet::Peer) {
+        let vlan = peer.vlan;
+        self.connection
+            .entry(link(host, nic, peer))
+            .and_modify(|v| { v.push(vlan); v.sort(); v.dedup(); })
+            .or_insert(vec![vlan]);
+    }
+
+    /// Update state of local `nic` in its links
+    pub fn update_nic(&mut self, nic: &Interface) {
+        fn rekey<V>(map: &mut HashMap<(Node, Node), V>, nic: &Interface) {
+            let keys: Vec<_> = map.keys().filter(|(me, _)| me.nic.index == nic.index).cloned().collect();
+            for key in keys {
+                if let Some(v) = map.remove(&key) {
+                    let (mut me, peer) = key;
+                    me.nic = nic.clone();
+                    map.insert((me, peer), v);
+                }
+            }
+        }
+        rekey(&mut self.connection, nic);
+        rekey(&mut self.probes, nic);
+    }
+
+    /// Forget links of removed local NIC
+    pub fn remove_nic(&mut self, ifindex: u32) {
+        self.connection.retain(|(me, _), _| me.nic.index != ifindex);
+        self.probes.retain(|(me, _), _| me.nic.index != ifindex);
+    }
+
+    /// Record `message` from `peer` on local `nic`
+    pub fn record(&mut self, host: &str, nic: &Interface, peer: packet::Peer, message: packet::Message) {
+        match message {
+            packet::Message::ProbeReply(probe) => self.add_probe(host, nic, peer, &probe),
+            packet::Message::Reached(answer) => self.add_reached(host, nic, peer, &answer),
+            _ => self.add(host, nic, peer),
+        }
+    }
+
+    /// Answers of `peer` on local `nic` in the VLAN of `peer`
+    fn answers(&mut self, host: &str, nic: &Interface, peer: packet::Peer) -> &mut VlanProbe {
+        let vlan = peer.vlan;
+        self.probes
+            .entry(link(host, nic, peer))
+            .or_default()
+            .entry(vlan)
+            .or_default()
+    }
+
+    /// Record answer to `probe` from `peer` on local `nic`
+    pub fn add_probe(&mut self, host: &str, nic: &Interface, peer: packet::Peer, probe: &packet::Probe) {
+        let answer = self.answers(host, nic, peer);
+        answer.addrs = probe.addrs.clone();
+        answer.frame = answer.frame.max(probe.size);
+        answer.rtt = min_rtt(answer.rtt, probe.rtt);
+    }
+
+    /// Record L3 `answer` from `peer` on local `nic`
+    pub fn add_reached(&mut self, host: &str, nic: &Interface, peer: packet::Peer, answer: &l3::Answer) {
+        let probe = self.answers(host, nic, peer);
+        match answer {
+            l3::Answer::Neighbor(addr) => {
+                probe.reached.insert(*addr);
+            },
+            l3::Answer::Echo { addr, size, rtt } => {
+                probe.reached.insert(*addr);
+                probe.mtu = probe.mtu.max(Some(*size));
+                probe.rtt = min_rtt(probe.rtt, Some(*rtt));
+            },
+        }
+    }
+}
+
+fn min_rtt(old: Option<Duration>, new: Option<Duration>) -> Option<Duration> {
+    match (old, new) {
+        (Some(old), Some(new)) => Some(old.min(new)),
+        (old, new) => old.or(new),
+    }
+}
+
+fn link(host: &str, nic: &Interface, peer: packet::Peer) -> (Node, Node) {
+    let me = Node {
+        host: host.to_string(),
+        nic: nic.clone(),
+    };
+    let p = Node {
+        host: peer.host,
+        nic: Interface {
+            name: peer.nic,
+            mac: peer.mac.octets().into(),
+            ..Default::default()
+        }
+    };
+    (me, p)
 }
 
 impl fmt::Display for Topo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for ((me, peer), vlans) in &self.connection {
-            let _ = write!(f, "{} <-> {} VLAN: {}", me, peer, show_vlan(&vlans));
+            let down = if me.nic.state == OperState::Down { " [DOWN]" } else { "" };
+            writeln!(f, "{} <-> {} VLAN: {}{}", me, peer, show_vlan(vlans), down)?;
+            for (vlan, answer) in self.probes.get(&(me.clone(), peer.clone())).into_iter().flatten() {
+                writeln!(f, "    VLAN {vlan}: {answer}")?;
+            }
         }
         Ok(())
     }
 }
 
-fn show_vlan(vlans: &Vec<u16>) -> String {
+pub fn show_vlan(vlans: &[u16]) -> String {
     if vlans.is_empty() {
         return String::new();
     }
-    let mut start = vlans.get(0);
+    let mut start = vlans.first();
     let mut vlan_str = String::new();
     let mut range = false;
     let len = vlans.len();
@@ -166,36 +328,142 @@ fn show_vlan(vlans: &Vec<u16>) -> String {
     vlan_str
 }
 
+const NIC_PATH: &str = "/sys/class/net/";
+
 pub fn get_physical_nics() -> Vec<Interface> {
-    const NIC_PATH: &str = "/sys/class/net/";
     let mut nics: Vec<_> = vec![];
 
     if let Ok(dir) = fs::read_dir(NIC_PATH) {
-        for entry in dir {
-            let entry = entry.unwrap();
-            if let Ok(path) = fs::read_link(entry.path()) {
-                if !path.starts_with("../../devices/virtual/") {
-                    let ifindex = fs::read_to_string(entry.path().join("ifindex")).unwrap();
-                    let mac = fs::read_to_string(entry.path().join("address")).unwrap();
-                    let oper = fs::read_to_string(entry.path().join("operstate")).unwrap();
-
-                    let interface = Interface {
-                        index: ifindex.trim().parse().unwrap(),
-                        name: entry.file_name().into_string().unwrap(),
-                        state: oper.parse::<OperState>().unwrap(),
-                        mac: mac.parse::<MacAddress>().unwrap(),
-                    };
-                    nics.push(interface);
-                }
+        for entry in dir.flatten() {
+            if let Some(interface) = get_physical_nic(&entry.file_name().to_string_lossy()) {
+                nics.push(interface);
             }
         }
     }
     nics
 }
 
+/// Read NIC `name` from sysfs, `None` if it is virtual or already removed
+pub fn get_physical_nic(name: &str) -> Option<Interface> {
+    let path = Path::new(NIC_PATH).join(name);
+    if fs::read_link(&path).ok()?.starts_with("../../devices/virtual/") {
+        return None;
+    }
+    let ifindex = fs::read_to_string(path.join("ifindex")).ok()?;
+    let mac = fs::read_to_string(path.join("address")).ok()?;
+    let oper = fs::read_to_string(path.join("operstate")).ok()?;
+    let master = fs::read_link(path.join("master"))
+        .ok()
+        .and_then(|m| m.file_name().map(|m| m.to_string_lossy().to_string()));
+
+    Some(Interface {
+        index: ifindex.trim().parse().ok()?,
+        name: name.to_string(),
+        // `unknown`, `dormant` and so on are not usable
+        state: oper.parse::<OperState>().unwrap_or(OperState::Down),
+        mac: mac.parse::<MacAddress>().ok()?,
+        master,
+    })
+}
+
+/// Addresses of `vlan` on `nic`, i.e. addresses on the NIC itself (or its bond)
+/// for untagged, or on VLAN sub-interfaces like `eth0.120` for tagged VLAN.
+pub fn vlan_addrs(nic: &Interface, vlan: u16) -> Vec<IpAddr> {
+    let parents: Vec<&str> = std::iter::once(nic.name.as_str())
+        .chain(nic.master.as_deref())
+        .collect();
+    let names: Vec<String> = if vlan == 0 {
+        parents.iter().map(|p| p.to_string()).collect()
+    } else {
+        // VLAN device name | VLAN ID | parent device
+        fs::read_to_string("/proc/net/vlan/config")
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let v: Vec<_> = line.split('|').map(|s| s.trim()).collect();
+                match v[..] {
+                    [name, id, parent] if id == vlan.to_string() && parents.contains(&parent) => Some(name.to_string()),
+                    _ => None,
+                }
+            })
+            .collect()
+    };
+
+    let mut addrs = vec![];
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    unsafe {
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            return addrs;
+        }
+        let mut ifa = ifaddrs;
+        while !ifa.is_null() {
+            let addr = (*ifa).ifa_addr;
+            let name = CStr::from_ptr((*ifa).ifa_name).to_string_lossy();
+            if !addr.is_null() && names.iter().any(|n| *n == name) {
+                match (*addr).sa_family as i32 {
+                    libc::AF_INET => {
+                        let sa = &*(addr as *const libc::sockaddr_in);
+                        addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr))));
+                    },
+                    libc::AF_INET6 => {
+                        let sa = &*(addr as *const libc::sockaddr_in6);
+                        addrs.push(IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr)));
+                    },
+                    _ => (),
+                }
+            }
+            ifa = (*ifa).ifa_next;
+        }
+        libc::freeifaddrs(ifaddrs);
+    }
+    addrs
+}
+
+/// Metadata of a received frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecvMeta {
+    /// ifindex where the frame is received
+    pub ifindex: u32,
+    /// Frame length on wire
+    pub len: usize,
+    /// Bytes copied into the receive buffer, shorter than `len` when the buffer
+    /// or the snap length of the ring is
+    pub caplen: usize,
+    /// VLAN TCI which is stripped from frame by NIC or kernel. Kernel clears it
+    /// before delivering to sockets of a specific protocol, it is only kept for `ETH_P_ALL`.
+    pub vlan_tci: Option<u16>,
+    /// Frame is sent by this host, only `ETH_P_ALL` sockets see them
+    pub outgoing: bool,
+    pub timestamp: Option<SystemTime>,
+}
+
+/// Raw L2 packet I/O used by discovery.
+///
+/// `Socket` is the AF_PACKET implementation, `sim::SimSocket` is attached to
+/// a simulated fabric for tests.
+pub trait PacketIo {
+    /// Receive packets from `ifindex` only
+    fn bind(&self, ifindex: u32) -> io::Result<()>;
+
+    fn set_promiscuous(&self, promisc: bool, ifindex: u32) -> io::Result<()>;
+
+    /// Receive a packet into `buf`
+    fn recv(&mut self, buf: &mut [u8]) -> impl Future<Output = io::Result<RecvMeta>> + Send;
+
+    /// Send `buf` out of `ifindex`
+    fn send(&mut self, buf: &[u8], ifindex: u32) -> impl Future<Output = io::Result<isize>> + Send;
+
+    /// Addresses of the local host in `vlan` on `nic`, reported to peers and
+    /// the sources of L3 probes
+    fn addrs(&self, nic: &Interface, vlan: u16) -> Vec<IpAddr> {
+        vlan_addrs(nic, vlan)
+    }
+}
+
 pub struct Socket {
     fd: AsyncFd<OwnedFd>,
     proto: u16,
+    ring: Option<RxRing>,
 }
 
 impl Socket {
@@ -207,15 +475,46 @@ impl Socket {
                     let flag = libc::fcntl(fd, libc::F_GETFL, 0);
                     libc::fcntl(fd, libc::F_SETFL, flag | libc::O_NONBLOCK);
                 }
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                // VLAN tag stripped by NIC and receive time are passed in control message
+                setsockopt(&fd, libc::SOL_PACKET, libc::PACKET_AUXDATA, &1i32)?;
+                setsockopt(&fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, &1i32)?;
                 Ok(Socket {
-                    fd: AsyncFd::new(unsafe { OwnedFd::from_raw_fd(fd) })?,
+                    fd: AsyncFd::new(fd)?,
                     proto,
+                    ring: None,
                 })
             },
         }
     }
 
-    pub fn bind(&self, ifindex: u32) -> io::Result<()> {
+    /// Receive with a PACKET_RX_RING mmap'd ring of TPACKET_V3 blocks instead of
+    /// one `recvmsg` per frame. Frames are read from a block without syscall
+    /// until all frames of the block are consumed.
+    pub fn with_rx_ring(proto: u16, config: RingConfig) -> io::Result<Self> {
+        let mut sock = Socket::new(proto)?;
+        sock.ring = Some(RxRing::new(sock.fd.get_ref(), config)?);
+        Ok(sock)
+    }
+}
+
+fn setsockopt<T>(fd: &OwnedFd, level: i32, name: i32, value: &T) -> io::Result<()> {
+    if unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        ) != 0
+    } {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl PacketIo for Socket {
+    fn bind(&self, ifindex: u32) -> io::Result<()> {
         let sa = libc::sockaddr_ll {
             sll_family: libc::AF_PACKET as libc::sa_family_t,
             sll_protocol: self.proto.to_be(),
@@ -239,7 +538,7 @@ impl Socket {
         }
     }
 
-    pub fn set_promiscuous(&self, promisc: bool, ifindex: u32) -> io::Result<()> {
+    fn set_promiscuous(&self, promisc: bool, ifindex: u32) -> io::Result<()> {
         let req = libc::packet_mreq {
             mr_ifindex: ifindex as i32,
             mr_type: libc::PACKET_MR_PROMISC as u16,
@@ -266,11 +565,16 @@ impl Socket {
         Ok(())
     }
 
-    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<u32> {
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<RecvMeta> {
         loop {
             let mut guard = self.fd.readable().await?;
-            match guard.try_io(|inner| recv(inner.get_ref().as_raw_fd(), buf)) {
-                Ok(ifindex) => return ifindex,
+            let result = match self.ring {
+                // readiness is cleared only if no block is ready
+                Some(ref mut ring) => guard.try_io(|_| ring.next(buf).ok_or(io::Error::from(io::ErrorKind::WouldBlock))),
+                None => guard.try_io(|inner| recv(inner.get_ref().as_raw_fd(), buf)),
+            };
+            match result {
+                Ok(meta) => return meta,
                 Err(_would_block) => {
                     continue
                 },
@@ -278,7 +582,7 @@ impl Socket {
         }
     }
 
-    pub async fn send(&mut self, buf: &[u8], ifindex: u32) -> io::Result<isize> {
+    async fn send(&mut self, buf: &[u8], ifindex: u32) -> io::Result<isize> {
         let sa = libc::sockaddr_ll {
             sll_family: libc::AF_PACKET as libc::sa_family_t,
             sll_protocol: self.proto.to_be(),
@@ -298,28 +602,58 @@ impl Socket {
     }
 }
 
-fn recv(fd: i32, buf: &mut [u8]) -> io::Result<u32> {                                                                                                                         
+fn recv(fd: i32, buf: &mut [u8]) -> io::Result<RecvMeta> {
     let mut sa: libc::sockaddr_ll = unsafe { mem::zeroed() };
-    let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // room for PACKET_AUXDATA and SCM_TIMESTAMPNS
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &raw mut sa as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control);
     debug!("recv from socket {fd}");
     unsafe {
-        match libc::recvfrom(fd,
-                       buf.as_mut_ptr() as *mut libc::c_void,
-                       buf.len(),
-                       0,   // flags
-                       &raw mut sa as *mut libc::sockaddr,
-                       &mut addr_len) {
-                       //std::ptr::null_mut(),
-                       //std::ptr::null_mut()) {
+        // MSG_TRUNC returns the real length of frame even if buf is shorter
+        match libc::recvmsg(fd, &mut msg, libc::MSG_TRUNC) {
             -1 => {
                 let err = io::Error::last_os_error(); // io::ErrorKind::WouldBlock
                 //error!("recv failed: {}, kind: {:?}", err, err.kind());
                 Err(err)
             },
             len => {
-                let iface_index = sa.sll_ifindex as u32;
-                debug!("fd({fd}) len: {len}, from nic: {iface_index}");
-                Ok(iface_index)
+                let mut meta = RecvMeta {
+                    ifindex: sa.sll_ifindex as u32,
+                    len: len as usize,
+                    caplen: (len as usize).min(buf.len()),
+                    outgoing: sa.sll_pkttype == libc::PACKET_OUTGOING,
+                    ..Default::default()
+                };
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                while !cmsg.is_null() {
+                    let data = libc::CMSG_DATA(cmsg);
+                    match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                        (libc::SOL_PACKET, libc::PACKET_AUXDATA) => {
+                            let aux = (data as *const libc::tpacket_auxdata).read_unaligned();
+                            if aux.tp_status & libc::TP_STATUS_VLAN_VALID != 0 {
+                                meta.vlan_tci = Some(aux.tp_vlan_tci);
+                            }
+                        },
+                        (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                            let ts = (data as *const libc::timespec).read_unaligned();
+                            meta.timestamp = Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+                        },
+                        _ => (),
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                }
+                debug!("fd({fd}) {meta:?}");
+                Ok(meta)
             }
         }
     }
@@ -1,20 +1,27 @@
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio::time::{sleep, Duration};
-use pnet::datalink;
 use clap::Parser;
-use log::{info, debug};
+use log::{info, error};
 
-use topology::packet;
 use topology::packet::Peer;
+use topology::packet::Message;
+use topology::discovery::{probe, recv_packet, send_packet, Nics};
+use topology::netlink::{self, LinkEvent, LinkMonitor};
 use topology::hostname;
 use topology::get_physical_nics;
 use topology::Interface;
 use topology::OperState;
 use topology::Topo;
-use topology::Node;
 use topology::Socket;
+use topology::ring::RingConfig;
+use topology::lag;
+use topology::lag::Lag;
+use topology::tui;
 
 use std::collections::HashMap;
+use std::io::{self, IsTerminal};
+use std::sync::{Arc, RwLock};
 
 
 #[derive(Parser, Debug)]
@@ -26,11 +33,37 @@ struct Opt {
     /// Nics used to detect, default are all UP nic
     #[arg(short, long)]
     interface: Option<Vec<String>>,
+
+    /// Print plain text instead of interactive UI, it is also used when stdout is not a terminal
+    #[arg(short, long)]
+    plain: bool,
+
+    /// Probe each VLAN where a peer answers with frames of `--frame-size`, for its
+    /// RTT and the addresses it reports
+    #[arg(long)]
+    probe: bool,
+
+    /// Frame payload sizes of `--probe`, the largest one reaching the peer is reported
+    #[arg(long, value_delimiter = ',', default_value = "1500,9000")]
+    frame_size: Vec<u16>,
+
+    /// Check the addresses peers report with ARP and NDP, then the MTU to them with
+    /// echo requests of `--mtu` which are not fragmented. Implies `--probe`.
+    #[arg(long)]
+    l3: bool,
+
+    /// IP packet sizes of `--l3` echo requests, the largest one answered is reported
+    #[arg(long, value_delimiter = ',', default_value = "1500,9000")]
+    mtu: Vec<u16>,
+
+    /// Receive with TPACKET_V3 mmap'd ring instead of one syscall per frame
+    #[arg(long)]
+    ring: bool,
 }
 
 fn vlan_range(s: &str) -> Result<(u16, u16), String> {
     match s.split_once('-') {
-        None => Err(format!("Vlan range format error. Should be like `2-4`")),
+        None => Err("Vlan range format error. Should be like `2-4`".to_string()),
         Some((start, end)) => {
             let s: u16 = start.parse().map_err(|_| format!("`{start}` is not a number"))?;
             let e: u16 = end.parse().map_err(|_| format!("`{end}` is not a number"))?;
@@ -44,87 +77,136 @@ fn vlan_range(s: &str) -> Result<(u16, u16), String> {
 }
 
 async fn show_topo(
-    mut rx: mpsc::UnboundedReceiver<(u32, Peer)>,
+    mut rx: mpsc::UnboundedReceiver<(u32, Peer, Message)>,
+    mut links: mpsc::UnboundedReceiver<LinkEvent>,
     host: String,
-    nics: Vec<Interface>,
+    mut nics: Vec<Interface>,
+    mut lags: Vec<Lag>,
 ) {
-    let mut topo = Topo {
-        connection: HashMap::new(),
-    };
-    while let Some((ifindex, peer)) = rx.recv().await {
-        info!("TOPO: updating for {ifindex} connect {peer:?}");
-        let interface: Vec<_> = nics.iter().filter(|n| n.index == ifindex).collect();
-        if interface.len() == 0 {
-            continue;
-        }
-        let me = Node {
-            host: host.clone(),
-            nic: interface[0].clone(),
-        };
-        let p = Node {
-            host: peer.host,
-            nic: Interface {
-                name: peer.nic,
-                mac: peer.mac.octets().into(),
-                ..Default::default()
+    let mut topo = Topo::default();
+    let terminal = io::stdout().is_terminal();
+    loop {
+        tokio::select! {
+            Some((ifindex, peer, message)) = rx.recv() => {
+                info!("TOPO: updating for {ifindex} connect {peer:?}");
+                let Some(interface) = nics.iter().find(|n| n.index == ifindex) else {
+                    continue;
+                };
+                topo.record(&host, interface, peer, message);
+                info!("TOPO: updated for {ifindex}");
             }
-        };
-
-        topo.connection
-            .entry((me, p))
-            .and_modify(|v| { v.push(peer.vlan); v.sort(); v.dedup(); })
-            .or_insert(vec![peer.vlan]);
-        info!("TOPO: updated for {ifindex}");
-        if rx.len() == 0 {
+            Some(event) = links.recv() => {
+                info!("TOPO: {event:?}");
+                if netlink::apply(&event, &mut nics, &mut topo) {
+                    lags = lag::get_lags(&nics);
+                    info!("{lags:?}");
+                }
+            }
+            else => break,
+        }
+        if rx.is_empty() {
             sleep(Duration::from_millis(300)).await;
-            if rx.len() != 0 {
+            if !rx.is_empty() {
                 // read message from channel
                 continue;
             }
-            print!("\x1b[2J"); // clear screen with new line
-            print!("\x1b[H");  // move cursor to left-top
+            if terminal {
+                print!("\x1b[2J"); // clear screen with new line
+                print!("\x1b[H");  // move cursor to left-top
+            }
             println!("{:<24} {:^12} {:>24}", "local", "<-->", "Peer");
             println!("{topo}");
+            for warning in lag::check(&topo, &lags) {
+                println!("WARNING: {warning}");
+            }
         }
     }
 }
 
-async fn recv_packet(
-    packet: mpsc::UnboundedSender<(u32, Peer)>,
-    topo: mpsc::UnboundedSender<(u32, Peer)>,
-    ifindex: u32,
-) {
-    let mut sock = Socket::new(libc::ETH_P_AARP as u16).unwrap();
-    sock.set_promiscuous(true, ifindex).unwrap();
-    sock.bind(ifindex).unwrap();
-    loop {
-        let mut buf: [u8; 1024] = [0; 1024];
-        let ifindex = sock.recv(&mut buf).await.unwrap() as u32;
-        if let Some((peer, request)) = packet::parse(&buf) {
-            debug!("Receive {} at {} from {peer:?}", if request {"REQUEST"} else {"REPLY"}, ifindex);
-            if request {
-                packet.send((ifindex, peer.clone())).unwrap();
-            }
-            topo.send((ifindex, peer)).unwrap();
-          }
+/// Receive tasks of NICs which are UP
+struct Receivers {
+    ptx: mpsc::UnboundedSender<(u32, Peer, Message)>,
+    ttx: mpsc::UnboundedSender<(u32, Peer, Message)>,
+    vlans: Vec<(u16, u16)>,
+    sizes: Vec<u16>,
+    /// Echo request sizes of L3 probing, `None` without it
+    l3: Option<Vec<u16>>,
+    ring: bool,
+    tasks: HashMap<u32, JoinHandle<()>>,
+}
+
+impl Receivers {
+    /// Start receiving on `nic` and send a burst of probes out of it
+    fn start(&mut self, nic: &Interface) {
+        if self.tasks.contains_key(&nic.index) {
+            return;
+        }
+        // all protocols for VLAN TCI stripped by NIC to be kept, see `RecvMeta::vlan_tci`
+        let sock = if self.ring {
+            Socket::with_rx_ring(libc::ETH_P_ALL as u16, RingConfig::default())
+        } else {
+            Socket::new(libc::ETH_P_ALL as u16)
+        };
+        match sock {
+            Ok(sock) => {
+                let task = tokio::spawn(recv_packet(sock, self.ptx.clone(), self.ttx.clone(), nic.index, self.sizes.clone(), self.l3.clone()));
+                self.tasks.insert(nic.index, task);
+                probe(&self.ptx, std::slice::from_ref(nic), &self.vlans);
+            },
+            Err(e) => error!("{}: failed to create socket: {e}", nic.name),
+        }
+    }
+
+    fn stop(&mut self, ifindex: u32) {
+        if let Some(task) = self.tasks.remove(&ifindex) {
+            task.abort();
+        }
     }
 }
 
-async fn send_packet(mut rx: mpsc::UnboundedReceiver<(u32, Peer)>, host: String, nics: Vec<Interface>) {
-    let mut sock = Socket::new(libc::ETH_P_AARP as u16).unwrap();
-    while let Some((ifindex, peer)) = rx.recv().await {
-        let interface: Vec<_> = nics.iter().filter(|n| n.index == ifindex).collect();
-        if interface.len() == 0 {
-            continue;
+/// Start and stop receivers as NICs are added, removed, go up or down
+async fn watch_links(
+    mut monitor: LinkMonitor,
+    mut receivers: Receivers,
+    nics: Nics,
+    interface: Option<Vec<String>>,
+    links: mpsc::UnboundedSender<LinkEvent>,
+) {
+    loop {
+        let events = match monitor.next().await {
+            Ok(events) => events,
+            Err(e) => {
+                // e.g. ENOBUFS when link messages overflow the socket, they are lost
+                error!("netlink: {e}, dumping links again");
+                let known = nics.read().unwrap().clone();
+                match netlink::dump(&known) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        error!("netlink: failed to dump links: {e}");
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                }
+            }
+        };
+        for event in events {
+            match &event {
+                LinkEvent::Changed(nic) => {
+                    if interface.as_ref().is_some_and(|i| !i.contains(&nic.name)) {
+                        continue;
+                    }
+                    if nic.state == OperState::Up {
+                        receivers.start(nic);
+                    } else {
+                        receivers.stop(nic.index);
+                    }
+                },
+                LinkEvent::Removed(ifindex) => receivers.stop(*ifindex),
+                LinkEvent::Lag(_) => (),
+            }
+            netlink::update_nics(&event, &mut nics.write().unwrap());
+            let _ = links.send(event);
         }
-        let mut buf = [0u8; 256];
-        let vlan = peer.vlan;
-        let mac = peer.mac;
-        packet::builder(&mut buf, &interface[0], mac, vlan, &host);
-        debug!("{}: Send with VLAN {vlan} MAC({mac})", interface[0].name);
-        sock.set_promiscuous(true, ifindex).unwrap();
-        //sock.bind(ifindex).unwrap();
-        let _len = sock.send(&buf, ifindex).await;
     }
 }
 
@@ -145,44 +227,65 @@ async fn main() {
     info!("{name:?}");
 
     let nics = get_physical_nics();
-
-    let nics = if opt.interface.is_some() {
+    let nics: Vec<_> = if opt.interface.is_some() {
         nics.into_iter().filter(|n| opt.interface.as_ref().unwrap().contains(&n.name)).collect()
     } else {
         nics
     };
-    let nics: Vec<_> = nics
-        .into_iter()
-        .filter(|n| if n.state == OperState::Up { true } else { println!("{} is not UP!", n.name); false})
-        .collect();
+    // Probe via every member of a bond or team, results are reported per aggregation
+    let lags = lag::get_lags(&nics);
+    info!("{lags:?}");
+    for nic in nics.iter().filter(|n| n.state != OperState::Up) {
+        println!("{} is not UP!", nic.name);
+    }
 
     info!("{nics:?}");
+    let up: Vec<_> = nics.iter().filter(|n| n.state == OperState::Up).cloned().collect();
+    let (ptx, prx) = mpsc::unbounded_channel::<(u32, Peer, Message)>();
+    let (ttx, trx) = mpsc::unbounded_channel::<(u32, Peer, Message)>();
+    let (progress_tx, progress_rx) = mpsc::unbounded_channel::<u32>();
+    let (links_tx, links_rx) = mpsc::unbounded_channel::<LinkEvent>();
+    let shared: Nics = Arc::new(RwLock::new(nics.clone()));
+
     let mut handlers: Vec<_> = Vec::new();
-    if !nics.is_empty() {
-        let (ptx, prx) = mpsc::unbounded_channel::<(u32, Peer)>();
-        let (ttx, trx) = mpsc::unbounded_channel::<(u32, Peer)>();
+    let sock = Socket::new(libc::ETH_P_AARP as u16).unwrap();
+    let handler = tokio::spawn(send_packet(sock, prx, progress_tx, name.clone(), shared.clone()));
+    handlers.push(handler);
 
-        let handler = tokio::spawn(show_topo(trx, name.clone(), nics.clone()));
-        handlers.push(handler);
-        for nic in &nics {
-            let handler = tokio::spawn(recv_packet(ptx.clone(), ttx.clone(), nic.index));
+    let mut receivers = Receivers {
+        ptx: ptx.clone(),
+        ttx,
+        vlans: vlans.clone(),
+        sizes: if opt.probe || opt.l3 { opt.frame_size.clone() } else { vec![] },
+        l3: opt.l3.then(|| opt.mtu.clone()),
+        ring: opt.ring,
+        tasks: HashMap::new(),
+    };
+    for nic in &up {
+        receivers.start(nic);
+    }
+    match LinkMonitor::new() {
+        Ok(monitor) => {
+            let handler = tokio::spawn(watch_links(monitor, receivers, shared.clone(), opt.interface, links_tx));
             handlers.push(handler);
-        }
-        let handler = tokio::spawn(send_packet(prx, name.clone(), nics.clone()));
-        handlers.push(handler);
+        },
+        Err(e) => error!("Failed to watch NIC changes: {e}"),
+    }
 
-        for nic in nics {
-            for (start, end) in &vlans {
-                for vlan in *start..=*end {
-                    let peer = Peer {
-                        mac: datalink::MacAddr::broadcast(),
-                        vlan: vlan,
-                        ..Default::default()
-                    };
-                    ptx.send((nic.index, peer)).unwrap();
-                }
-            }
+    if opt.plain || !io::stdout().is_terminal() {
+        let handler = tokio::spawn(show_topo(trx, links_rx, name.clone(), nics, lags));
+        handlers.push(handler);
+    } else {
+        let total = vlans.iter().map(|(start, end)| (end - start) as usize + 1).sum();
+        let app = tui::App::new(name.clone(), nics, lags, total);
+        let reprobe = || {
+            let nics: Vec<_> = shared.read().unwrap().iter().filter(|n| n.state == OperState::Up).cloned().collect();
+            probe(&ptx, &nics, &vlans);
+        };
+        if let Err(e) = tui::run(app, trx, progress_rx, links_rx, reprobe).await {
+            eprintln!("Failed to run terminal UI: {e}");
         }
+        return;
     }
 
     for handler in handlers {
@@ -0,0 +1,363 @@
+//! Link events from rtnetlink, used to follow NICs which are added, removed
+//! or change operational state while discovery is running.
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use log::debug;
+use tokio::io::unix::AsyncFd;
+
+use crate::lag::lag_kind;
+use crate::{get_physical_nic, Interface, OperState, Topo};
+
+const NLMSG_HDRLEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const NLMSG_ERROR: u16 = libc::NLMSG_ERROR as u16;
+const NLMSG_DONE: u16 = libc::NLMSG_DONE as u16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// NIC is added or its state is changed
+    Changed(Interface),
+    /// NIC with the ifindex is removed
+    Removed(u32),
+    /// Bond or team device is added, removed or changed
+    Lag(String),
+}
+
+/// Link message parsed from RTM_NEWLINK or RTM_DELLINK
+#[derive(Debug, PartialEq, Eq)]
+pub struct Link {
+    pub index: u32,
+    pub name: String,
+    pub state: OperState,
+    pub removed: bool,
+    /// IFLA_INFO_KIND, like `bond` or `team`, `None` for physical NICs
+    pub kind: Option<String>,
+}
+
+/// Socket subscribed to RTMGRP_LINK
+pub struct LinkMonitor {
+    fd: AsyncFd<OwnedFd>,
+}
+
+fn socket(flags: libc::c_int) -> io::Result<OwnedFd> {
+    match unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC | flags, libc::NETLINK_ROUTE) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+/// Send RTM_GETLINK for link `index`, or for all links with NLM_F_DUMP
+fn get_link(fd: &OwnedFd, index: u32, flags: libc::c_int) -> io::Result<()> {
+    let mut request = [0u8; NLMSG_HDRLEN + IFINFOMSG_LEN];
+    request[0..4].copy_from_slice(&((NLMSG_HDRLEN + IFINFOMSG_LEN) as u32).to_ne_bytes());
+    request[4..6].copy_from_slice(&libc::RTM_GETLINK.to_ne_bytes());
+    request[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | flags) as u16).to_ne_bytes());
+    request[NLMSG_HDRLEN + 4..NLMSG_HDRLEN + 8].copy_from_slice(&index.to_ne_bytes());
+    if unsafe { libc::send(fd.as_raw_fd(), request.as_ptr() as *const libc::c_void, request.len(), 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recv(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+    match unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        len => Ok(len as usize),
+    }
+}
+
+/// Kind of the link named `name` from IFLA_INFO_KIND, like `bond`, `team`,
+/// `bridge` or `openvswitch`
+pub fn link_kind(name: &str) -> io::Result<Option<String>> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let index = match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => return Err(io::Error::last_os_error()),
+        index => index,
+    };
+    let fd = socket(0)?;
+    get_link(&fd, index, 0)?;
+    let mut buf = vec![0u8; 32768];
+    let len = recv(&fd, &mut buf)?;
+    match parse(&buf[..len]).into_iter().find(|link| link.index == index) {
+        Some(link) => Ok(link.kind),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no link message of {name:?}"))),
+    }
+}
+
+/// Events of all links from a RTM_GETLINK dump, as `LinkMonitor::next` reports
+/// them, and `Removed` for `nics` which are gone. It brings `nics` back in sync
+/// after link messages are lost.
+pub fn dump(nics: &[Interface]) -> io::Result<Vec<LinkEvent>> {
+    let fd = socket(0)?;
+    get_link(&fd, 0, libc::NLM_F_DUMP)?;
+    let mut links = vec![];
+    let mut buf = vec![0u8; 32768];
+    // the dump is split into datagrams ended by NLMSG_DONE
+    loop {
+        let len = recv(&fd, &mut buf)?;
+        let mut done = len == 0;
+        for (kind, body) in messages(&buf[..len]) {
+            match kind {
+                libc::RTM_NEWLINK => links.extend(parse_link(body, false)),
+                NLMSG_DONE => done = true,
+                NLMSG_ERROR => match u32_at(body, 0).map(|e| e as i32) {
+                    Some(0) | None => done = true,
+                    Some(e) => return Err(io::Error::from_raw_os_error(-e)),
+                },
+                _ => (),
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    let removed: Vec<_> = nics
+        .iter()
+        .filter(|n| !links.iter().any(|link| link.index == n.index))
+        .map(|n| LinkEvent::Removed(n.index))
+        .collect();
+    let mut events = events(links);
+    events.extend(removed);
+    Ok(events)
+}
+
+/// Events of link messages. Only physical NICs and aggregations are reported.
+fn events(links: Vec<Link>) -> Vec<LinkEvent> {
+    let mut events = vec![];
+    for link in links {
+        debug!("netlink: {link:?}");
+        if link.kind.as_deref().and_then(lag_kind).is_some() {
+            events.push(LinkEvent::Lag(link.name));
+        } else if link.removed {
+            events.push(LinkEvent::Removed(link.index));
+        } else if let Some(mut nic) = get_physical_nic(&link.name) {
+            // sysfs may not be updated yet
+            nic.state = link.state;
+            events.push(LinkEvent::Changed(nic));
+        }
+    }
+    events
+}
+
+impl LinkMonitor {
+    pub fn new() -> io::Result<Self> {
+        let fd = socket(libc::SOCK_NONBLOCK)?;
+        let mut sa: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        sa.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        sa.nl_groups = libc::RTMGRP_LINK as u32;
+        if unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &raw const sa as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(LinkMonitor { fd: AsyncFd::new(fd)? })
+    }
+
+    /// Wait for next link messages. Only physical NICs and aggregations are reported.
+    pub async fn next(&mut self) -> io::Result<Vec<LinkEvent>> {
+        let mut buf = vec![0u8; 32768];
+        let len = loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|inner| {
+                match unsafe { libc::recv(inner.get_ref().as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    len => Ok(len as usize),
+                }
+            }) {
+                Ok(len) => break len?,
+                Err(_would_block) => continue,
+            }
+        };
+
+        Ok(events(parse(&buf[..len])))
+    }
+}
+
+/// Apply `event` to the list of known `nics`. True when aggregations of `nics`
+/// may have changed, i.e. an aggregation changed or a NIC got another master.
+pub fn update_nics(event: &LinkEvent, nics: &mut Vec<Interface>) -> bool {
+    match event {
+        LinkEvent::Changed(nic) => match nics.iter_mut().find(|n| n.index == nic.index) {
+            Some(n) => mem::replace(n, nic.clone()).master != nic.master,
+            None => {
+                nics.push(nic.clone());
+                nic.master.is_some()
+            },
+        },
+        LinkEvent::Removed(ifindex) => {
+            let enslaved = nics.iter().any(|n| n.index == *ifindex && n.master.is_some());
+            nics.retain(|n| n.index != *ifindex);
+            enslaved
+        },
+        LinkEvent::Lag(_) => true,
+    }
+}
+
+/// Apply `event` to known `nics` and local side of `topo`, see `update_nics`
+pub fn apply(event: &LinkEvent, nics: &mut Vec<Interface>, topo: &mut Topo) -> bool {
+    match event {
+        LinkEvent::Changed(nic) => topo.update_nic(nic),
+        LinkEvent::Removed(ifindex) => topo.remove_nic(*ifindex),
+        LinkEvent::Lag(_) => (),
+    }
+    update_nics(event, nics)
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(buf.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buf.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Messages in a netlink datagram as (type, body)
+fn messages(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut messages = vec![];
+    let mut offset = 0;
+    while let (Some(len), Some(kind)) = (u32_at(buf, offset), u16_at(buf, offset + 4)) {
+        let len = len as usize;
+        if len < NLMSG_HDRLEN || offset + len > buf.len() {
+            break;
+        }
+        messages.push((kind, &buf[offset + NLMSG_HDRLEN..offset + len]));
+        offset += align(len);
+    }
+    messages
+}
+
+/// Parse link messages in a netlink datagram
+pub fn parse(buf: &[u8]) -> Vec<Link> {
+    messages(buf)
+        .into_iter()
+        .filter(|(kind, _)| *kind == libc::RTM_NEWLINK || *kind == libc::RTM_DELLINK)
+        .filter_map(|(kind, body)| parse_link(body, kind == libc::RTM_DELLINK))
+        .collect()
+}
+
+/// Attributes of `buf` as (type, data), nested ones are left in their data
+fn attributes(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = vec![];
+    let mut offset = 0;
+    while let (Some(len), Some(kind)) = (u16_at(buf, offset), u16_at(buf, offset + 2)) {
+        let len = len as usize;
+        if len < 4 || offset + len > buf.len() {
+            break;
+        }
+        // the top bits flag nested and byte order
+        attrs.push((kind & 0x3fff, &buf[offset + 4..offset + len]));
+        offset += align(len);
+    }
+    attrs
+}
+
+fn string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).trim_end_matches('\0').to_string()
+}
+
+/// Parse ifinfomsg and its attributes
+fn parse_link(buf: &[u8], removed: bool) -> Option<Link> {
+    // ifi_family, pad, ifi_type, then ifi_index
+    let index = u32_at(buf, 4)?;
+    let mut name = String::new();
+    let mut state = OperState::Down;
+    let mut kind = None;
+    for (attr, data) in attributes(buf.get(IFINFOMSG_LEN..)?) {
+        match attr {
+            libc::IFLA_IFNAME => name = string(data),
+            libc::IFLA_OPERSTATE if data.first().copied() == Some(libc::IF_OPER_UP as u8) => {
+                state = OperState::Up;
+            },
+            libc::IFLA_LINKINFO => {
+                kind = attributes(data).into_iter().find(|(attr, _)| *attr == libc::IFLA_INFO_KIND).map(|(_, data)| string(data));
+            },
+            _ => (),
+        }
+    }
+    Some(Link {
+        index,
+        name,
+        state,
+        removed,
+        kind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut v = ((4 + data.len()) as u16).to_ne_bytes().to_vec();
+        v.extend_from_slice(&kind.to_ne_bytes());
+        v.extend_from_slice(data);
+        v.resize(align(v.len()), 0);
+        v
+    }
+
+    fn message(kind: u16, index: u32, attrs: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = vec![0u8; IFINFOMSG_LEN];
+        body[4..8].copy_from_slice(&index.to_ne_bytes());
+        for a in attrs {
+            body.extend_from_slice(a);
+        }
+        let mut v = ((NLMSG_HDRLEN + body.len()) as u32).to_ne_bytes().to_vec();
+        v.extend_from_slice(&kind.to_ne_bytes());
+        v.resize(NLMSG_HDRLEN, 0);
+        v.extend_from_slice(&body);
+        v
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut buf = message(libc::RTM_NEWLINK, 3, &[
+            attr(libc::IFLA_IFNAME, b"eth1\0"),
+            attr(libc::IFLA_OPERSTATE, &[libc::IF_OPER_UP as u8]),
+        ]);
+        buf.extend(message(libc::RTM_DELLINK, 4, &[attr(libc::IFLA_IFNAME, b"eth2\0")]));
+        // IFLA_LINKINFO is nested, with IFLA_INFO_KIND then IFLA_INFO_DATA
+        let info = [attr(libc::IFLA_INFO_KIND, b"team\0"), attr(libc::IFLA_INFO_DATA, &[0; 4])].concat();
+        buf.extend(message(libc::RTM_NEWLINK, 5, &[attr(libc::IFLA_IFNAME, b"team0\0"), attr(libc::IFLA_LINKINFO | 0x8000, &info)]));
+
+        assert_eq!(parse(&buf), vec![
+            Link { index: 3, name: "eth1".to_string(), state: OperState::Up, removed: false, kind: None },
+            Link { index: 4, name: "eth2".to_string(), state: OperState::Down, removed: true, kind: None },
+            Link { index: 5, name: "team0".to_string(), state: OperState::Down, removed: false, kind: Some("team".to_string()) },
+        ]);
+    }
+
+    #[test]
+    fn test_dump_end() {
+        let mut buf = message(libc::RTM_NEWLINK, 3, &[attr(libc::IFLA_IFNAME, b"eth1\0")]);
+        buf.extend(message(NLMSG_DONE, 0, &[]));
+        let kinds: Vec<_> = messages(&buf).into_iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, vec![libc::RTM_NEWLINK, NLMSG_DONE]);
+        assert_eq!(parse(&buf).len(), 1);
+    }
+
+    #[test]
+    fn test_update_nics() {
+        let eth0 = Interface { index: 2, name: "eth0".to_string(), ..Default::default() };
+        let mut nics = vec![eth0.clone()];
+        let up = Interface { state: OperState::Up, ..eth0.clone() };
+        assert!(!update_nics(&LinkEvent::Changed(up), &mut nics));
+        let enslaved = Interface { master: Some("bond0".to_string()), ..eth0.clone() };
+        assert!(update_nics(&LinkEvent::Changed(enslaved.clone()), &mut nics));
+        assert!(!update_nics(&LinkEvent::Changed(enslaved), &mut nics));
+        assert!(update_nics(&LinkEvent::Lag("bond0".to_string()), &mut nics));
+        assert!(update_nics(&LinkEvent::Removed(2), &mut nics));
+        assert!(nics.is_empty());
+        assert!(!update_nics(&LinkEvent::Removed(2), &mut nics));
+    }
+}
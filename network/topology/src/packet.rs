@@ -13,8 +13,43 @@ use pnet::packet::arp::ArpHardwareTypes;
 use pnet::packet::PacketSize;
 use pnet::packet::MutablePacket;
 
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::l3::{Answer, Query};
 use crate::Interface;
 
+/// Kind of packet, it is carried in ARP operation
+#[derive(Debug, Clone)]
+pub enum Message {
+    /// Broadcast to discover peers
+    Request,
+    /// Unicast answer to `Request`
+    Reply,
+    /// Unicast padded to `Probe::size` to check which frame sizes pass on a VLAN
+    Probe(Probe),
+    /// Answer to `Probe` with the addresses peer reports on that VLAN
+    ProbeReply(Probe),
+    /// ARP, NDP or ICMP echo to an address of the peer, built by `l3::build`
+    Reach(Query),
+    /// Answer of the peer to `Reach`
+    Reached(Answer),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Probe {
+    /// Sending time of `Probe` in nanoseconds since UNIX epoch, echoed in `ProbeReply`
+    pub stamp: u64,
+    /// Size of frame payload, echoed in `ProbeReply`
+    pub size: u16,
+    pub addrs: Vec<IpAddr>,
+    /// Round trip time, only set for received `ProbeReply`
+    pub rtt: Option<Duration>,
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
 
 pub fn builder(
         buf: &mut [u8],
@@ -23,6 +58,19 @@ pub fn builder(
         vlan: u16,
         hostname: &str
     ) -> usize {
+    let message = if dst_mac.is_broadcast() { Message::Request } else { Message::Reply };
+    build(buf, interface, dst_mac, vlan, hostname, &message)
+}
+
+/// Build `message` into `buf`. `buf` must be large enough for the padding of `Message::Probe`.
+pub fn build(
+        buf: &mut [u8],
+        interface: &Interface,
+        dst_mac: MacAddr,
+        vlan: u16,
+        hostname: &str,
+        message: &Message,
+    ) -> usize {
    let mut eth = MutableEthernetPacket::new(buf).unwrap();
    let src_mac: [u8; 6] = (&interface.mac).into();
 
@@ -51,12 +99,14 @@ pub fn builder(
    rarp.set_protocol_type(EtherTypes::Ipv4);
    rarp.set_hw_addr_len(6);
    rarp.set_proto_addr_len(4);
-   if dst_mac.is_broadcast() {
+   match message {
        //rarp.set_operation(ArpOperations::Request);
-       rarp.set_operation(ArpOperation(3));
-   } else {
+       Message::Request => rarp.set_operation(ArpOperation(3)),
        //rarp.set_operation(ArpOperations::Reply);
-       rarp.set_operation(ArpOperation(4));
+       Message::Reply => rarp.set_operation(ArpOperation(4)),
+       Message::Probe(_) => rarp.set_operation(ArpOperation(5)),
+       Message::ProbeReply(_) => rarp.set_operation(ArpOperation(6)),
+       Message::Reach(_) | Message::Reached(_) => unreachable!("not a frame of the topology protocol"),
    }
    len += rarp.packet_size();
    //if dst_mac.is_broadcast() {
@@ -83,13 +133,46 @@ pub fn builder(
        len += 1;
    }
 
+   // fill probe: timestamp, size and addresses
+   let padding = match message {
+       Message::Probe(probe) | Message::ProbeReply(probe) => {
+           let stamp = if let Message::Probe(_) = message { now() } else { probe.stamp };
+           payload.extend_from_slice(&stamp.to_be_bytes());
+           payload.extend_from_slice(&probe.size.to_be_bytes());
+           // the count is one byte, further addresses are not sent
+           let addrs = &probe.addrs[..probe.addrs.len().min(u8::MAX as usize)];
+           payload.push(addrs.len() as u8);
+           for addr in addrs {
+               match addr {
+                   IpAddr::V4(ip) => { payload.push(4); payload.extend_from_slice(&ip.octets()); },
+                   IpAddr::V6(ip) => { payload.push(6); payload.extend_from_slice(&ip.octets()); },
+               }
+           }
+           len = start + payload.len();
+           match message {
+               // pad frame payload (after ethernet and vlan header) to probe size
+               Message::Probe(_) => eth_len(vlan) + probe.size as usize,
+               _ => len,
+           }
+       },
+       _ => len,
+   };
+
    // add payload to packet
    let end = len;
    buf[start..end].copy_from_slice(&payload);
 
+   if padding > len {
+       buf[len..padding].fill(0);
+       len = padding;
+   }
    len
 }
 
+fn eth_len(vlan: u16) -> usize {
+    if vlan != 0 { 18 } else { 14 }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Peer {
     pub host: String,
@@ -98,13 +181,13 @@ pub struct Peer {
     pub vlan: u16,
 }
 
-pub fn parse(buf: &[u8]) -> Option<(Peer, bool)> {
-    let packet = EthernetPacket::new(buf).unwrap();
+pub fn parse(buf: &[u8]) -> Option<(Peer, Message)> {
+    let packet = EthernetPacket::new(buf)?;
     let mac = packet.get_source();
     let mut offset = packet.packet_size();
 
-    let (_vlan, ether_type) = if packet.get_ethertype() == EtherTypes::Vlan {
-        let vlan = VlanPacket::new(&buf[offset..]).unwrap();
+    let (tag, ether_type) = if packet.get_ethertype() == EtherTypes::Vlan {
+        let vlan = VlanPacket::new(&buf[offset..])?;
         offset += vlan.packet_size();
         (vlan.get_vlan_identifier(), vlan.get_ethertype())
     } else {
@@ -113,33 +196,42 @@ pub fn parse(buf: &[u8]) -> Option<(Peer, bool)> {
 
     //if ether_type == EtherTypes::Rarp {
     if ether_type == EtherTypes::Aarp {
-        let rarp = ArpPacket::new(&buf[offset..]).unwrap();
+        let rarp = ArpPacket::new(buf.get(offset..)?)?;
         //let request = if rarp.get_operation() == ArpOperations::Request {
-        let request = if rarp.get_operation() == ArpOperation(3) {
-            // should reply with unicast
-            true
-        } else {
-            false
-        };
+        let operation = rarp.get_operation();
         offset += rarp.packet_size();
 
         // interface
-        let len = buf[offset] as usize;
+        let len = *buf.get(offset)? as usize;
         let start = offset + 1;
         let end = start + len;
-        let nic = String::from_utf8_lossy(&buf[start..end]).to_string();
+        let nic = String::from_utf8_lossy(buf.get(start..end)?).to_string();
 
         // hostname
         offset = end;
-        let len = buf[offset] as usize;
+        let len = *buf.get(offset)? as usize;
         let start = offset + 1;
         let end = start + len;
-        let host = String::from_utf8_lossy(&buf[start..end]).to_string();
+        let host = String::from_utf8_lossy(buf.get(start..end)?).to_string();
 
-        // vlan
+        // vlan sent in, the tag of frame when it is still tagged
         offset = end;
-        let v: &[u8; 2] = &buf[offset..offset + 2].try_into().unwrap();
-        let vlan = u16::from_be_bytes(*v);
+        let v: [u8; 2] = buf.get(offset..offset + 2)?.try_into().ok()?;
+        let vlan = if tag != 0 { tag } else { u16::from_be_bytes(v) };
+        offset += 2;
+
+        let message = match operation {
+            // should reply with unicast
+            ArpOperation(3) => Message::Request,
+            ArpOperation(4) => Message::Reply,
+            ArpOperation(5) => Message::Probe(parse_probe(&buf[offset..])?),
+            ArpOperation(6) => {
+                let mut probe = parse_probe(&buf[offset..])?;
+                probe.rtt = Some(Duration::from_nanos(now().saturating_sub(probe.stamp)));
+                Message::ProbeReply(probe)
+            },
+            _ => return None,
+        };
 
         Some((
             Peer {
@@ -148,7 +240,7 @@ pub fn parse(buf: &[u8]) -> Option<(Peer, bool)> {
                 mac,
                 vlan,
             },
-            request
+            message
             ))
 
     } else {
@@ -156,3 +248,118 @@ pub fn parse(buf: &[u8]) -> Option<(Peer, bool)> {
     }
 }
 
+
+fn parse_probe(buf: &[u8]) -> Option<Probe> {
+    let stamp = u64::from_be_bytes(buf.get(0..8)?.try_into().ok()?);
+    let size = u16::from_be_bytes(buf.get(8..10)?.try_into().ok()?);
+    let count = *buf.get(10)?;
+    let mut offset = 11;
+    let mut addrs = vec![];
+    for _ in 0..count {
+        match buf.get(offset)? {
+            4 => {
+                let ip: [u8; 4] = buf.get(offset + 1..offset + 5)?.try_into().ok()?;
+                addrs.push(IpAddr::V4(Ipv4Addr::from(ip)));
+                offset += 5;
+            },
+            6 => {
+                let ip: [u8; 16] = buf.get(offset + 1..offset + 17)?.try_into().ok()?;
+                addrs.push(IpAddr::V6(Ipv6Addr::from(ip)));
+                offset += 17;
+            },
+            _ => return None,
+        }
+    }
+    Some(Probe {
+        stamp,
+        size,
+        addrs,
+        rtt: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MacAddress;
+
+    fn interface() -> Interface {
+        Interface {
+            index: 1,
+            name: "eth0".to_string(),
+            mac: MacAddress::from([2, 0, 0, 0, 0, 1]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut buf = [0u8; 9100];
+        let probe = Probe { size: 1500, ..Default::default() };
+        let len = build(&mut buf, &interface(), MacAddr::new(2, 0, 0, 0, 0, 2), 20, "a", &Message::Probe(probe));
+        assert_eq!(len, 18 + 1500);
+
+        let (peer, message) = parse(&buf[..len]).unwrap();
+        assert_eq!((peer.host.as_str(), peer.nic.as_str(), peer.vlan), ("a", "eth0", 20));
+        assert_eq!(peer.mac, MacAddr::new(2, 0, 0, 0, 0, 1));
+        let Message::Probe(probe) = message else { panic!("not a probe: {message:?}") };
+        assert_eq!(probe.size, 1500);
+        assert!(probe.stamp > 0);
+        assert!(probe.rtt.is_none());
+    }
+
+    #[test]
+    fn test_probe_reply() {
+        let mut buf = [0u8; 1600];
+        let addrs = vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V6(Ipv6Addr::LOCALHOST)];
+        let reply = Probe { stamp: now(), size: 9000, addrs: addrs.clone(), rtt: None };
+        let len = build(&mut buf, &interface(), MacAddr::new(2, 0, 0, 0, 0, 2), 0, "b", &Message::ProbeReply(reply));
+        // replies are not padded
+        assert!(len < 100);
+
+        let (peer, message) = parse(&buf[..len]).unwrap();
+        assert_eq!(peer.vlan, 0);
+        let Message::ProbeReply(probe) = message else { panic!("not a probe reply: {message:?}") };
+        assert_eq!(probe.size, 9000);
+        assert_eq!(probe.addrs, addrs);
+        assert!(probe.rtt.is_some());
+    }
+
+    #[test]
+    fn test_many_addrs() {
+        let mut buf = [0u8; 8192];
+        let addrs: Vec<_> = (0..300u32).map(|i| IpAddr::V4(Ipv4Addr::from(0x0a000000 + i))).collect();
+        let reply = Probe { stamp: now(), size: 1500, addrs: addrs.clone(), rtt: None };
+        let len = build(&mut buf, &interface(), MacAddr::new(2, 0, 0, 0, 0, 2), 0, "b", &Message::ProbeReply(reply));
+        let (_, message) = parse(&buf[..len]).unwrap();
+        let Message::ProbeReply(probe) = message else { panic!("not a probe reply: {message:?}") };
+        assert_eq!(probe.addrs, addrs[..255]);
+    }
+
+    #[test]
+    fn test_parse_probe() {
+        let probe = [0, 0, 0, 0, 0, 0, 0, 1, 0x05, 0xdc, 1, 4, 192, 168, 0, 1];
+        let parsed = parse_probe(&probe).unwrap();
+        assert_eq!((parsed.stamp, parsed.size), (1, 1500));
+        assert_eq!(parsed.addrs, [IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1))]);
+
+        // truncated header, truncated address and unknown address family
+        assert!(parse_probe(&probe[..10]).is_none());
+        assert!(parse_probe(&probe[..15]).is_none());
+        let mut unknown = probe;
+        unknown[11] = 5;
+        assert!(parse_probe(&unknown).is_none());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut buf = [0u8; 256];
+        let len = build(&mut buf, &interface(), MacAddr::broadcast(), 10, "host", &Message::Request);
+        assert!(parse(&buf[..len]).is_some());
+        // frame ends before the VLAN of payload
+        let end = 18 + 28 + 1 + 4 + 1 + 4 + 2;
+        for cut in 0..end {
+            assert!(parse(&buf[..cut]).is_none(), "parsed {cut} bytes");
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! PACKET_RX_RING receive path with TPACKET_V3 blocks.
+//!
+//! Kernel fills frames into a block and hands the whole block to user space
+//! when it is full or `timeout_ms` expires. Frames of a ready block are read
+//! from the mmap'd ring directly, then the block is given back to kernel.
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::{setsockopt, RecvMeta};
+
+/// TPACKET_ALIGN(sizeof(struct tpacket3_hdr)), where `sockaddr_ll` follows the header
+const TPACKET3_HDRLEN: usize = (mem::size_of::<libc::tpacket3_hdr>() + libc::TPACKET_ALIGNMENT - 1)
+    & !(libc::TPACKET_ALIGNMENT - 1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RingConfig {
+    /// Size of a block, multiple of page size
+    pub block_size: u32,
+    pub block_nr: u32,
+    /// Upper bound of frame size, kernel packs frames of any size in a block
+    pub frame_size: u32,
+    /// Time to retire a block which is not full
+    pub timeout_ms: u32,
+}
+
+impl Default for RingConfig {
+    fn default() -> Self {
+        RingConfig {
+            block_size: 1 << 20,
+            block_nr: 8,
+            frame_size: 1 << 14,
+            timeout_ms: 10,
+        }
+    }
+}
+
+pub struct RxRing {
+    map: *mut u8,
+    block_size: usize,
+    block_nr: usize,
+    /// Block to read
+    block: usize,
+    /// Offset of next frame in current block and frames left
+    next: Option<(usize, u32)>,
+}
+
+// The mapping is owned by RxRing only
+unsafe impl Send for RxRing {}
+
+impl RxRing {
+    pub fn new(fd: &OwnedFd, config: RingConfig) -> io::Result<Self> {
+        setsockopt(fd, libc::SOL_PACKET, libc::PACKET_VERSION, &(libc::tpacket_versions::TPACKET_V3 as i32))?;
+        let req = libc::tpacket_req3 {
+            tp_block_size: config.block_size,
+            tp_block_nr: config.block_nr,
+            tp_frame_size: config.frame_size,
+            tp_frame_nr: config.block_size / config.frame_size * config.block_nr,
+            tp_retire_blk_tov: config.timeout_ms,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, libc::SOL_PACKET, libc::PACKET_RX_RING, &req)?;
+
+        let size = config.block_size as usize * config.block_nr as usize;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RxRing {
+            map: map as *mut u8,
+            block_size: config.block_size as usize,
+            block_nr: config.block_nr as usize,
+            block: 0,
+            next: None,
+        })
+    }
+
+    /// Copy next frame into `buf`, `None` if no block is ready
+    pub fn next(&mut self, buf: &mut [u8]) -> Option<RecvMeta> {
+        loop {
+            unsafe {
+                let block = self.map.add(self.block * self.block_size) as *mut libc::tpacket_block_desc;
+                let hdr = &raw mut (*block).hdr.bh1;
+                if ptr::read_volatile(&raw const (*hdr).block_status) & libc::TP_STATUS_USER == 0 {
+                    return None;
+                }
+                fence(Ordering::Acquire);
+
+                let (offset, left) = *self
+                    .next
+                    .get_or_insert(((*hdr).offset_to_first_pkt as usize, (*hdr).num_pkts));
+                if left == 0 {
+                    // all frames are read, give the block back to kernel
+                    fence(Ordering::Release);
+                    ptr::write_volatile(&raw mut (*hdr).block_status, libc::TP_STATUS_KERNEL);
+                    self.next = None;
+                    self.block = (self.block + 1) % self.block_nr;
+                    continue;
+                }
+
+                let frame = (block as *const u8).add(offset);
+                let tp = (frame as *const libc::tpacket3_hdr).read();
+                let sa = (frame.add(TPACKET3_HDRLEN) as *const libc::sockaddr_ll).read_unaligned();
+                let len = (tp.tp_snaplen as usize).min(buf.len());
+                ptr::copy_nonoverlapping(frame.add(tp.tp_mac as usize), buf.as_mut_ptr(), len);
+                self.next = Some((offset + tp.tp_next_offset as usize, left - 1));
+
+                return Some(RecvMeta {
+                    ifindex: sa.sll_ifindex as u32,
+                    len: tp.tp_len as usize,
+                    caplen: len,
+                    vlan_tci: (tp.tp_status & libc::TP_STATUS_VLAN_VALID != 0).then_some(tp.hv1.tp_vlan_tci as u16),
+                    outgoing: sa.sll_pkttype == libc::PACKET_OUTGOING,
+                    timestamp: Some(UNIX_EPOCH + Duration::new(tp.tp_sec as u64, tp.tp_nsec)),
+                });
+            }
+        }
+    }
+}
+
+impl Drop for RxRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.block_size * self.block_nr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketIo, Socket};
+    use tokio::time::timeout;
+
+    /// Local experimental ethertype
+    const ETH_P_TEST: u16 = 0x88b5;
+
+    #[tokio::test]
+    async fn test_ring() {
+        let config = RingConfig {
+            block_size: 1 << 16,
+            block_nr: 2,
+            frame_size: 1 << 11,
+            timeout_ms: 10,
+        };
+        let mut rx = match Socket::with_rx_ring(libc::ETH_P_ALL as u16, config) {
+            Ok(sock) => sock,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                eprintln!("skipped, AF_PACKET socket needs CAP_NET_RAW: {e}");
+                return;
+            },
+            Err(e) => panic!("{e}"),
+        };
+        let lo = unsafe { libc::if_nametoindex(c"lo".as_ptr()) };
+        rx.bind(lo).unwrap();
+        let mut tx = Socket::new(ETH_P_TEST).unwrap();
+
+        let mut frame = vec![0u8; 100];
+        frame[12..14].copy_from_slice(&ETH_P_TEST.to_be_bytes());
+        let stamp = std::process::id().to_be_bytes();
+        frame[14..18].copy_from_slice(&stamp);
+        tx.send(&frame, lo).await.unwrap();
+
+        // shorter than frame, only its head is copied
+        let mut buf = [0u8; 64];
+        let meta = timeout(Duration::from_secs(5), async {
+            loop {
+                let meta = rx.recv(&mut buf).await.unwrap();
+                if !meta.outgoing && buf[12..14] == ETH_P_TEST.to_be_bytes() && buf[14..18] == stamp {
+                    return meta;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(meta.ifindex, lo);
+        assert_eq!((meta.len, meta.caplen), (100, 64));
+        assert_eq!(buf[..], frame[..64]);
+        assert!(meta.timestamp.is_some());
+    }
+}
@@ -0,0 +1,247 @@
+//! Simulated L2 fabric to run discovery without root and real NICs.
+//!
+//! NICs are plugged into switch ports with VLAN membership. A frame sent out
+//! of a NIC is forwarded to every other port of the same switch which carries
+//! its VLAN, broadcast frames are flooded and unicast frames are delivered only
+//! to the port of the destination MAC, as a switch which learned all MACs.
+//! Addresses assigned to a NIC are answered like its kernel would, to ARP,
+//! NDP and echo requests.
+//!
+//! It is only built with the `sim` feature, which tests enable.
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use pnet::datalink::MacAddr;
+use tokio::sync::mpsc;
+
+use crate::l3;
+use crate::{Interface, MacAddress, PacketIo, RecvMeta};
+
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+/// Received frame, ifindex where it is received and VLAN TCI stripped from it
+type Frame = (u32, Vec<u8>, Option<u16>);
+
+#[derive(Debug, Clone)]
+pub struct Port {
+    pub switch: String,
+    /// Untagged frames are in this VLAN, `None` drops untagged frames
+    pub native: Option<u16>,
+    /// Tagged VLANs allowed on this port
+    pub vlans: Vec<u16>,
+    /// Largest frame payload forwarded
+    pub mtu: usize,
+    /// NIC strips VLAN tag of received frames and reports it in `RecvMeta::vlan_tci`
+    pub offload: bool,
+}
+
+impl Port {
+    pub fn new(switch: &str, native: Option<u16>, vlans: impl IntoIterator<Item = u16>) -> Self {
+        Port {
+            switch: switch.to_string(),
+            native,
+            vlans: vlans.into_iter().collect(),
+            mtu: 1500,
+            offload: false,
+        }
+    }
+
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    pub fn offload(mut self) -> Self {
+        self.offload = true;
+        self
+    }
+
+    fn carries(&self, vlan: u16) -> bool {
+        self.native == Some(vlan) || self.vlans.contains(&vlan)
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Port and MAC of each plugged NIC, by ifindex
+    ports: HashMap<u32, (Port, [u8; 6])>,
+    /// Sockets bound to each ifindex
+    sockets: HashMap<u32, Vec<mpsc::UnboundedSender<Frame>>>,
+    /// Addresses of each ifindex and VLAN
+    addrs: HashMap<(u32, u16), Vec<IpAddr>>,
+}
+
+#[derive(Clone, Default)]
+pub struct Fabric {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Fabric {
+    /// Plug `nic` into `port`. ifindex of NICs must be unique in the whole fabric.
+    pub fn plug(&self, nic: &Interface, port: Port) {
+        let mac: [u8; 6] = (&nic.mac).into();
+        self.inner.lock().unwrap().ports.insert(nic.index, (port, mac));
+    }
+
+    /// Give `addr` to `nic` in `vlan`
+    pub fn assign(&self, nic: &Interface, vlan: u16, addr: IpAddr) {
+        self.inner.lock().unwrap().addrs.entry((nic.index, vlan)).or_default().push(addr);
+    }
+
+    /// Addresses of `ifindex` in `vlan`
+    pub fn addrs(&self, ifindex: u32, vlan: u16) -> Vec<IpAddr> {
+        self.inner.lock().unwrap().addrs.get(&(ifindex, vlan)).cloned().unwrap_or_default()
+    }
+
+    /// Whether a socket receives from `ifindex`
+    pub fn bound(&self, ifindex: u32) -> bool {
+        self.inner.lock().unwrap().sockets.contains_key(&ifindex)
+    }
+
+    pub fn socket(&self) -> SimSocket {
+        let (tx, rx) = mpsc::unbounded_channel();
+        SimSocket {
+            fabric: self.clone(),
+            tx,
+            rx,
+        }
+    }
+
+    fn forward(&self, buf: &[u8], ifindex: u32) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+        let Some((ingress, _)) = inner.ports.get(&ifindex) else {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        };
+        // MACs and ethertype, with the VLAN tag and inner ethertype when tagged
+        let tagged = buf.get(12..14) == Some(&[0x81, 0x00]);
+        if buf.len() < if tagged { 18 } else { 14 } {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let (tag, payload) = if tagged {
+            (Some(u16::from_be_bytes([buf[14], buf[15]]) & 0xfff), &buf[16..])
+        } else {
+            (None, &buf[12..])
+        };
+        let vlan = match tag.or(ingress.native) {
+            Some(vlan) if ingress.carries(vlan) => vlan,
+            _ => return Ok(()),
+        };
+        // answers of kernels, sent once the fabric is unlocked
+        let mut answers = vec![];
+        // payload includes ethertype
+        let size = payload.len() - 2;
+        if size > ingress.mtu {
+            return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+        }
+
+        for (index, (egress, mac)) in &inner.ports {
+            if *index == ifindex
+                || egress.switch != ingress.switch
+                || !egress.carries(vlan)
+                || size > egress.mtu
+                || (buf[0..6] != BROADCAST && buf[0..6] != *mac)
+            {
+                continue;
+            }
+            let mut frame = buf[0..12].to_vec();
+            let tagged = egress.native != Some(vlan);
+            if tagged && !egress.offload {
+                frame.extend_from_slice(&[0x81, 0x00]);
+                frame.extend_from_slice(&vlan.to_be_bytes());
+            }
+            frame.extend_from_slice(payload);
+            let tci = (tagged && egress.offload).then_some(vlan);
+            for sock in inner.sockets.get(index).into_iter().flatten() {
+                let _ = sock.send((*index, frame.clone(), tci));
+            }
+            if let Some(addrs) = inner.addrs.get(&(*index, vlan)) {
+                let answer = l3::answer(&frame, MacAddr::from(*mac), if tagged { vlan } else { 0 }, addrs);
+                answers.extend(answer.map(|answer| (answer, *index)));
+            }
+        }
+        drop(inner);
+        for (answer, index) in answers {
+            let _ = self.forward(&answer, index);
+        }
+        Ok(())
+    }
+}
+
+/// Socket attached to a `Fabric`
+pub struct SimSocket {
+    fabric: Fabric,
+    tx: mpsc::UnboundedSender<Frame>,
+    rx: mpsc::UnboundedReceiver<Frame>,
+}
+
+impl PacketIo for SimSocket {
+    fn bind(&self, ifindex: u32) -> io::Result<()> {
+        let mut inner = self.fabric.inner.lock().unwrap();
+        inner.sockets.entry(ifindex).or_default().push(self.tx.clone());
+        Ok(())
+    }
+
+    fn set_promiscuous(&self, _promisc: bool, _ifindex: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<RecvMeta> {
+        match self.rx.recv().await {
+            Some((ifindex, frame, vlan_tci)) => {
+                let len = frame.len().min(buf.len());
+                buf[..len].copy_from_slice(&frame[..len]);
+                Ok(RecvMeta {
+                    ifindex,
+                    len: frame.len(),
+                    caplen: len,
+                    vlan_tci,
+                    outgoing: false,
+                    timestamp: Some(SystemTime::now()),
+                })
+            },
+            None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+
+    async fn send(&mut self, buf: &[u8], ifindex: u32) -> io::Result<isize> {
+        self.fabric.forward(buf, ifindex)?;
+        Ok(buf.len() as isize)
+    }
+
+    fn addrs(&self, nic: &Interface, vlan: u16) -> Vec<IpAddr> {
+        self.fabric.addrs(nic.index, vlan)
+    }
+}
+
+/// Interface of a simulated host
+pub fn nic(index: u32, name: &str, mac: [u8; 6]) -> Interface {
+    Interface {
+        index,
+        name: name.to_string(),
+        mac: MacAddress::from(mac),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_frame() {
+        let fabric = Fabric::default();
+        let a = nic(1, "sim0", [2, 0, 0, 0, 0, 1]);
+        fabric.plug(&a, Port::new("sw1", Some(1), [10]));
+
+        let mut frame = vec![0xff; 12];
+        assert_eq!(fabric.forward(&frame, 1).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        // tagged frame without inner ethertype
+        frame.extend_from_slice(&[0x81, 0x00, 0x00, 0x0a]);
+        assert_eq!(fabric.forward(&frame, 1).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        frame.extend_from_slice(&[0x80, 0xf3]);
+        assert!(fabric.forward(&frame, 1).is_ok());
+    }
+}
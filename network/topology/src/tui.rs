@@ -0,0 +1,436 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use tokio::sync::mpsc;
+
+use crate::lag::{self, Lag};
+use crate::netlink::{self, LinkEvent};
+use crate::packet::{Message, Peer};
+use crate::{show_vlan, Interface, Node, OperState, Topo};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Local,
+    Host,
+    Nic,
+    Vlan,
+}
+
+impl SortKey {
+    fn next(self) -> Self {
+        match self {
+            SortKey::Local => SortKey::Host,
+            SortKey::Host => SortKey::Nic,
+            SortKey::Nic => SortKey::Vlan,
+            SortKey::Vlan => SortKey::Local,
+        }
+    }
+}
+
+/// State of the interactive topology view
+pub struct App {
+    host: String,
+    nics: Vec<Interface>,
+    lags: Vec<Lag>,
+    topo: Topo,
+    /// Probes sent and probes to send, per ifindex
+    progress: HashMap<u32, (usize, usize)>,
+    /// Probes to send by each NIC in one round
+    total: usize,
+    sort: SortKey,
+    filter: String,
+    editing: bool,
+    table: TableState,
+}
+
+impl App {
+    /// `total` is the number of probes sent by each NIC in one round
+    pub fn new(host: String, nics: Vec<Interface>, lags: Vec<Lag>, total: usize) -> Self {
+        let progress = nics.iter().map(|n| (n.index, (0, total))).collect();
+        App {
+            host,
+            nics,
+            lags,
+            topo: Topo::default(),
+            progress,
+            total,
+            sort: SortKey::Local,
+            filter: String::new(),
+            editing: false,
+            table: TableState::default().with_selected(0),
+        }
+    }
+
+    fn update(&mut self, ifindex: u32, peer: Peer, message: Message) {
+        if let Some(nic) = self.nics.iter().find(|n| n.index == ifindex) {
+            self.topo.record(&self.host, nic, peer, message);
+        }
+    }
+
+    /// NIC is added, removed or changed its state, or an aggregation changed
+    fn link(&mut self, event: LinkEvent) {
+        if netlink::apply(&event, &mut self.nics, &mut self.topo) {
+            self.lags = lag::get_lags(&self.nics);
+        }
+        match event {
+            // a round of probes is sent when NIC comes up
+            LinkEvent::Changed(nic) if nic.state == OperState::Up => {
+                self.progress.insert(nic.index, (0, self.total));
+            }
+            LinkEvent::Changed(_) | LinkEvent::Lag(_) => (),
+            LinkEvent::Removed(ifindex) => {
+                self.progress.remove(&ifindex);
+            }
+        }
+    }
+
+    fn sent(&mut self, ifindex: u32) {
+        if let Some((sent, _)) = self.progress.get_mut(&ifindex) {
+            *sent += 1;
+        }
+    }
+
+    fn reset_progress(&mut self) {
+        for (sent, _) in self.progress.values_mut() {
+            *sent = 0;
+        }
+    }
+
+    /// Links after filtering and sorting, links equal by the sort key keep
+    /// their order by local NIC, peer host and peer NIC across redraws
+    fn links(&self) -> Vec<(&Node, &Node, &Vec<u16>)> {
+        let vlan: Option<u16> = self.filter.parse().ok();
+        let mut links: Vec<_> = self
+            .topo
+            .connection
+            .iter()
+            .map(|((me, peer), vlans)| (me, peer, vlans))
+            .filter(|(_, peer, vlans)| {
+                self.filter.is_empty() || peer.host.contains(&self.filter) || vlan.is_some_and(|v| vlans.contains(&v))
+            })
+            .collect();
+        let names = |(me, peer, _): &(&Node, &Node, &Vec<u16>)| {
+            (me.nic.name.clone(), peer.host.clone(), peer.nic.name.clone(), <[u8; 6]>::from(&peer.nic.mac))
+        };
+        links.sort_by(|a, b| {
+            let key = match self.sort {
+                SortKey::Local => Ordering::Equal,
+                SortKey::Host => a.1.host.cmp(&b.1.host),
+                SortKey::Nic => a.1.nic.name.cmp(&b.1.nic.name),
+                SortKey::Vlan => b.2.len().cmp(&a.2.len()),
+            };
+            key.then_with(|| names(a).cmp(&names(b)))
+        });
+        links
+    }
+
+    /// Handle a key press, return false to quit
+    fn key(&mut self, key: KeyEvent, reprobe: &impl Fn()) -> bool {
+        if self.editing {
+            match key.code {
+                KeyCode::Enter => self.editing = false,
+                KeyCode::Esc => {
+                    self.editing = false;
+                    self.filter.clear();
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => (),
+            }
+            self.table.select(Some(0));
+            return true;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('s') => self.sort = self.sort.next(),
+            KeyCode::Char('/') => self.editing = true,
+            KeyCode::Char('r') => {
+                self.reset_progress();
+                reprobe();
+            }
+            KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+            _ => (),
+        }
+        true
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [progress, table, detail, status] = Layout::vertical([
+            Constraint::Length(self.nics.len() as u16 + 2),
+            Constraint::Min(5),
+            Constraint::Length(10),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        self.draw_progress(frame, progress);
+
+        let links = self.links();
+        let rows: Vec<_> = links
+            .iter()
+            .map(|(me, peer, vlans)| {
+                let local = match &me.nic.master {
+                    Some(master) => format!("{master}({})", me.nic.name),
+                    None => me.nic.name.clone(),
+                };
+                Row::new(vec![local, peer.host.clone(), peer.nic.name.clone(), show_vlan(vlans)])
+            })
+            .collect();
+        let selected = self.table.selected().and_then(|i| links.get(i)).map(|(me, peer, vlans)| {
+            let mut text = vec![
+                Line::from(format!("Local: {me}")),
+                Line::from(format!("Peer:  {peer}")),
+                Line::from(format!("VLAN:  {} ({} total)", show_vlan(vlans), vlans.len())),
+            ];
+            for (vlan, answer) in self.topo.probes.get(&((*me).clone(), (*peer).clone())).into_iter().flatten() {
+                text.push(Line::from(format!("VLAN {vlan}: {answer}")));
+            }
+            if let Some(lag) = self.lags.iter().find(|l| Some(&l.name) == me.nic.master.as_ref()) {
+                text.push(Line::from(format!("{}: {:?} {} members {}",
+                    lag.name, lag.kind, lag.mode.as_deref().unwrap_or("-"), lag.members.join(","))));
+                if let Some(partner) = lag.partners.get(&me.nic.name) {
+                    text.push(Line::from(format!("LACP partner {} key {} port {}", partner.system, partner.key, partner.port)));
+                }
+            }
+            text
+        });
+        let count = rows.len();
+        let header = Row::new(vec!["Local", "Peer host", "Peer NIC", "VLAN"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let widget = Table::new(rows, [
+                Constraint::Percentage(20),
+                Constraint::Percentage(25),
+                Constraint::Percentage(20),
+                Constraint::Percentage(35),
+            ])
+            .header(header)
+            .block(Block::bordered().title(format!(" {} links, sort by {:?} ", count, self.sort)))
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(widget, table, &mut self.table);
+
+        let mut text = selected.unwrap_or_default();
+        for warning in lag::check(&self.topo, &self.lags) {
+            text.push(Line::from(format!("WARNING: {warning}")));
+        }
+        frame.render_widget(Paragraph::new(text).block(Block::bordered().title(" Detail ")), detail);
+
+        let help = if self.editing {
+            format!("Filter by host or VLAN: {}_", self.filter)
+        } else {
+            format!("q: quit  s: sort  /: filter [{}]  r: re-probe  ↑↓: select", self.filter)
+        };
+        frame.render_widget(Paragraph::new(help), status);
+    }
+
+    fn draw_progress(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Probe ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let rows = Layout::vertical(vec![Constraint::Length(1); self.nics.len()]).split(inner);
+        for (nic, area) in self.nics.iter().zip(rows.iter()) {
+            let (sent, total) = self.progress.get(&nic.index).copied().unwrap_or_default();
+            let ratio = if total == 0 { 1.0 } else { sent as f64 / total as f64 };
+            let gauge = Gauge::default()
+                .ratio(ratio.min(1.0))
+                .label(match nic.state {
+                    OperState::Up => format!("{} {sent}/{total}", nic.name),
+                    _ => format!("{} DOWN", nic.name),
+                });
+            frame.render_widget(gauge, *area);
+        }
+    }
+}
+
+/// Run the interactive view until user quits.
+///
+/// `topo` receives answers of peers, `progress` receives the ifindex of each
+/// probe sent, `links` receives NIC changes and `reprobe` is called to start another round of probes.
+pub async fn run(
+    mut app: App,
+    mut topo: mpsc::UnboundedReceiver<(u32, Peer, Message)>,
+    mut progress: mpsc::UnboundedReceiver<u32>,
+    mut links: mpsc::UnboundedReceiver<LinkEvent>,
+    reprobe: impl Fn(),
+) -> std::io::Result<()> {
+    let (ktx, mut keys) = mpsc::unbounded_channel::<KeyEvent>();
+    // crossterm reads terminal in blocking mode
+    thread::spawn(move || loop {
+        if event::poll(Duration::from_millis(100)).unwrap_or(false) {
+            if let Ok(Event::Key(key)) = event::read() {
+                if key.kind == KeyEventKind::Press && ktx.send(key).is_err() {
+                    break;
+                }
+            }
+        } else if ktx.is_closed() {
+            break;
+        }
+    });
+
+    let mut terminal: DefaultTerminal = ratatui::init();
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| app.draw(frame)) {
+            break Err(e);
+        }
+        tokio::select! {
+            Some((ifindex, peer, message)) = topo.recv() => app.update(ifindex, peer, message),
+            Some(ifindex) = progress.recv() => app.sent(ifindex),
+            Some(event) = links.recv() => app.link(event),
+            Some(key) = keys.recv() => {
+                if !app.key(key, &reprobe) {
+                    break Ok(());
+                }
+            }
+            else => break Ok(()),
+        }
+        // drain pending messages to avoid redrawing for every packet
+        while let Ok((ifindex, peer, message)) = topo.try_recv() {
+            app.update(ifindex, peer, message);
+        }
+        while let Ok(ifindex) = progress.try_recv() {
+            app.sent(ifindex);
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use pnet::datalink::MacAddr;
+
+    use super::*;
+
+    fn nic(index: u32, name: &str) -> Interface {
+        Interface {
+            index,
+            name: name.to_string(),
+            mac: [2, 0, 0, 0, 0, index as u8].into(),
+            ..Default::default()
+        }
+    }
+
+    fn peer(host: &str, nic: &str, mac: u8, vlan: u16) -> Peer {
+        Peer {
+            host: host.to_string(),
+            nic: nic.to_string(),
+            mac: MacAddr::new(2, 0, 0, 0, 1, mac),
+            vlan,
+        }
+    }
+
+    /// eth0 reaches `b` in VLAN 10-11 and `1001` in VLAN 20, eth1 reaches `b` in VLAN 10-11
+    fn app() -> App {
+        let nics = vec![nic(1, "eth0"), nic(2, "eth1")];
+        let mut app = App::new("a".to_string(), nics.clone(), vec![], 1);
+        let mut topo = Topo::default();
+        for vlan in [10, 11] {
+            topo.add("a", &nics[1], peer("b", "eth1", 2, vlan));
+            topo.add("a", &nics[0], peer("b", "eth0", 1, vlan));
+        }
+        topo.add("a", &nics[0], peer("1001", "eth0", 3, 20));
+        app.topo = topo;
+        app
+    }
+
+    /// Rows as (local NIC, peer host, peer NIC)
+    fn rows(app: &App) -> Vec<(&str, &str, &str)> {
+        app.links().iter().map(|(me, peer, _)| (me.nic.name.as_str(), peer.host.as_str(), peer.nic.name.as_str())).collect()
+    }
+
+    fn press(app: &mut App, keys: &str) -> bool {
+        keys.chars().all(|c| app.key(KeyEvent::from(KeyCode::Char(c)), &|| ()))
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut app = app();
+        app.filter = "b".to_string();
+        assert_eq!(rows(&app), [("eth0", "b", "eth0"), ("eth1", "b", "eth1")]);
+        app.filter = "11".to_string();
+        assert_eq!(rows(&app), [("eth0", "b", "eth0"), ("eth1", "b", "eth1")]);
+        // a number is a VLAN or a part of host name
+        app.filter = "1001".to_string();
+        assert_eq!(rows(&app), [("eth0", "1001", "eth0")]);
+        app.filter = "20".to_string();
+        assert_eq!(rows(&app), [("eth0", "1001", "eth0")]);
+        app.filter = "30".to_string();
+        assert!(rows(&app).is_empty());
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut app = app();
+        assert_eq!(rows(&app), [("eth0", "1001", "eth0"), ("eth0", "b", "eth0"), ("eth1", "b", "eth1")]);
+        app.sort = SortKey::Host;
+        assert_eq!(rows(&app), [("eth0", "1001", "eth0"), ("eth0", "b", "eth0"), ("eth1", "b", "eth1")]);
+        app.sort = SortKey::Nic;
+        assert_eq!(rows(&app), [("eth0", "1001", "eth0"), ("eth0", "b", "eth0"), ("eth1", "b", "eth1")]);
+        // equal VLAN counts are ordered by names, not by hash map order
+        app.sort = SortKey::Vlan;
+        for _ in 0..10 {
+            assert_eq!(rows(&app), [("eth0", "b", "eth0"), ("eth1", "b", "eth1"), ("eth0", "1001", "eth0")]);
+            app.topo.connection = app.topo.connection.drain().collect();
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut app = app();
+        assert!(press(&mut app, "s"));
+        assert_eq!(app.sort, SortKey::Host);
+        assert!(press(&mut app, "sss"));
+        assert_eq!(app.sort, SortKey::Local);
+
+        // filter is edited until Enter or Esc, Esc also clears it
+        assert!(press(&mut app, "/b"));
+        assert!(app.editing);
+        assert_eq!(app.filter, "b");
+        assert!(app.key(KeyEvent::from(KeyCode::Backspace), &|| ()));
+        assert!(press(&mut app, "1001"));
+        assert!(app.key(KeyEvent::from(KeyCode::Enter), &|| ()));
+        assert!(!app.editing);
+        assert_eq!(rows(&app), [("eth0", "1001", "eth0")]);
+        assert!(press(&mut app, "/x"));
+        assert!(app.key(KeyEvent::from(KeyCode::Esc), &|| ()));
+        assert!(!app.editing && app.filter.is_empty());
+
+        // re-probe starts the progress over
+        app.sent(1);
+        assert_eq!(app.progress[&1], (1, 1));
+        let reprobed = Cell::new(0);
+        assert!(app.key(KeyEvent::from(KeyCode::Char('r')), &|| reprobed.set(reprobed.get() + 1)));
+        assert_eq!((reprobed.get(), app.progress[&1]), (1, (0, 1)));
+
+        assert!(!press(&mut app, "q"));
+        assert!(!app.key(KeyEvent::from(KeyCode::Esc), &|| ()));
+    }
+
+    #[test]
+    fn test_select() {
+        let mut app = app();
+        assert_eq!(app.table.selected(), Some(0));
+        assert!(app.key(KeyEvent::from(KeyCode::Down), &|| ()));
+        assert!(press(&mut app, "j"));
+        assert_eq!(app.table.selected(), Some(2));
+        assert!(press(&mut app, "k"));
+        assert_eq!(app.table.selected(), Some(1));
+        assert!(app.key(KeyEvent::from(KeyCode::Up), &|| ()));
+        assert_eq!(app.table.selected(), Some(0));
+        // a changed filter selects the first row again
+        assert!(press(&mut app, "j/b"));
+        assert_eq!(app.table.selected(), Some(0));
+    }
+}
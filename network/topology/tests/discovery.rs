@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+use topology::discovery::{probe, recv_packet, send_packet};
+use topology::lag::{self, Lag, LagKind};
+use topology::packet::{Message, Peer};
+use topology::sim::{nic, Fabric, Port};
+use topology::{Interface, Topo};
+
+/// Start discovery tasks of a simulated host, return its topology channel
+fn start(
+    fabric: &Fabric,
+    host: &str,
+    nics: &[Interface],
+    vlans: &[(u16, u16)],
+    sizes: &[u16],
+    l3: Option<&[u16]>,
+) -> mpsc::UnboundedReceiver<(u32, Peer, Message)> {
+    let (ptx, prx) = mpsc::unbounded_channel();
+    let (ttx, trx) = mpsc::unbounded_channel();
+    let (progress, _) = mpsc::unbounded_channel();
+    for nic in nics {
+        tokio::spawn(recv_packet(fabric.socket(), ptx.clone(), ttx.clone(), nic.index, sizes.to_vec(), l3.map(<[u16]>::to_vec)));
+    }
+    tokio::spawn(send_packet(fabric.socket(), prx, progress, host.to_string(), Arc::new(RwLock::new(nics.to_vec()))));
+    probe(&ptx, nics, vlans);
+    trx
+}
+
+/// Longest wait for a topology, only reached when a test fails
+const DEADLINE: Duration = Duration::from_secs(5);
+
+/// Build topology until `done` holds for it
+async fn collect(
+    mut rx: mpsc::UnboundedReceiver<(u32, Peer, Message)>,
+    host: &str,
+    nics: &[Interface],
+    done: impl Fn(&Topo) -> bool,
+) -> Topo {
+    let mut topo = Topo::default();
+    let receive = async {
+        while !done(&topo) {
+            let (ifindex, peer, message) = rx.recv().await.unwrap();
+            let nic = nics.iter().find(|n| n.index == ifindex).unwrap();
+            topo.record(host, nic, peer, message);
+        }
+    };
+    if timeout(DEADLINE, receive).await.is_err() {
+        panic!("{host}: topology is not complete in {DEADLINE:?}:\n{topo}");
+    }
+    topo
+}
+
+/// Connections as (local NIC, peer host, peer NIC) -> VLANs
+fn links(topo: &Topo) -> HashMap<(String, String, String), Vec<u16>> {
+    topo.connection
+        .iter()
+        .map(|((me, peer), vlans)| ((me.nic.name.clone(), peer.host.clone(), peer.nic.name.clone()), vlans.clone()))
+        .collect()
+}
+
+fn link(local: &str, host: &str, nic: &str) -> (String, String, String) {
+    (local.to_string(), host.to_string(), nic.to_string())
+}
+
+#[tokio::test]
+async fn test_vlan_membership() {
+    let fabric = Fabric::default();
+    let a = vec![nic(1, "eth0", [2, 0, 0, 0, 0, 1])];
+    let b = vec![nic(2, "eth1", [2, 0, 0, 0, 0, 2])];
+    fabric.plug(&a[0], Port::new("sw1", Some(1), 10..=12));
+    fabric.plug(&b[0], Port::new("sw1", Some(1), 11..=13));
+
+    let vlans = [(10, 13), (0, 0)];
+    let rx_a = start(&fabric, "a", &a, &vlans, &[], None);
+    let rx_b = start(&fabric, "b", &b, &vlans, &[], None);
+    let expect_a = HashMap::from([(link("eth0", "b", "eth1"), vec![0, 11, 12])]);
+    let expect_b = HashMap::from([(link("eth1", "a", "eth0"), vec![0, 11, 12])]);
+    let (topo_a, _) = tokio::join!(
+        collect(rx_a, "a", &a, |topo| links(topo) == expect_a),
+        collect(rx_b, "b", &b, |topo| links(topo) == expect_b),
+    );
+    assert!(topo_a.probes.is_empty());
+}
+
+#[tokio::test]
+async fn test_rack_with_bond() {
+    let fabric = Fabric::default();
+    let mut a = vec![nic(1, "eth0", [2, 0, 0, 0, 0, 1]), nic(2, "eth1", [2, 0, 0, 0, 0, 1])];
+    for nic in &mut a {
+        nic.master = Some("bond0".to_string());
+    }
+    let b = vec![nic(3, "eth0", [2, 0, 0, 0, 0, 3]), nic(4, "eth1", [2, 0, 0, 0, 0, 4])];
+    fabric.plug(&a[0], Port::new("sw1", Some(1), 10..=12));
+    // VLAN 12 is missing on the second switch
+    fabric.plug(&a[1], Port::new("sw2", Some(1), 10..=11));
+    fabric.plug(&b[0], Port::new("sw1", Some(1), 10..=12));
+    fabric.plug(&b[1], Port::new("sw2", Some(1), 10..=12));
+
+    let vlans = [(10, 12), (0, 0)];
+    let rx_a = start(&fabric, "a", &a, &vlans, &[], None);
+    // kept open for the discovery of b to answer a
+    let _rx_b = start(&fabric, "b", &b, &vlans, &[], None);
+    let expect = HashMap::from([
+        (link("eth0", "b", "eth0"), vec![0, 10, 11, 12]),
+        (link("eth1", "b", "eth1"), vec![0, 10, 11]),
+    ]);
+    let topo_a = collect(rx_a, "a", &a, |topo| links(topo) == expect).await;
+    assert!(topo_a.connection.keys().all(|(me, _)| me.nic.master.as_deref() == Some("bond0")));
+
+    let bond = Lag {
+        name: "bond0".to_string(),
+        kind: LagKind::Bond,
+        mode: Some("active-backup".to_string()),
+        members: vec!["eth0".to_string(), "eth1".to_string()],
+        partners: HashMap::new(),
+    };
+    assert_eq!(
+        lag::check(&topo_a, &[bond]),
+        vec!["bond0: members carry different VLANs: eth0 VLAN 0,10-12, eth1 VLAN 0,10-11"]
+    );
+}
+
+#[tokio::test]
+async fn test_probe_frame_size() {
+    let fabric = Fabric::default();
+    let a = vec![nic(1, "sim0", [2, 0, 0, 0, 0, 1])];
+    let b = vec![nic(2, "sim1", [2, 0, 0, 0, 0, 2])];
+    fabric.plug(&a[0], Port::new("sw1", None, [20]).mtu(9000));
+    fabric.plug(&b[0], Port::new("sw1", None, [20]));
+
+    let rx_a = start(&fabric, "a", &a, &[(20, 20)], &[1500, 9000], None);
+    let rx_b = start(&fabric, "b", &b, &[(20, 20)], &[], None);
+    let (topo_a, topo_b) = tokio::join!(
+        // the probe of 9000 bytes is dropped by the port of b
+        collect(rx_a, "a", &a, |topo| !topo.probes.is_empty()),
+        collect(rx_b, "b", &b, |topo| !topo.connection.is_empty()),
+    );
+
+    assert_eq!(links(&topo_a), HashMap::from([(link("sim0", "b", "sim1"), vec![20])]));
+    let probes: Vec<_> = topo_a.probes.values().flat_map(|v| v.iter()).collect();
+    assert_eq!(probes.len(), 1);
+    let (vlan, answer) = probes[0];
+    assert_eq!(*vlan, 20);
+    assert_eq!(answer.frame, 1500);
+    assert!(answer.rtt.is_some());
+    assert!(topo_b.probes.is_empty());
+}
+
+#[tokio::test]
+async fn test_l3() {
+    let fabric = Fabric::default();
+    let a = vec![nic(1, "sim0", [2, 0, 0, 0, 0, 1])];
+    let b = vec![nic(2, "sim1", [2, 0, 0, 0, 0, 2])];
+    fabric.plug(&a[0], Port::new("sw1", None, [20]).mtu(9000));
+    fabric.plug(&b[0], Port::new("sw1", None, [20]));
+    // a has no IPv6 address, it solicits from the link-local address of its MAC
+    fabric.assign(&a[0], 20, "10.0.20.1".parse().unwrap());
+    let addrs: Vec<IpAddr> = vec!["10.0.20.2".parse().unwrap(), "fe80::2".parse().unwrap()];
+    for addr in &addrs {
+        fabric.assign(&b[0], 20, *addr);
+    }
+
+    let rx_a = start(&fabric, "a", &a, &[(20, 20)], &[1500], Some(&[1500, 9000]));
+    let _rx_b = start(&fabric, "b", &b, &[(20, 20)], &[], None);
+    // the echo requests of 9000 bytes are dropped by the port of b
+    let topo_a = collect(rx_a, "a", &a, |topo| {
+        topo.probes.values().flat_map(|v| v.values()).any(|probe| probe.reached.len() == 2 && probe.mtu == Some(1500))
+    })
+    .await;
+    let probes: Vec<_> = topo_a.probes.values().flat_map(|v| v.iter()).collect();
+    assert_eq!(probes.len(), 1);
+    let (vlan, answer) = probes[0];
+    assert_eq!(*vlan, 20);
+    assert_eq!(answer.addrs, addrs);
+    assert!(answer.to_string().starts_with("L2 ok, L3 10.0.20.2 fe80::2, MTU 1500, RTT "), "{answer}");
+}
+
+#[tokio::test]
+async fn test_vlan_offload() {
+    let fabric = Fabric::default();
+    let a = vec![nic(1, "eth0", [2, 0, 0, 0, 0, 1])];
+    let b = vec![nic(2, "eth1", [2, 0, 0, 0, 0, 2])];
+    // a is an access port of VLAN 10, b a trunk whose NIC strips tags
+    fabric.plug(&a[0], Port::new("sw1", Some(10), []));
+    fabric.plug(&b[0], Port::new("sw1", None, [10]).offload());
+
+    // only a sends requests, its untagged request is seen by b in VLAN 10 and answered there
+    let rx_b = start(&fabric, "b", &b, &[], &[], None);
+    while !fabric.bound(b[0].index) {
+        tokio::task::yield_now().await;
+    }
+    let rx_a = start(&fabric, "a", &a, &[(0, 0)], &[], None);
+    let expect_a = HashMap::from([(link("eth0", "b", "eth1"), vec![10])]);
+    let expect_b = HashMap::from([(link("eth1", "a", "eth0"), vec![10])]);
+    tokio::join!(
+        collect(rx_a, "a", &a, |topo| links(topo) == expect_a),
+        collect(rx_b, "b", &b, |topo| links(topo) == expect_b),
+    );
+}